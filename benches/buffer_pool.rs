@@ -0,0 +1,95 @@
+//! Allocations per request of the gzip codecs with and without `--buffer-pool`, under a steady
+//! stream of requests on one thread. The two take turns going first over several rounds, so
+//! neither always gets the warmer caches, and the medians are reported at the end.
+//!
+//!     cargo bench --bench buffer_pool
+
+use http_proxy::compress::{Compressor, Decompressor, Encode};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Counts every allocation and reallocation
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const REQUESTS: usize = 2_000;
+/// A 64k body arriving in 4k chunks
+const CHUNKS: usize = 16;
+const ROUNDS: usize = 7;
+
+/// Allocations and time per request
+fn run(pooled: bool) -> (f64, Duration) {
+    let chunk: Vec<u8> = (0..4096u32)
+        .map(|i| b"{\"id\": 12345, \"name\": \"value\"}, "[i as usize % 32])
+        .collect();
+    let start = Instant::now();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..REQUESTS {
+        let mut compressor = Compressor::new(6);
+        let mut decompressor = Decompressor::new();
+        if pooled {
+            compressor = compressor.with_buffer_pool();
+            decompressor = decompressor.with_buffer_pool();
+        }
+        for i in 0..CHUNKS {
+            let compressed = compressor.encode(&chunk, i == CHUNKS - 1).unwrap();
+            let decompressed = decompressor.encode(&compressed, i == CHUNKS - 1).unwrap();
+            std::hint::black_box(decompressed);
+        }
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    (
+        allocations as f64 / REQUESTS as f64,
+        start.elapsed() / REQUESTS as u32,
+    )
+}
+
+fn report(mode: &str, (allocations, time): (f64, Duration)) {
+    println!("{mode:<12} {allocations:>8.1} allocations/request {time:>8.1?}/request");
+}
+
+fn main() {
+    let mut times = [Vec::new(), Vec::new()];
+    let mut allocations = [0.0; 2];
+    for round in 0..ROUNDS {
+        println!("round {}", round + 1);
+        let order = if round % 2 == 0 {
+            [false, true]
+        } else {
+            [true, false]
+        };
+        for pooled in order {
+            let result = run(pooled);
+            report(if pooled { "pooled" } else { "unpooled" }, result);
+            allocations[pooled as usize] = result.0;
+            times[pooled as usize].push(result.1);
+        }
+    }
+    println!("median of {ROUNDS} rounds");
+    for (pooled, mode) in [(false, "unpooled"), (true, "pooled")] {
+        let times = &mut times[pooled as usize];
+        times.sort();
+        report(mode, (allocations[pooled as usize], times[ROUNDS / 2]));
+    }
+}
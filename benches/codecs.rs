@@ -0,0 +1,147 @@
+//! Throughput and ratio of the codecs over typical bodies, to help pick `--algorithm` and the
+//! level flags
+//!
+//!     cargo bench --bench codecs
+
+use http_proxy::compress::{
+    BrotliCompressor, BrotliDecompressor, Compressor, Decompressor, DeflateCompressor,
+    DeflateDecompressor, Encode, ZstdCompressor, ZstdDecompressor,
+};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// Size of each payload, fed to the codec in `CHUNK` sized pieces like a streamed body
+const SIZE: usize = 1024 * 1024;
+const CHUNK: usize = 16 * 1024;
+/// Each measurement runs at least this long
+const MIN_TIME: Duration = Duration::from_millis(500);
+
+fn json() -> Vec<u8> {
+    let mut out = String::from("[");
+    let mut i = 0;
+    while out.len() < SIZE {
+        let _ = write!(
+            out,
+            "{{\"id\":{i},\"name\":\"user{}\",\"email\":\"user{i}@example.com\",\
+             \"active\":{},\"score\":{}.{}}},",
+            i % 977,
+            i % 3 == 0,
+            i * 7 % 100,
+            i % 10
+        );
+        i += 1;
+    }
+    out.truncate(SIZE - 1);
+    out.push(']');
+    out.into_bytes()
+}
+
+fn html() -> Vec<u8> {
+    let mut out = String::from("<!DOCTYPE html><html><head><title>Items</title></head><body><ul>");
+    let mut i = 0;
+    while out.len() < SIZE {
+        let _ = writeln!(
+            out,
+            "<li class=\"item item-{}\"><a href=\"/items/{i}\">Item {i}</a> \
+             <span class=\"price\">{}.99</span></li>",
+            i % 5,
+            i * 13 % 1000
+        );
+        i += 1;
+    }
+    out.truncate(SIZE);
+    out.into_bytes()
+}
+
+/// Incompressible, xorshift so every run sees the same bytes
+fn random() -> Vec<u8> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    (0..SIZE)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Run `make()` over `input` until `MIN_TIME` passed, returning the output of one run and the
+/// throughput in MB/s of input
+fn measure(input: &[u8], make: &dyn Fn() -> Box<dyn Encode>) -> (Vec<u8>, f64) {
+    let mut output = Vec::new();
+    let mut runs = 0;
+    let start = Instant::now();
+    while runs == 0 || start.elapsed() < MIN_TIME {
+        let mut codec = make();
+        output.clear();
+        let chunks = input.chunks(CHUNK).count();
+        for (i, chunk) in input.chunks(CHUNK).enumerate() {
+            output.extend_from_slice(&codec.encode(chunk, i == chunks - 1).unwrap());
+        }
+        runs += 1;
+    }
+    let seconds = start.elapsed().as_secs_f64();
+    (output, (input.len() * runs) as f64 / seconds / 1e6)
+}
+
+type Make = Box<dyn Fn() -> Box<dyn Encode>>;
+
+fn codec<C: Encode + 'static, D: Encode + 'static>(
+    name: String,
+    compressor: impl Fn() -> C + 'static,
+    decompressor: impl Fn() -> D + 'static,
+) -> (String, Make, Make) {
+    (
+        name,
+        Box::new(move || Box::new(compressor())),
+        Box::new(move || Box::new(decompressor())),
+    )
+}
+
+fn main() {
+    let mut codecs = Vec::new();
+    for level in [1, 6, 9] {
+        codecs.push(codec(
+            format!("gzip {level}"),
+            move || Compressor::new(level),
+            Decompressor::new,
+        ));
+    }
+    for level in [-1, 1, 3, 6, 12, 19] {
+        codecs.push(codec(
+            format!("zstd {level}"),
+            move || ZstdCompressor::new(level).unwrap(),
+            || ZstdDecompressor::new().unwrap(),
+        ));
+    }
+    codecs.push(codec(
+        "br 5".to_string(),
+        || BrotliCompressor::new(5),
+        BrotliDecompressor::new,
+    ));
+    codecs.push(codec(
+        "deflate 6".to_string(),
+        || DeflateCompressor::new(6),
+        DeflateDecompressor::new,
+    ));
+
+    for (payload, input) in [("json", json()), ("html", html()), ("random", random())] {
+        println!("{payload}, {} bytes", input.len());
+        println!(
+            "  {:<10} {:>8} {:>14} {:>14}",
+            "codec", "ratio", "compress MB/s", "decompress MB/s"
+        );
+        for (name, compressor, decompressor) in &codecs {
+            let (compressed, compress_speed) = measure(&input, compressor.as_ref());
+            let (decompressed, decompress_speed) = measure(&compressed, decompressor.as_ref());
+            assert_eq!(decompressed, input, "{name} round trip");
+            // decompression speed counts the bytes produced, like compression counts consumed
+            let decompress_speed = decompress_speed * input.len() as f64 / compressed.len() as f64;
+            println!(
+                "  {name:<10} {:>8.3} {compress_speed:>14.1} {decompress_speed:>14.1}",
+                compressed.len() as f64 / input.len() as f64,
+            );
+        }
+    }
+}
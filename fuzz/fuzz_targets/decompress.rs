@@ -0,0 +1,59 @@
+//! Arbitrary bytes into the decompressors, the input the proxy takes from untrusted clients
+//! and upstreams. Errors are expected, a panic or output past the limit is a bug.
+//!
+//!     cargo +nightly fuzz run decompress
+#![no_main]
+
+use http_proxy::compress::{
+    BrotliDecompressor, Decompressor, DeflateDecompressor, Encode, ZstdDecompressor,
+};
+use libfuzzer_sys::fuzz_target;
+
+/// Decompressed bytes allowed per input, like `--max-decompressed-size`
+const LIMIT: usize = 1024 * 1024;
+
+fuzz_target!(|data: &[u8]| {
+    // the first byte picks the decompressor, the second seeds the chunking, the rest is the body
+    let [kind, seed, body @ ..] = data else {
+        return;
+    };
+    let mut decompressor: Box<dyn Encode> = match kind % 5 {
+        0 => Box::new(Decompressor::with_limit(LIMIT)),
+        1 => Box::new(Decompressor::with_limit(LIMIT).with_output_chunk(4096)),
+        2 => Box::new(ZstdDecompressor::with_limit(LIMIT).unwrap()),
+        3 => Box::new(BrotliDecompressor::with_limit(LIMIT)),
+        _ => Box::new(DeflateDecompressor::with_limit(LIMIT)),
+    };
+    let mut state = u64::from(*seed) | 1;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    let mut rest = body;
+    let mut total = 0;
+    loop {
+        let (chunk, tail) = rest.split_at((next() as usize % 512).min(rest.len()));
+        rest = tail;
+        // may come early, and more input may follow it
+        let end = rest.is_empty() || next() % 16 == 0;
+        let Ok(output) = decompressor.encode(chunk, end) else {
+            return;
+        };
+        total += output.len();
+        assert!(total <= LIMIT, "{total} bytes out");
+        // what a chunked decompressor holds back comes out with empty input
+        while decompressor.has_pending() {
+            let Ok(output) = decompressor.encode(&[], end) else {
+                return;
+            };
+            total += output.len();
+            assert!(total <= LIMIT, "{total} bytes out");
+        }
+        if rest.is_empty() {
+            return;
+        }
+    }
+});
@@ -0,0 +1,162 @@
+use crate::compress::Algorithm;
+use clap::ValueEnum;
+
+/// One entry of an `Accept-Encoding` header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coding<'a> {
+    pub name: &'a str,
+    pub q: f32,
+}
+
+/// Parse `Accept-Encoding` header values into codings ordered by descending q-value. Codings
+/// with the same q-value keep the order they were listed in. A malformed q-value counts as 0,
+/// i.e. "not acceptable".
+pub fn parse<'a>(values: impl IntoIterator<Item = &'a str>) -> Vec<Coding<'a>> {
+    let mut codings: Vec<Coding> = values
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let name = parts.next()?.trim();
+            if name.is_empty() {
+                return None;
+            }
+            let mut q = 1.0;
+            for param in parts {
+                if let Some((key, value)) = param.split_once('=')
+                    && key.trim().eq_ignore_ascii_case("q")
+                {
+                    q = value
+                        .trim()
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|q| (0.0..=1.0).contains(q))
+                        .unwrap_or(0.0);
+                }
+            }
+            Some(Coding { name, q })
+        })
+        .collect();
+    // sort_by is stable
+    codings.sort_by(|a, b| b.q.total_cmp(&a.q));
+    codings
+}
+
+/// The q-value of the entry naming `algorithm`, `None` when there is none
+fn listed(codings: &[Coding], algorithm: Algorithm) -> Option<f32> {
+    codings
+        .iter()
+        .find(|c| Algorithm::from_content_encoding(c.name) == Some(algorithm))
+        .map(|c| c.q)
+}
+
+/// Whether `algorithm` is listed with a non-zero q-value, or not listed at all but covered by a
+/// `*` with one
+pub fn accepts(codings: &[Coding], algorithm: Algorithm) -> bool {
+    let q = listed(codings, algorithm)
+        .or_else(|| codings.iter().find(|c| c.name == "*").map(|c| c.q))
+        .unwrap_or(0.0);
+    q > 0.0
+}
+
+/// Pick the most preferred algorithm the proxy can produce. When several algorithms share the
+/// highest q-value, `preferred` wins if it is among them. A `*` stands for the algorithms the
+/// header doesn't name, `preferred` first. `None` means nothing overlaps and the body should
+/// stay unencoded.
+pub fn negotiate(codings: &[Coding], preferred: Algorithm) -> Option<Algorithm> {
+    let mut best: Option<(Algorithm, f32)> = None;
+    for coding in codings.iter().filter(|c| c.q > 0.0) {
+        let algorithm = if coding.name == "*" {
+            std::iter::once(preferred)
+                .chain(Algorithm::value_variants().iter().copied())
+                .find(|algorithm| {
+                    *algorithm != Algorithm::Identity && listed(codings, *algorithm).is_none()
+                })
+        } else {
+            Algorithm::from_content_encoding(coding.name)
+                .filter(|algorithm| *algorithm != Algorithm::Identity)
+        };
+        let Some(algorithm) = algorithm else {
+            continue;
+        };
+        match best {
+            // codings are sorted, everything after a lower q-value is worse
+            Some((_, q)) if coding.q < q => break,
+            Some(_) if algorithm != preferred => {}
+            _ => best = Some((algorithm, coding.q)),
+        }
+    }
+    best.map(|(algorithm, _)| algorithm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_q_values() {
+        let codings = parse(["gzip;q=0.5, br", "zstd ; Q=0.8, deflate;q=2, identity;q=0"]);
+        let names: Vec<_> = codings.iter().map(|c| (c.name, c.q)).collect();
+        assert_eq!(
+            names,
+            [
+                ("br", 1.0),
+                ("zstd", 0.8),
+                ("gzip", 0.5),
+                ("deflate", 0.0),
+                ("identity", 0.0)
+            ]
+        );
+        assert!(parse([""]).is_empty());
+    }
+
+    #[test]
+    fn negotiate_algorithm() {
+        let codings = parse(["gzip;q=0.5, br;q=0.9, zstd;q=0.9"]);
+        // br and zstd tie, the preferred one wins
+        assert_eq!(negotiate(&codings, Algorithm::Zstd), Some(Algorithm::Zstd));
+        assert_eq!(
+            negotiate(&codings, Algorithm::Gzip),
+            Some(Algorithm::Brotli)
+        );
+
+        let codings = parse(["lz4, x-gzip;q=0.1, zstd;q=0"]);
+        assert_eq!(negotiate(&codings, Algorithm::Zstd), Some(Algorithm::Gzip));
+        assert!(accepts(&codings, Algorithm::Gzip));
+        assert!(!accepts(&codings, Algorithm::Zstd));
+
+        let codings = parse(["lz4, identity"]);
+        assert_eq!(negotiate(&codings, Algorithm::Zstd), None);
+    }
+
+    #[test]
+    fn wildcard() {
+        let any = parse(["*"]);
+        assert_eq!(negotiate(&any, Algorithm::Zstd), Some(Algorithm::Zstd));
+        assert_eq!(negotiate(&any, Algorithm::Brotli), Some(Algorithm::Brotli));
+        assert!(accepts(&any, Algorithm::Gzip));
+
+        let none = parse(["*;q=0"]);
+        assert_eq!(negotiate(&none, Algorithm::Zstd), None);
+        assert!(!accepts(&none, Algorithm::Zstd));
+
+        // explicit entries win over the wildcard, whichever way
+        let codings = parse(["*, zstd;q=0"]);
+        assert_eq!(negotiate(&codings, Algorithm::Zstd), Some(Algorithm::Gzip));
+        assert!(!accepts(&codings, Algorithm::Zstd));
+        assert!(accepts(&codings, Algorithm::Brotli));
+        let codings = parse(["*;q=0, gzip"]);
+        assert_eq!(negotiate(&codings, Algorithm::Zstd), Some(Algorithm::Gzip));
+        assert!(!accepts(&codings, Algorithm::Zstd));
+        let codings = parse(["br;q=0.5, *;q=0.8"]);
+        assert_eq!(
+            negotiate(&codings, Algorithm::Brotli),
+            Some(Algorithm::Gzip)
+        );
+        assert_eq!(negotiate(&codings, Algorithm::Zstd), Some(Algorithm::Zstd));
+        // a tie with an explicit entry goes to the preferred one
+        let codings = parse(["br, *"]);
+        assert_eq!(negotiate(&codings, Algorithm::Zstd), Some(Algorithm::Zstd));
+        assert_eq!(negotiate(&codings, Algorithm::Gzip), Some(Algorithm::Gzip));
+    }
+}
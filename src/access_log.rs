@@ -0,0 +1,129 @@
+use crate::config::LogFormat;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// What gets logged about each finished request
+pub struct AccessLog<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub host: Option<&'a str>,
+    pub request_id: Option<&'a str>,
+    /// 0 when no response was sent
+    pub status: u16,
+    pub upstream: Option<&'a str>,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    pub latency: Duration,
+    /// From sending the request upstream to the upstream response header, `None` without one
+    pub upstream_ttfb: Option<Duration>,
+    /// Spent in the codecs of the request and the response body
+    pub codec_time: Duration,
+}
+
+impl AccessLog<'_> {
+    pub fn format(&self, format: LogFormat) -> String {
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let latency_ms = ms(self.latency);
+        let codec_ms = ms(self.codec_time);
+        match format {
+            LogFormat::Text => format!(
+                "{} {} host={} id={} status={} upstream={} bytes_in={} bytes_out={} latency={latency_ms:.3}ms ttfb={} codec={codec_ms:.3}ms",
+                self.method,
+                self.path,
+                self.host.unwrap_or("-"),
+                self.request_id.unwrap_or("-"),
+                self.status,
+                self.upstream.unwrap_or("-"),
+                self.bytes_in,
+                self.bytes_out,
+                self.upstream_ttfb
+                    .map_or_else(|| "-".to_string(), |ttfb| format!("{:.3}ms", ms(ttfb))),
+            ),
+            LogFormat::Json => {
+                let mut line = String::from("{");
+                let _ = write!(line, "\"method\":{}", json_string(self.method));
+                let _ = write!(line, ",\"path\":{}", json_string(self.path));
+                let _ = write!(line, ",\"host\":{}", json_nullable(self.host));
+                let _ = write!(line, ",\"request_id\":{}", json_nullable(self.request_id));
+                let _ = write!(line, ",\"status\":{}", self.status);
+                let _ = write!(line, ",\"upstream\":{}", json_nullable(self.upstream));
+                let _ = write!(line, ",\"bytes_in\":{}", self.bytes_in);
+                let _ = write!(line, ",\"bytes_out\":{}", self.bytes_out);
+                let _ = write!(line, ",\"latency_ms\":{latency_ms:.3}");
+                let ttfb = self
+                    .upstream_ttfb
+                    .map_or_else(|| "null".to_string(), |ttfb| format!("{:.3}", ms(ttfb)));
+                let _ = write!(line, ",\"upstream_ttfb_ms\":{ttfb}");
+                let _ = write!(line, ",\"codec_ms\":{codec_ms:.3}}}");
+                line
+            }
+        }
+    }
+}
+
+fn json_nullable(value: Option<&str>) -> String {
+    value.map_or_else(|| "null".to_string(), json_string)
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> AccessLog<'static> {
+        AccessLog {
+            method: "GET",
+            path: "/a\"b",
+            host: Some("example.com"),
+            request_id: Some("abc-123"),
+            status: 200,
+            upstream: None,
+            bytes_in: 0,
+            bytes_out: 42,
+            latency: Duration::from_micros(1500),
+            upstream_ttfb: None,
+            codec_time: Duration::from_micros(250),
+        }
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(
+            entry().format(LogFormat::Text),
+            "GET /a\"b host=example.com id=abc-123 status=200 upstream=- bytes_in=0 bytes_out=42 latency=1.500ms ttfb=- codec=0.250ms"
+        );
+        let upstream = AccessLog {
+            upstream_ttfb: Some(Duration::from_millis(1)),
+            ..entry()
+        };
+        assert!(upstream.format(LogFormat::Text).contains(" ttfb=1.000ms "));
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(
+            entry().format(LogFormat::Json),
+            r#"{"method":"GET","path":"/a\"b","host":"example.com","request_id":"abc-123","status":200,"upstream":null,"bytes_in":0,"bytes_out":42,"latency_ms":1.500,"upstream_ttfb_ms":null,"codec_ms":0.250}"#
+        );
+        assert_eq!(json_string("\u{1}\n"), r#""\u0001\n""#);
+    }
+}
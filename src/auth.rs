@@ -0,0 +1,57 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+/// Whether an `Authorization` header carries one of the `user:password` `credentials` of
+/// `--basic-auth`
+pub fn authorized(authorization: Option<&[u8]>, credentials: &[String]) -> bool {
+    let Some(given) = authorization.and_then(basic_credentials) else {
+        return false;
+    };
+    // every entry is compared, so the time taken doesn't tell which one came close
+    credentials.iter().fold(false, |found, expected| {
+        found | constant_time_eq(&given, expected.as_bytes())
+    })
+}
+
+/// A `Basic` authorization with `credentials`, as `user:password`
+pub fn basic(credentials: &str) -> String {
+    format!("Basic {}", STANDARD.encode(credentials))
+}
+
+/// The decoded `user:password` of a `Basic` authorization
+fn basic_credentials(authorization: &[u8]) -> Option<Vec<u8>> {
+    let (scheme, encoded) = authorization.trim_ascii().split_at_checked(6)?;
+    if !scheme.eq_ignore_ascii_case(b"basic ") {
+        return None;
+    }
+    STANDARD.decode(encoded.trim_ascii()).ok()
+}
+
+/// Compare without stopping at the first difference. Only the length can leak.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basic() {
+        let credentials = ["alice:secret".to_string(), "bob:hunter2".to_string()];
+        let header = |value: &str| authorized(Some(value.as_bytes()), &credentials);
+        // alice:secret
+        assert!(header("Basic YWxpY2U6c2VjcmV0"));
+        assert!(header("basic  YWxpY2U6c2VjcmV0 "));
+        // bob:hunter2
+        assert!(header("Basic Ym9iOmh1bnRlcjI="));
+        // alice:secrex
+        assert!(!header("Basic YWxpY2U6c2VjcmV4"));
+        assert!(!header("Bearer YWxpY2U6c2VjcmV0"));
+        assert!(!header("Basic not base64!"));
+        assert!(!header("Basic"));
+        assert!(!authorized(None, &credentials));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+        assert!(header(&super::basic("bob:hunter2")));
+    }
+}
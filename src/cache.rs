@@ -0,0 +1,210 @@
+use bytes::Bytes;
+use pingora::http::ResponseHeader;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// A response of `--cache-size`, as it was sent to the client that caused it to be cached
+pub struct Cached {
+    pub header: ResponseHeader,
+    pub body: Bytes,
+}
+
+impl Cached {
+    /// Bytes the entry counts against the size limit, the headers included
+    fn size(&self, key: &str) -> usize {
+        let headers: usize = self
+            .header
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        key.len() + headers + self.body.len()
+    }
+}
+
+struct Entry {
+    response: Arc<Cached>,
+    size: usize,
+    /// Position in `Entries::by_use`
+    used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<String, Entry>,
+    /// Keys from least to most recently used
+    by_use: BTreeMap<u64, String>,
+    size: usize,
+    clock: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.by_key.remove(key) {
+            self.by_use.remove(&entry.used);
+            self.size -= entry.size;
+        }
+    }
+}
+
+/// Responses kept in memory by `--cache-size`, the least recently used dropped first
+#[derive(Default)]
+pub struct Cache {
+    entries: Mutex<Entries>,
+}
+
+impl Cache {
+    /// The response cached under `key`, which now counts as the most recently used
+    pub fn get(&self, key: &str) -> Option<Arc<Cached>> {
+        let entries = &mut *self.entries.lock().unwrap();
+        let entry = entries.by_key.get_mut(key)?;
+        entries.clock += 1;
+        let key = entries.by_use.remove(&entry.used)?;
+        entry.used = entries.clock;
+        entries.by_use.insert(entry.used, key);
+        Some(entry.response.clone())
+    }
+
+    /// Cache `response` under `key`, dropping others until all of them fit in `limit` bytes.
+    /// A response that alone is bigger isn't cached.
+    pub fn insert(&self, key: String, response: Cached, limit: usize) {
+        let size = response.size(&key);
+        let entries = &mut *self.entries.lock().unwrap();
+        entries.remove(&key);
+        if size > limit {
+            return;
+        }
+        while entries.size + size > limit {
+            let Some((_, oldest)) = entries.by_use.pop_first() else {
+                break;
+            };
+            entries.remove(&oldest);
+        }
+        entries.clock += 1;
+        let used = entries.clock;
+        entries.by_use.insert(used, key.clone());
+        entries.size += size;
+        entries.by_key.insert(
+            key,
+            Entry {
+                response: Arc::new(response),
+                size,
+                used,
+            },
+        );
+    }
+
+    /// Bytes the cached responses take up
+    pub fn size(&self) -> usize {
+        self.entries.lock().unwrap().size
+    }
+}
+
+/// The key a request is cached under, its method, host and path with the query
+pub fn key(method: &http::Method, host: Option<&str>, path: &str) -> String {
+    format!("{method} {} {path}", host.unwrap_or_default())
+}
+
+/// The comma separated elements of all `name` headers
+fn elements(
+    headers: &http::HeaderMap,
+    name: http::header::HeaderName,
+) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(name)
+        .into_iter()
+        .map(|value| value.to_str().unwrap_or("*"))
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+}
+
+/// Whether `headers` have a `Cache-Control: no-store`
+pub fn no_store(headers: &http::HeaderMap) -> bool {
+    elements(headers, http::header::CACHE_CONTROL)
+        .any(|directive| directive.eq_ignore_ascii_case("no-store"))
+}
+
+/// Whether a response with `headers` can go to every client asking for it. One that sets a
+/// cookie, is `private` or varies by more than the `Accept-Encoding` the cache tells apart
+/// already is meant for the client that got it.
+pub fn storable(headers: &http::HeaderMap) -> bool {
+    !headers.contains_key(http::header::SET_COOKIE)
+        && !elements(headers, http::header::CACHE_CONTROL).any(|directive| {
+            let name = directive.split('=').next().unwrap_or_default().trim();
+            name.eq_ignore_ascii_case("no-store") || name.eq_ignore_ascii_case("private")
+        })
+        && elements(headers, http::header::VARY)
+            .all(|name| name.is_empty() || name.eq_ignore_ascii_case("accept-encoding"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(body: &'static [u8]) -> Cached {
+        Cached {
+            header: ResponseHeader::build(200, None).unwrap(),
+            body: Bytes::from_static(body),
+        }
+    }
+
+    #[test]
+    fn lru() {
+        let cache = Cache::default();
+        let size = response(b"0123456789").size("a");
+        cache.insert("a".to_string(), response(b"0123456789"), 2 * size);
+        cache.insert("b".to_string(), response(b"0123456789"), 2 * size);
+        assert_eq!(cache.size(), 2 * size);
+        // "a" is used more recently than "b" now, "b" makes room for "c"
+        assert!(cache.get("a").is_some());
+        cache.insert("c".to_string(), response(b"0123456789"), 2 * size);
+        assert!(cache.get("b").is_none());
+        assert_eq!(&cache.get("a").unwrap().body[..], b"0123456789");
+        assert!(cache.get("c").is_some());
+        assert_eq!(cache.size(), 2 * size);
+
+        // replaced, not added
+        cache.insert("c".to_string(), response(b"01234"), 2 * size);
+        assert_eq!(&cache.get("c").unwrap().body[..], b"01234");
+        assert_eq!(cache.size(), size + response(b"01234").size("c"));
+
+        // too big to cache at all, the old one is gone as well
+        cache.insert("a".to_string(), response(b"0123456789"), size - 1);
+        assert!(cache.get("a").is_none());
+    }
+
+    #[test]
+    fn directives() {
+        let mut headers = http::HeaderMap::new();
+        assert!(!no_store(&headers));
+        headers.append(http::header::CACHE_CONTROL, "max-age=60".parse().unwrap());
+        assert!(!no_store(&headers));
+        headers.append(
+            http::header::CACHE_CONTROL,
+            "private, No-Store".parse().unwrap(),
+        );
+        assert!(no_store(&headers));
+        assert!(!storable(&headers));
+
+        // meant for one client
+        let mut headers = http::HeaderMap::new();
+        headers.append(http::header::VARY, "Accept-Encoding".parse().unwrap());
+        headers.append(http::header::CACHE_CONTROL, "max-age=60".parse().unwrap());
+        assert!(storable(&headers));
+        for (name, value) in [
+            (http::header::SET_COOKIE, "session=1"),
+            (http::header::CACHE_CONTROL, "private=\"x-user\""),
+            (http::header::VARY, "accept-encoding, User-Agent"),
+            (http::header::VARY, "*"),
+        ] {
+            let mut headers = headers.clone();
+            headers.append(name, value.parse().unwrap());
+            assert!(!storable(&headers), "{headers:?}");
+        }
+
+        assert_eq!(
+            key(&http::Method::GET, Some("example.com"), "/a?b=c"),
+            "GET example.com /a?b=c"
+        );
+    }
+}
@@ -12,12 +12,235 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::pool;
+use brotli::{CompressorWriter, DecompressorWriter};
 use bytes::Bytes;
-use flate2::write::{GzDecoder, GzEncoder};
+use clap::ValueEnum;
+use flate2::GzBuilder;
+use flate2::write::{GzEncoder, MultiGzDecoder, ZlibDecoder, ZlibEncoder};
 use pingora::protocols::http::compression::COMPRESSION_ERROR;
-use pingora::{OrErr, Result};
+use pingora::{Error, OrErr, Result};
+use std::fmt;
 use std::io::Write;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
+use zstd::stream::raw::DParameter;
+use zstd::stream::{raw, zio};
+
+/// Largest window zstd decodes frames with unless told otherwise, 128 MiB
+pub const ZSTD_WINDOW_LOG_MAX: u32 = 27;
+
+/// The codings the proxy has an `Encode` for, both ways
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Gzip,
+    Zstd,
+    #[value(alias = "br")]
+    Brotli,
+    Deflate,
+    /// No coding at all, the body passes through as is
+    Identity,
+    /// A codec of `register_codec` under a name none of the others has. Bodies are compressed
+    /// with it, the proxy has nothing to decode it with.
+    #[value(skip)]
+    Registered(&'static str),
+}
+
+impl Algorithm {
+    /// The `Content-Encoding`/`Accept-Encoding` token of this algorithm
+    pub fn content_encoding(&self) -> &'static str {
+        match self {
+            Algorithm::Gzip => "gzip",
+            Algorithm::Zstd => "zstd",
+            Algorithm::Brotli => "br",
+            Algorithm::Deflate => "deflate",
+            Algorithm::Identity => "identity",
+            Algorithm::Registered(name) => name,
+        }
+    }
+
+    /// The algorithm an `--algorithm` name picks, one of the built-in ones or else a codec of
+    /// `register_codec`
+    pub fn by_name(name: &str) -> std::result::Result<Self, String> {
+        let name = name.trim();
+        <Self as ValueEnum>::from_str(name, true).or_else(|e| {
+            registered(name)
+                .map(|(name, _)| Algorithm::Registered(name))
+                .ok_or(e)
+        })
+    }
+
+    /// The algorithm behind a `Content-Encoding`/`Accept-Encoding` token, if the proxy has one
+    pub fn from_content_encoding(token: &str) -> Option<Self> {
+        let token = token.trim();
+        Self::value_variants()
+            .iter()
+            .copied()
+            .find(|algorithm| algorithm.content_encoding().eq_ignore_ascii_case(token))
+            .or_else(|| {
+                token
+                    .eq_ignore_ascii_case("x-gzip")
+                    .then_some(Algorithm::Gzip)
+            })
+    }
+
+    /// The compression levels this algorithm has. A registered codec takes any level, what it
+    /// makes of one is up to its factory.
+    pub fn levels(&self) -> std::ops::RangeInclusive<i32> {
+        if registered(self.content_encoding()).is_some() {
+            return i32::MIN..=i32::MAX;
+        }
+        match self {
+            Algorithm::Gzip | Algorithm::Deflate => 0..=9,
+            Algorithm::Zstd => -7..=22,
+            Algorithm::Brotli => 0..=11,
+            Algorithm::Identity => 0..=0,
+            Algorithm::Registered(_) => i32::MIN..=i32::MAX,
+        }
+    }
+
+    /// Level used when none is given, the same the proxy defaults to
+    pub fn default_level(&self) -> i32 {
+        match self {
+            Algorithm::Gzip | Algorithm::Zstd | Algorithm::Deflate | Algorithm::Registered(_) => 6,
+            Algorithm::Brotli => 5,
+            Algorithm::Identity => 0,
+        }
+    }
+
+    /// A compressor at `level`, or `default_level`. Errors when the level is out of
+    /// `levels`.
+    pub fn compressor(&self, level: Option<i32>) -> Result<Box<dyn Encode + Send + Sync>> {
+        let level = level.unwrap_or(self.default_level());
+        if let Some(registered) = registered_codec(self.content_encoding(), level) {
+            return Ok(registered);
+        }
+        let levels = self.levels();
+        if !levels.contains(&level) {
+            return Error::e_explain(
+                COMPRESSION_ERROR,
+                format!(
+                    "{self:?} level must be {} to {}, got {level}",
+                    levels.start(),
+                    levels.end()
+                ),
+            );
+        }
+        // in range, so not negative but for zstd
+        Ok(match self {
+            Algorithm::Gzip => Box::new(Compressor::new(level as u32)),
+            Algorithm::Zstd => Box::new(ZstdCompressor::new(level)?),
+            Algorithm::Brotli => Box::new(BrotliCompressor::new(level as u32)),
+            Algorithm::Deflate => Box::new(DeflateCompressor::new(level as u32)),
+            Algorithm::Identity => Box::new(Identity::default()),
+            // registered codecs stay registered
+            Algorithm::Registered(name) => {
+                return Error::e_explain(COMPRESSION_ERROR, format!("no codec {name} registered"));
+            }
+        })
+    }
+
+    /// A decompressor producing at most `max_decompressed_bytes`
+    pub fn decompressor(
+        &self,
+        max_decompressed_bytes: usize,
+    ) -> Result<Box<dyn Encode + Send + Sync>> {
+        let limit = max_decompressed_bytes;
+        Ok(match self {
+            Algorithm::Gzip => Box::new(Decompressor::with_limit(limit)),
+            Algorithm::Zstd => Box::new(ZstdDecompressor::with_limit(limit)?),
+            Algorithm::Brotli => Box::new(BrotliDecompressor::with_limit(limit)),
+            Algorithm::Deflate => Box::new(DeflateDecompressor::with_limit(limit)),
+            Algorithm::Identity => Box::new(Identity::default()),
+            Algorithm::Registered(name) => {
+                return Error::e_explain(
+                    COMPRESSION_ERROR,
+                    format!("{name} is a registered codec, it can't be decoded"),
+                );
+            }
+        })
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.content_encoding())
+    }
+}
+
+/// Parses a `Content-Encoding` token, see `from_content_encoding`
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(token: &str) -> std::result::Result<Self, String> {
+        Self::from_content_encoding(token).ok_or_else(|| format!("unknown coding {token:?}"))
+    }
+}
+
+// ====================== Registered codecs ======================
+
+/// Makes an encoder of a registered codec at a compression level
+pub type CodecFactory = Box<dyn Fn(i32) -> Box<dyn Encode + Send> + Send + Sync>;
+
+/// Codecs of `register_codec` by name
+static CODECS: RwLock<Vec<(&'static str, Arc<CodecFactory>)>> = RwLock::new(Vec::new());
+
+/// Register `factory` as the compressor of the codec `name`, in place of one registered under
+/// that name before. Names are `Content-Encoding` tokens: the codec registered under the token
+/// of an `Algorithm` is used instead of the built-in compressor wherever that algorithm is
+/// picked, for `--algorithm`, routes and transcoding alike. Other names are picked as
+/// `Algorithm::Registered`, see `Algorithm::by_name`; register them before the config is parsed.
+pub fn register_codec(name: &str, factory: CodecFactory) {
+    let mut codecs = CODECS.write().unwrap();
+    let factory = Arc::new(factory);
+    match codecs
+        .iter_mut()
+        .find(|(registered, _)| registered.eq_ignore_ascii_case(name))
+    {
+        Some((_, registered)) => *registered = factory,
+        // never dropped, so an `Algorithm` can name it and stay `Copy`
+        None => codecs.push((Box::leak(name.into()), factory)),
+    }
+}
+
+/// The name and factory of the codec registered under `name`
+fn registered(name: &str) -> Option<(&'static str, Arc<CodecFactory>)> {
+    CODECS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(registered, _)| registered.eq_ignore_ascii_case(name))
+        .map(|(name, factory)| (*name, factory.clone()))
+}
+
+/// An encoder at `level` of the codec registered under `name`, if there is one
+pub fn registered_codec(name: &str, level: i32) -> Option<Box<dyn Encode + Send + Sync>> {
+    let (_, factory) = registered(name)?;
+    // the lock is released, a factory may register codecs itself
+    Some(Box::new(Registered(Mutex::new(factory(level)))))
+}
+
+/// An encoder of a registered codec, which only has to be `Send`
+struct Registered(Mutex<Box<dyn Encode + Send>>);
+
+impl Encode for Registered {
+    fn encode(&mut self, input: &[u8], end: bool) -> Result<Bytes> {
+        self.0.get_mut().unwrap().encode(input, end)
+    }
+
+    fn stat(&self) -> (&'static str, usize, usize, Duration) {
+        self.0.lock().unwrap().stat()
+    }
+
+    fn has_pending(&self) -> bool {
+        self.0.lock().unwrap().has_pending()
+    }
+
+    fn stats(&self) -> Vec<(&'static str, usize, usize, Duration)> {
+        self.0.lock().unwrap().stats()
+    }
+}
 
 pub trait Encode {
     /// Encode the input bytes. The `end` flag signals the end of the entire input. The `end` flag
@@ -27,23 +250,164 @@ pub trait Encode {
     /// Return the Encoder's name, the total input bytes, the total output bytes and the total
     /// duration spent on encoding the data.
     fn stat(&self) -> (&'static str, usize, usize, Duration);
+    /// Whether there is output the last `encode` held back, which `encode(&[], end)` returns the
+    /// next part of. Only encoders limiting their output per call hold any back.
+    fn has_pending(&self) -> bool {
+        false
+    }
+    /// The `stat` of each codec doing the work, which an encoder made of others has several of
+    fn stats(&self) -> Vec<(&'static str, usize, usize, Duration)> {
+        vec![self.stat()]
+    }
+}
+
+/// Error out when a decoder has produced more than `max` bytes in total. This is the guard against
+/// decompression bombs: a tiny compressed body can expand to gigabytes.
+fn check_decompressed_size(name: &str, total_out: usize, max: usize) -> Result<()> {
+    if total_out > max {
+        return Error::e_explain(
+            COMPRESSION_ERROR,
+            format!("{name} decompressed body exceeds {max} bytes"),
+        );
+    }
+    Ok(())
+}
+
+/// Decompressors take their input in slices of at most this many bytes. However big a chunk
+/// the client sends, what they reserve up front stays small and a bomb is stopped by
+/// `check_decompressed_size` after one slice, not once the whole chunk has expanded.
+const MAX_INPUT_SLICE: usize = 16 * 1024;
+
+/// Count `input` more bytes towards the next flush of a compressor, true when `interval` bytes
+/// have come in since the last one
+fn flush_due(unflushed: &mut usize, input: usize, interval: usize) -> bool {
+    *unflushed = unflushed.saturating_add(input);
+    if *unflushed < interval {
+        return false;
+    }
+    *unflushed = 0;
+    true
+}
+
+/// Capacity of the buffers pooled codecs start out with
+const POOLED_BUF_SIZE: usize = 16 * 1024;
+
+/// What a codec wrote to `buf` in this call. A pooled buffer is handed over without a copy and
+/// replaced by another from the pool, where it returns once the output is dropped.
+fn take_output(buf: &mut Vec<u8>, pooled: bool) -> Bytes {
+    if !pooled {
+        return std::mem::take(buf).into(); // into() Bytes will drop excess capacity
+    }
+    if buf.is_empty() {
+        return Bytes::new();
+    }
+    pool::freeze(std::mem::replace(buf, pool::take(POOLED_BUF_SIZE)))
 }
 
 pub struct Decompressor {
-    decompress: GzDecoder<Vec<u8>>,
+    decompress: MultiGzDecoder<Vec<u8>>,
     total_in: usize,
     total_out: usize,
     duration: Duration,
+    max_decompressed_bytes: usize,
+    /// The output buffer came from `pool` and is kept across calls
+    pooled: bool,
+    /// Most bytes one `encode` returns, `usize::MAX` unless set with `with_output_chunk`
+    output_chunk: usize,
+    /// Input not decoded yet because the output of a call was full
+    pending_input: Vec<u8>,
+    /// The end of the input was seen, `finished` once the decoder was told
+    pending_end: bool,
+    finished: bool,
 }
 
+/// Most bytes a deflate stream can expand one byte to
+const MAX_DEFLATE_RATIO: usize = 1032;
+
 impl Decompressor {
     pub fn new() -> Self {
+        Self::with_limit(usize::MAX)
+    }
+
+    /// Create a decompressor that errors out once more than `max_decompressed_bytes` bytes
+    /// were produced in total
+    pub fn with_limit(max_decompressed_bytes: usize) -> Self {
         Decompressor {
-            decompress: GzDecoder::new(vec![]),
+            decompress: MultiGzDecoder::new(vec![]),
             total_in: 0,
             total_out: 0,
             duration: Duration::new(0, 0),
+            max_decompressed_bytes,
+            pooled: false,
+            output_chunk: usize::MAX,
+            pending_input: Vec::new(),
+            pending_end: false,
+            finished: false,
+        }
+    }
+
+    /// Return at most `size` bytes from each `encode`, decoding no more input than it takes to
+    /// fill them. The rest is kept for later calls, see `Encode::has_pending`, so however much
+    /// a body expands the decoded bytes held at a time stay around `size`.
+    pub fn with_output_chunk(mut self, size: usize) -> Self {
+        self.output_chunk = size.max(1);
+        self
+    }
+
+    fn encode_chunked(&mut self, input: &[u8], end: bool) -> Result<Bytes> {
+        self.pending_input.extend_from_slice(input);
+        self.pending_end |= end;
+        // any piece this size can't expand past a chunk
+        let piece = (self.output_chunk / MAX_DEFLATE_RATIO).max(1);
+        let mut fed = 0;
+        while fed < self.pending_input.len() && self.decompress.get_ref().len() < self.output_chunk
+        {
+            let len = piece.min(self.pending_input.len() - fed);
+            self.decompress
+                .write_all(&self.pending_input[fed..fed + len])
+                .and_then(|()| self.decompress.flush())
+                .or_err(COMPRESSION_ERROR, "while decompress Gzip")?;
+            fed += len;
+            let total_out = self.total_out + self.decompress.get_ref().len();
+            check_decompressed_size("Gzip", total_out, self.max_decompressed_bytes)?;
+        }
+        self.pending_input.drain(..fed);
+        if self.pending_input.is_empty() && self.pending_end && !self.finished {
+            self.decompress
+                .try_finish()
+                .or_err(COMPRESSION_ERROR, "while decompress Gzip")?;
+            self.finished = true;
+            let total_out = self.total_out + self.decompress.get_ref().len();
+            check_decompressed_size("Gzip", total_out, self.max_decompressed_bytes)?;
         }
+        let buf = self.decompress.get_mut();
+        let len = buf.len().min(self.output_chunk);
+        let output = Bytes::copy_from_slice(&buf[..len]);
+        buf.drain(..len);
+        self.total_out += len;
+        Ok(output)
+    }
+
+    /// Decode into a buffer from this thread's `pool` that is reused for every call and
+    /// returned when the decompressor is dropped
+    pub fn with_buffer_pool(mut self) -> Self {
+        *self.decompress.get_mut() = pool::take(POOLED_BUF_SIZE);
+        self.pooled = true;
+        self
+    }
+}
+
+impl Drop for Decompressor {
+    fn drop(&mut self) {
+        if self.pooled {
+            pool::give(std::mem::take(self.decompress.get_mut()));
+        }
+    }
+}
+
+impl Default for Decompressor {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -53,32 +417,54 @@ impl Encode for Decompressor {
         const ESTIMATED_COMPRESSION_RATIO: usize = 3; // estimated 2.5-3x compression
         let start = Instant::now();
         self.total_in += input.len();
+        if self.output_chunk != usize::MAX {
+            let output = self.encode_chunked(input, end);
+            self.duration += start.elapsed();
+            return output;
+        }
         // cap the buf size amplification, there is a DoS risk of always allocate
         // 3x the memory of the input buffer
-        let reserve_size = if input.len() < MAX_INIT_COMPRESSED_SIZE_CAP {
-            input.len() * ESTIMATED_COMPRESSION_RATIO
-        } else {
-            input.len()
-        };
-        self.decompress.get_mut().reserve(reserve_size);
-        self.decompress
-            .write_all(input)
-            .or_err(COMPRESSION_ERROR, "while decompress Gzip")?;
+        for slice in input.chunks(MAX_INPUT_SLICE) {
+            let reserve_size = if slice.len() < MAX_INIT_COMPRESSED_SIZE_CAP {
+                slice.len() * ESTIMATED_COMPRESSION_RATIO
+            } else {
+                slice.len()
+            };
+            self.decompress.get_mut().reserve(reserve_size);
+            self.decompress
+                .write_all(slice)
+                .or_err(COMPRESSION_ERROR, "while decompress Gzip")?;
+            let total_out = self.total_out + self.decompress.get_ref().len();
+            check_decompressed_size("Gzip", total_out, self.max_decompressed_bytes)?;
+        }
         // write to vec will never fail, only possible error is that the input data
         // was not actually gzip compressed
         if end {
             self.decompress
                 .try_finish()
                 .or_err(COMPRESSION_ERROR, "while decompress Gzip")?;
+        } else {
+            // the decoder holds on to its output until the next write otherwise
+            self.decompress
+                .flush()
+                .or_err(COMPRESSION_ERROR, "while decompress Gzip")?;
         }
         self.total_out += self.decompress.get_ref().len();
+        check_decompressed_size("Gzip", self.total_out, self.max_decompressed_bytes)?;
+        let output = take_output(self.decompress.get_mut(), self.pooled);
         self.duration += start.elapsed();
-        Ok(std::mem::take(self.decompress.get_mut()).into()) // into() Bytes will drop excess capacity
+        Ok(output)
     }
 
     fn stat(&self) -> (&'static str, usize, usize, Duration) {
         ("de-gzip", self.total_in, self.total_out, self.duration)
     }
+
+    fn has_pending(&self) -> bool {
+        !self.pending_input.is_empty()
+            || !self.decompress.get_ref().is_empty()
+            || (self.pending_end && !self.finished)
+    }
 }
 
 pub struct Compressor {
@@ -87,17 +473,62 @@ pub struct Compressor {
     total_in: usize,
     total_out: usize,
     duration: Duration,
+    /// `usize::MAX` unless set with `with_flush_interval`
+    flush_interval: usize,
+    unflushed: usize,
+    /// See `Decompressor::pooled`
+    pooled: bool,
+}
+
+/// Fields of the header `Compressor` starts its stream with. The default, what
+/// `Compressor::new` writes, has no file name, a zero mtime and 255 (unknown) as the OS, so
+/// the same body always compresses to the same bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GzipHeader {
+    pub filename: Option<Vec<u8>>,
+    /// Seconds since the Unix epoch, 0 for none
+    pub mtime: u32,
+    pub os: Option<u8>,
 }
 
 impl Compressor {
     pub fn new(level: u32) -> Compressor {
+        Self::with_header(level, &GzipHeader::default())
+    }
+
+    /// Like `new`, with `header` in the gzip header
+    pub fn with_header(level: u32, header: &GzipHeader) -> Compressor {
+        let mut builder = GzBuilder::new().mtime(header.mtime);
+        if let Some(filename) = &header.filename {
+            builder = builder.filename(filename.clone());
+        }
+        if let Some(os) = header.os {
+            builder = builder.operating_system(os);
+        }
         Compressor {
-            compress: GzEncoder::new(vec![], flate2::Compression::new(level)),
+            compress: builder.write(vec![], flate2::Compression::new(level)),
             total_in: 0,
             total_out: 0,
             duration: Duration::new(0, 0),
+            flush_interval: usize::MAX,
+            unflushed: 0,
+            pooled: false,
         }
     }
+
+    /// See `Decompressor::with_buffer_pool`
+    pub fn with_buffer_pool(mut self) -> Self {
+        *self.compress.get_mut() = pool::take(POOLED_BUF_SIZE);
+        self.pooled = true;
+        self
+    }
+
+    /// Flush whenever `bytes` of input came in since the last flush, so a streamed body is
+    /// passed on before it ends
+    pub fn with_flush_interval(mut self, bytes: usize) -> Self {
+        self.flush_interval = bytes;
+        self
+    }
 }
 
 impl Encode for Compressor {
@@ -111,12 +542,16 @@ impl Encode for Compressor {
             .get_mut()
             .reserve(std::cmp::min(MAX_INIT_COMPRESSED_BUF_SIZE, input.len()));
         self.write_all(input).unwrap(); // write to vec, should never fail
+        let flush = flush_due(&mut self.unflushed, input.len(), self.flush_interval);
         if end {
             self.try_finish().unwrap(); // write to vec, should never fail
+        } else if flush {
+            self.flush().unwrap(); // write to vec, should never fail
         }
         self.total_out += self.compress.get_ref().len();
+        let output = take_output(self.compress.get_mut(), self.pooled);
         self.duration += start.elapsed();
-        Ok(std::mem::take(self.compress.get_mut()).into()) // into() Bytes will drop excess capacity
+        Ok(output)
     }
 
     fn stat(&self) -> (&'static str, usize, usize, Duration) {
@@ -124,9 +559,17 @@ impl Encode for Compressor {
     }
 }
 
+impl Drop for Compressor {
+    fn drop(&mut self) {
+        if self.pooled {
+            pool::give(std::mem::take(self.compress.get_mut()));
+        }
+    }
+}
+
 use std::ops::{Deref, DerefMut};
 impl Deref for Decompressor {
-    type Target = GzDecoder<Vec<u8>>;
+    type Target = MultiGzDecoder<Vec<u8>>;
 
     fn deref(&self) -> &Self::Target {
         &self.decompress
@@ -160,18 +603,63 @@ pub struct ZstdCompressor {
     total_in: usize,
     total_out: usize,
     duration: Duration,
+    /// `usize::MAX` unless set with `with_flush_interval`
+    flush_interval: usize,
+    unflushed: usize,
 }
 
 impl ZstdCompressor {
-    pub fn new(level: i32) -> Self {
+    pub fn new(level: i32) -> Result<Self> {
         let buf = Vec::new();
-        let encoder = zstd::stream::write::Encoder::new(buf, level).unwrap();
-        Self {
+        let encoder = zstd::stream::write::Encoder::new(buf, level)
+            .or_err(COMPRESSION_ERROR, "while creating Zstd compressor")?;
+        Ok(Self {
             compress: encoder,
             total_in: 0,
             total_out: 0,
             duration: Duration::new(0, 0),
-        }
+            flush_interval: usize::MAX,
+            unflushed: 0,
+        })
+    }
+
+    /// Create a compressor whose frames can only be decoded with the same `dictionary`
+    pub fn with_dictionary(level: i32, dictionary: &[u8]) -> Result<Self> {
+        let encoder = zstd::stream::write::Encoder::with_dictionary(Vec::new(), level, dictionary)
+            .or_err(COMPRESSION_ERROR, "while loading Zstd dictionary")?;
+        Ok(Self {
+            compress: encoder,
+            total_in: 0,
+            total_out: 0,
+            duration: Duration::new(0, 0),
+            flush_interval: usize::MAX,
+            unflushed: 0,
+        })
+    }
+
+    /// See `Compressor::with_flush_interval`
+    pub fn with_flush_interval(mut self, bytes: usize) -> Self {
+        self.flush_interval = bytes;
+        self
+    }
+
+    /// Find matches up to `2^log` bytes back instead of as far as the level does. The window
+    /// is held in memory while compressing and while decompressing.
+    pub fn with_window_log(mut self, log: u32) -> Result<Self> {
+        self.compress
+            .window_log(log)
+            .or_err(COMPRESSION_ERROR, "while setting Zstd window log")?;
+        Ok(self)
+    }
+
+    /// Look for matches far back in big bodies, in a window of `2^27` bytes unless set with
+    /// `with_window_log`
+    pub fn with_long_distance_matching(mut self) -> Result<Self> {
+        self.compress.long_distance_matching(true).or_err(
+            COMPRESSION_ERROR,
+            "while enabling Zstd long distance matching",
+        )?;
+        Ok(self)
     }
 }
 
@@ -182,11 +670,19 @@ impl Encode for ZstdCompressor {
         self.compress
             .get_mut()
             .reserve(std::cmp::min(16 * 1024, input.len()));
-        self.compress.write_all(input).unwrap();
+        self.compress
+            .write_all(input)
+            .or_err(COMPRESSION_ERROR, "while compress Zstd")?;
+        let flush = flush_due(&mut self.unflushed, input.len(), self.flush_interval);
         if end {
             self.compress
                 .do_finish()
-                .unwrap();
+                .or_err(COMPRESSION_ERROR, "while compress Zstd")?;
+        } else if flush {
+            // ends the current block, the frame goes on
+            self.compress
+                .flush()
+                .or_err(COMPRESSION_ERROR, "while compress Zstd")?;
         }
         self.total_out += self.compress.get_ref().len();
         self.duration += start.elapsed();
@@ -201,48 +697,462 @@ impl Encode for ZstdCompressor {
 // ====================== ZSTD Decompressor ======================
 
 pub struct ZstdDecompressor {
-    decompress: zstd::stream::write::Decoder<'static, Vec<u8>>,
+    // `write::Decoder` can only flush, the zio writer underneath can also finish the frame
+    decompress: zio::Writer<Vec<u8>, raw::Decoder<'static>>,
     total_in: usize,
     total_out: usize,
     duration: Duration,
+    max_decompressed_bytes: usize,
 }
 
 impl ZstdDecompressor {
-    pub fn new() -> Self {
+    pub fn new() -> Result<Self> {
+        Self::with_limit(usize::MAX)
+    }
+
+    /// Create a decompressor that errors out once more than `max_decompressed_bytes` bytes
+    /// were produced in total
+    pub fn with_limit(max_decompressed_bytes: usize) -> Result<Self> {
         // Vec<u8> 作为输出缓冲
         let buf = Vec::new();
-        let decoder = zstd::stream::write::Decoder::new(buf).unwrap();
+        let decoder =
+            raw::Decoder::new().or_err(COMPRESSION_ERROR, "while creating Zstd decompressor")?;
+        Ok(Self {
+            decompress: zio::Writer::new(buf, decoder),
+            total_in: 0,
+            total_out: 0,
+            duration: Duration::new(0, 0),
+            max_decompressed_bytes,
+        })
+    }
+
+    /// Like `with_limit`, for frames compressed with `dictionary`
+    pub fn with_dictionary(dictionary: &[u8], max_decompressed_bytes: usize) -> Result<Self> {
+        let decoder = raw::Decoder::with_dictionary(dictionary)
+            .or_err(COMPRESSION_ERROR, "while loading Zstd dictionary")?;
+        Ok(Self {
+            decompress: zio::Writer::new(Vec::new(), decoder),
+            total_in: 0,
+            total_out: 0,
+            duration: Duration::new(0, 0),
+            max_decompressed_bytes,
+        })
+    }
+
+    /// Accept frames with a window of up to `2^log` bytes, more than the default of
+    /// `ZSTD_WINDOW_LOG_MAX`
+    pub fn with_window_log_max(mut self, log: u32) -> Result<Self> {
+        self.decompress
+            .operation_mut()
+            .set_parameter(DParameter::WindowLogMax(log))
+            .or_err(COMPRESSION_ERROR, "while setting Zstd window log")?;
+        Ok(self)
+    }
+}
+
+impl Encode for ZstdDecompressor {
+    fn encode(&mut self, input: &[u8], end: bool) -> Result<Bytes> {
+        let start = Instant::now();
+        self.total_in += input.len();
+        for slice in input.chunks(MAX_INPUT_SLICE) {
+            self.decompress.writer_mut().reserve(slice.len() * 2);
+            self.decompress
+                .write_all(slice)
+                .or_err(COMPRESSION_ERROR, "while decompress Zstd")?;
+            let total_out = self.total_out + self.decompress.writer().len();
+            check_decompressed_size("Zstd", total_out, self.max_decompressed_bytes)?;
+        }
+        // finish() drains everything still buffered in the decoder and fails on a truncated
+        // frame, flush() would silently accept it
+        if end {
+            self.decompress
+                .finish()
+                .or_err(COMPRESSION_ERROR, "while decompress Zstd")?;
+        } else {
+            // the decoder holds on to its output until the next write otherwise
+            self.decompress
+                .flush()
+                .or_err(COMPRESSION_ERROR, "while decompress Zstd")?;
+        }
+        self.total_out += self.decompress.writer().len();
+        check_decompressed_size("Zstd", self.total_out, self.max_decompressed_bytes)?;
+        self.duration += start.elapsed();
+        Ok(std::mem::take(self.decompress.writer_mut()).into())
+    }
+
+    fn stat(&self) -> (&'static str, usize, usize, Duration) {
+        ("de-zstd", self.total_in, self.total_out, self.duration)
+    }
+}
+
+// ====================== Brotli Compressor ======================
+
+pub struct BrotliCompressor {
+    // the writer is consumed when the stream is finished, see `encode`
+    compress: Option<CompressorWriter<Vec<u8>>>,
+    total_in: usize,
+    total_out: usize,
+    duration: Duration,
+    /// `usize::MAX` unless set with `with_flush_interval`
+    flush_interval: usize,
+    unflushed: usize,
+}
+
+impl BrotliCompressor {
+    pub fn new(level: u32) -> Self {
         Self {
-            decompress: decoder,
+            // buf_size:4096 , lgwin:19 same as pingora's brotli compressor
+            compress: Some(CompressorWriter::new(vec![], 4096, level, 19)),
             total_in: 0,
             total_out: 0,
             duration: Duration::new(0, 0),
+            flush_interval: usize::MAX,
+            unflushed: 0,
         }
     }
+
+    /// See `Compressor::with_flush_interval`
+    pub fn with_flush_interval(mut self, bytes: usize) -> Self {
+        self.flush_interval = bytes;
+        self
+    }
 }
 
-impl Encode for ZstdDecompressor {
+impl Encode for BrotliCompressor {
     fn encode(&mut self, input: &[u8], end: bool) -> Result<Bytes> {
+        // reserve at most 16k
+        const MAX_INIT_COMPRESSED_BUF_SIZE: usize = 16 * 1024;
         let start = Instant::now();
         self.total_in += input.len();
-        self.decompress.get_mut().reserve(input.len() * 2);
-        self.decompress
+        let Some(compress) = self.compress.as_mut() else {
+            return Error::e_explain(COMPRESSION_ERROR, "brotli stream already finished");
+        };
+        compress
+            .get_mut()
+            .reserve(std::cmp::min(MAX_INIT_COMPRESSED_BUF_SIZE, input.len()));
+        compress
             .write_all(input)
-            .or_err(COMPRESSION_ERROR, "while decompress Zstd")?;
+            .or_err(COMPRESSION_ERROR, "while compress Brotli")?;
+        if !end && flush_due(&mut self.unflushed, input.len(), self.flush_interval) {
+            compress
+                .flush()
+                .or_err(COMPRESSION_ERROR, "while compress Brotli")?;
+        }
+        // flush() only emits a sync point, the last meta-block is written when the writer is
+        // consumed, so take it out on `end`
+        let output = if end {
+            self.compress.take().unwrap().into_inner()
+        } else {
+            std::mem::take(compress.get_mut())
+        };
+        self.total_out += output.len();
+        self.duration += start.elapsed();
+        Ok(output.into()) // into() Bytes will drop excess capacity
+    }
+
+    fn stat(&self) -> (&'static str, usize, usize, Duration) {
+        ("br", self.total_in, self.total_out, self.duration)
+    }
+}
+
+// ====================== Brotli Decompressor ======================
+
+pub struct BrotliDecompressor {
+    decompress: DecompressorWriter<Vec<u8>>,
+    total_in: usize,
+    total_out: usize,
+    duration: Duration,
+    max_decompressed_bytes: usize,
+}
+
+impl BrotliDecompressor {
+    pub fn new() -> Self {
+        Self::with_limit(usize::MAX)
+    }
+
+    /// Create a decompressor that errors out once more than `max_decompressed_bytes` bytes
+    /// were produced in total
+    pub fn with_limit(max_decompressed_bytes: usize) -> Self {
+        Self {
+            // default buf is 4096 if 0 is used
+            decompress: DecompressorWriter::new(vec![], 0),
+            total_in: 0,
+            total_out: 0,
+            duration: Duration::new(0, 0),
+            max_decompressed_bytes,
+        }
+    }
+}
+
+impl Default for BrotliDecompressor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encode for BrotliDecompressor {
+    fn encode(&mut self, input: &[u8], end: bool) -> Result<Bytes> {
+        const MAX_INIT_COMPRESSED_SIZE_CAP: usize = 4 * 1024;
+        // Brotli compress ratio can be 3.5 to 4.5
+        const ESTIMATED_COMPRESSION_RATIO: usize = 4;
+        let start = Instant::now();
+        self.total_in += input.len();
+        // cap the buf size amplification, there is a DoS risk of always allocate
+        // 4x the memory of the input buffer
+        for slice in input.chunks(MAX_INPUT_SLICE) {
+            let reserve_size = if slice.len() < MAX_INIT_COMPRESSED_SIZE_CAP {
+                slice.len() * ESTIMATED_COMPRESSION_RATIO
+            } else {
+                slice.len()
+            };
+            self.decompress.get_mut().reserve(reserve_size);
+            self.decompress
+                .write_all(slice)
+                .or_err(COMPRESSION_ERROR, "while decompress Brotli")?;
+            let total_out = self.total_out + self.decompress.get_ref().len();
+            check_decompressed_size("Brotli", total_out, self.max_decompressed_bytes)?;
+        }
+        // write to vec will never fail, only possible error is that the input data
+        // was not actually brotli compressed or is truncated
         if end {
             self.decompress
-                .flush().unwrap();
+                .close()
+                .or_err(COMPRESSION_ERROR, "while decompress Brotli")?;
         }
         self.total_out += self.decompress.get_ref().len();
+        check_decompressed_size("Brotli", self.total_out, self.max_decompressed_bytes)?;
         self.duration += start.elapsed();
         Ok(std::mem::take(self.decompress.get_mut()).into())
     }
 
     fn stat(&self) -> (&'static str, usize, usize, Duration) {
-        ("de-zstd", self.total_in, self.total_out, self.duration)
+        ("de-br", self.total_in, self.total_out, self.duration)
     }
 }
 
+// ====================== Deflate Compressor ======================
+
+// HTTP `deflate` is the zlib format (RFC 1950), not a raw deflate stream
+pub struct DeflateCompressor {
+    compress: ZlibEncoder<Vec<u8>>,
+    total_in: usize,
+    total_out: usize,
+    duration: Duration,
+    /// `usize::MAX` unless set with `with_flush_interval`
+    flush_interval: usize,
+    unflushed: usize,
+}
+
+impl DeflateCompressor {
+    pub fn new(level: u32) -> Self {
+        Self {
+            compress: ZlibEncoder::new(vec![], flate2::Compression::new(level)),
+            total_in: 0,
+            total_out: 0,
+            duration: Duration::new(0, 0),
+            flush_interval: usize::MAX,
+            unflushed: 0,
+        }
+    }
+
+    /// See `Compressor::with_flush_interval`
+    pub fn with_flush_interval(mut self, bytes: usize) -> Self {
+        self.flush_interval = bytes;
+        self
+    }
+}
+
+impl Encode for DeflateCompressor {
+    // infallible because compression can take any data
+    fn encode(&mut self, input: &[u8], end: bool) -> Result<Bytes> {
+        // reserve at most 16k
+        const MAX_INIT_COMPRESSED_BUF_SIZE: usize = 16 * 1024;
+        let start = Instant::now();
+        self.total_in += input.len();
+        self.compress
+            .get_mut()
+            .reserve(std::cmp::min(MAX_INIT_COMPRESSED_BUF_SIZE, input.len()));
+        self.compress.write_all(input).unwrap(); // write to vec, should never fail
+        let flush = flush_due(&mut self.unflushed, input.len(), self.flush_interval);
+        if end {
+            self.compress.try_finish().unwrap(); // write to vec, should never fail
+        } else if flush {
+            self.compress.flush().unwrap(); // write to vec, should never fail
+        }
+        self.total_out += self.compress.get_ref().len();
+        self.duration += start.elapsed();
+        Ok(std::mem::take(self.compress.get_mut()).into()) // into() Bytes will drop excess capacity
+    }
+
+    fn stat(&self) -> (&'static str, usize, usize, Duration) {
+        ("deflate", self.total_in, self.total_out, self.duration)
+    }
+}
+
+// ====================== Deflate Decompressor ======================
+
+pub struct DeflateDecompressor {
+    decompress: ZlibDecoder<Vec<u8>>,
+    total_in: usize,
+    total_out: usize,
+    duration: Duration,
+    max_decompressed_bytes: usize,
+}
+
+impl DeflateDecompressor {
+    pub fn new() -> Self {
+        Self::with_limit(usize::MAX)
+    }
+
+    /// Create a decompressor that errors out once more than `max_decompressed_bytes` bytes
+    /// were produced in total
+    pub fn with_limit(max_decompressed_bytes: usize) -> Self {
+        Self {
+            decompress: ZlibDecoder::new(vec![]),
+            total_in: 0,
+            total_out: 0,
+            duration: Duration::new(0, 0),
+            max_decompressed_bytes,
+        }
+    }
+}
+
+impl Default for DeflateDecompressor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encode for DeflateDecompressor {
+    fn encode(&mut self, input: &[u8], end: bool) -> Result<Bytes> {
+        const MAX_INIT_COMPRESSED_SIZE_CAP: usize = 4 * 1024;
+        const ESTIMATED_COMPRESSION_RATIO: usize = 3; // estimated 2.5-3x compression
+        let start = Instant::now();
+        self.total_in += input.len();
+        // cap the buf size amplification, there is a DoS risk of always allocate
+        // 3x the memory of the input buffer
+        for slice in input.chunks(MAX_INPUT_SLICE) {
+            let reserve_size = if slice.len() < MAX_INIT_COMPRESSED_SIZE_CAP {
+                slice.len() * ESTIMATED_COMPRESSION_RATIO
+            } else {
+                slice.len()
+            };
+            self.decompress.get_mut().reserve(reserve_size);
+            self.decompress
+                .write_all(slice)
+                .or_err(COMPRESSION_ERROR, "while decompress Deflate")?;
+            let total_out = self.total_out + self.decompress.get_ref().len();
+            check_decompressed_size("Deflate", total_out, self.max_decompressed_bytes)?;
+        }
+        if end {
+            self.decompress
+                .try_finish()
+                .or_err(COMPRESSION_ERROR, "while decompress Deflate")?;
+        } else {
+            // the decoder holds on to its output until the next write otherwise
+            self.decompress
+                .flush()
+                .or_err(COMPRESSION_ERROR, "while decompress Deflate")?;
+        }
+        self.total_out += self.decompress.get_ref().len();
+        check_decompressed_size("Deflate", self.total_out, self.max_decompressed_bytes)?;
+        self.duration += start.elapsed();
+        Ok(std::mem::take(self.decompress.get_mut()).into())
+    }
+
+    fn stat(&self) -> (&'static str, usize, usize, Duration) {
+        ("de-deflate", self.total_in, self.total_out, self.duration)
+    }
+}
+
+// ====================== Identity ======================
+
+/// Hands out its input as is, for `Algorithm::Identity`
+#[derive(Default)]
+pub struct Identity {
+    total: usize,
+}
+
+impl Encode for Identity {
+    fn encode(&mut self, input: &[u8], _end: bool) -> Result<Bytes> {
+        self.total += input.len();
+        Ok(Bytes::copy_from_slice(input))
+    }
+
+    fn stat(&self) -> (&'static str, usize, usize, Duration) {
+        ("identity", self.total, self.total, Duration::ZERO)
+    }
+}
+
+/// Encoders run one after the other, each on the output of the one before, like the
+/// decoders of a body with layered codings
+pub struct Chain {
+    stages: Vec<Box<dyn Encode + Send + Sync>>,
+    /// The leading stages that got the end of their input and gave out all of their output
+    finished: usize,
+}
+
+impl Chain {
+    pub fn new(stages: Vec<Box<dyn Encode + Send + Sync>>) -> Self {
+        Self {
+            stages,
+            finished: 0,
+        }
+    }
+}
+
+impl Encode for Chain {
+    fn encode(&mut self, input: &[u8], end: bool) -> Result<Bytes> {
+        let mut data: Option<Bytes> = None;
+        let mut end = end;
+        for (i, stage) in self.stages.iter_mut().enumerate() {
+            let input = data.as_deref().unwrap_or(input);
+            let output = if i < self.finished {
+                Bytes::new()
+            } else {
+                stage.encode(input, end)?
+            };
+            // a stage holding output back isn't done, nor is any after it
+            end &= !stage.has_pending();
+            if end && i == self.finished {
+                self.finished += 1;
+            }
+            data = Some(output);
+        }
+        Ok(data.unwrap_or_else(|| Bytes::copy_from_slice(input)))
+    }
+
+    fn has_pending(&self) -> bool {
+        self.stages.iter().any(|stage| stage.has_pending())
+    }
+
+    fn stats(&self) -> Vec<(&'static str, usize, usize, Duration)> {
+        self.stages.iter().flat_map(|stage| stage.stats()).collect()
+    }
+
+    fn stat(&self) -> (&'static str, usize, usize, Duration) {
+        let total_in = self.stages.first().map_or(0, |stage| stage.stat().1);
+        let total_out = self.stages.last().map_or(0, |stage| stage.stat().2);
+        let duration = self.stages.iter().map(|stage| stage.stat().3).sum();
+        ("chain", total_in, total_out, duration)
+    }
+}
+
+// ====================== Transcoding ======================
+
+/// Decode `input` from `from` and encode it again with `to`, at `level` or a default one. For
+/// bodies that are at hand as a whole, without any limit on what they decompress to.
+pub fn transcode(
+    input: &[u8],
+    from: Algorithm,
+    to: Algorithm,
+    level: Option<i32>,
+) -> Result<Bytes> {
+    let mut encoder = to.compressor(level)?;
+    let plain = from.decompressor(usize::MAX)?.encode(input, true)?;
+    encoder.encode(&plain, true)
+}
+
 #[cfg(test)]
 mod tests_stream {
     use super::*;
@@ -264,6 +1174,58 @@ mod tests_stream {
         assert!(compressor.get_ref().is_empty());
     }
 
+    #[test]
+    fn gzip_header() {
+        let header = |compressed: &[u8]| {
+            let mut decoder = flate2::write::GzDecoder::new(Vec::new());
+            decoder.write_all(compressed).unwrap();
+            let header = decoder.header().unwrap();
+            (
+                header.filename().map(<[u8]>::to_vec),
+                header.mtime(),
+                header.operating_system(),
+            )
+        };
+        let plain = Compressor::new(6).encode(b"abcdefg", true).unwrap();
+        assert_eq!(header(&plain), (None, 0, 255));
+        let default = Compressor::with_header(6, &GzipHeader::default())
+            .encode(b"abcdefg", true)
+            .unwrap();
+        assert_eq!(default, plain);
+
+        let stamped = GzipHeader {
+            filename: Some(b"body.json".to_vec()),
+            mtime: 1_700_000_000,
+            os: Some(3),
+        };
+        let compressed = Compressor::with_header(6, &stamped)
+            .encode(b"abcdefg", true)
+            .unwrap();
+        assert_eq!(
+            header(&compressed),
+            (Some(b"body.json".to_vec()), 1_700_000_000, 3)
+        );
+        let mut decompressor = Decompressor::new();
+        assert_eq!(
+            &decompressor.encode(&compressed, true).unwrap()[..],
+            b"abcdefg"
+        );
+    }
+
+    #[test]
+    fn gzip_levels() {
+        let input = b"abcdefg abcdefg abcdefg abcdefg".repeat(32);
+        for level in [0, 9] {
+            let mut compressor = Compressor::new(level);
+            let compressed = compressor.encode(&input, true).unwrap();
+            assert_eq!(&compressed[..3], &[0x1f, 0x8b, 0x08]);
+
+            let mut decompressor = Decompressor::new();
+            let decompressed = decompressor.encode(&compressed, true).unwrap();
+            assert_eq!(&decompressed[..], &input[..]);
+        }
+    }
+
     #[test]
     fn gunzip_data() {
         let mut decompressor = Decompressor::new();
@@ -280,4 +1242,598 @@ mod tests_stream {
 
         assert!(decompressor.get_ref().is_empty());
     }
+
+    #[test]
+    fn brotli_round_trip() {
+        let mut compressor = BrotliCompressor::new(5);
+        let mut compressed = compressor.encode(b"abcdefg", false).unwrap().to_vec();
+        compressed.extend_from_slice(&compressor.encode(b"hijklmn", true).unwrap());
+        assert_eq!(compressor.total_in, 14);
+        assert_eq!(compressor.total_out, compressed.len());
+        // the stream is finished, nothing more can be written
+        assert!(compressor.encode(b"x", true).is_err());
+
+        let mut decompressor = BrotliDecompressor::new();
+        let decompressed = decompressor.encode(&compressed, true).unwrap();
+        assert_eq!(&decompressed[..], b"abcdefghijklmn");
+        assert_eq!(decompressor.total_in, compressed.len());
+        assert_eq!(decompressor.total_out, decompressed.len());
+        assert_eq!(decompressor.stat().0, "de-br");
+    }
+
+    #[test]
+    fn brotli_truncated_data() {
+        let mut compressor = BrotliCompressor::new(5);
+        let compressed = compressor.encode(b"abcdefg", true).unwrap();
+
+        let mut decompressor = BrotliDecompressor::new();
+        assert!(
+            decompressor
+                .encode(&compressed[..compressed.len() - 1], true)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn deflate_data() {
+        let mut compressor = DeflateCompressor::new(6);
+        let compressed = compressor.encode(b"abcdefg", true).unwrap();
+        // zlib header for the default compression level
+        assert_eq!(&compressed[..2], &[0x78, 0x9c]);
+        // check the adler32 footer
+        assert_eq!(&compressed[compressed.len() - 4..], &[10, 219, 2, 189]);
+        assert_eq!(compressor.total_in, 7);
+        assert_eq!(compressor.total_out, compressed.len());
+
+        assert!(compressor.compress.get_ref().is_empty());
+    }
+
+    #[test]
+    fn inflate_data() {
+        let mut decompressor = DeflateDecompressor::new();
+
+        let compressed_bytes = &[
+            0x78, 0x9c, 75, 76, 74, 78, 73, 77, 75, 7, 0, 10, 219, 2, 189,
+        ];
+        let decompressed = decompressor.encode(compressed_bytes, true).unwrap();
+
+        assert_eq!(&decompressed[..], b"abcdefg");
+        assert_eq!(decompressor.total_in, compressed_bytes.len());
+        assert_eq!(decompressor.total_out, decompressed.len());
+
+        assert!(decompressor.decompress.get_ref().is_empty());
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        let input = b"hello hello hello hello deflate".repeat(64);
+        let mut compressor = DeflateCompressor::new(6);
+        let mut decompressor = DeflateDecompressor::new();
+        let (head, tail) = input.split_at(input.len() / 2);
+
+        let mut decompressed = decompressor
+            .encode(&compressor.encode(head, false).unwrap(), false)
+            .unwrap()
+            .to_vec();
+        decompressed.extend_from_slice(
+            &decompressor
+                .encode(&compressor.encode(tail, true).unwrap(), true)
+                .unwrap(),
+        );
+        assert_eq!(decompressed, input);
+        assert!(compressor.total_out < compressor.total_in);
+    }
+
+    #[test]
+    fn decompressed_size_limit() {
+        fn decompressors(limit: usize) -> Vec<Box<dyn Encode>> {
+            vec![
+                Box::new(Decompressor::with_limit(limit)),
+                Box::new(ZstdDecompressor::with_limit(limit).unwrap()),
+                Box::new(BrotliDecompressor::with_limit(limit)),
+                Box::new(DeflateDecompressor::with_limit(limit)),
+            ]
+        }
+        let input = vec![b'a'; 64 * 1024];
+        let compressed = [
+            Compressor::new(6).encode(&input, true).unwrap(),
+            ZstdCompressor::new(6)
+                .unwrap()
+                .encode(&input, true)
+                .unwrap(),
+            BrotliCompressor::new(5).encode(&input, true).unwrap(),
+            DeflateCompressor::new(6).encode(&input, true).unwrap(),
+        ];
+
+        for (mut decompressor, compressed) in
+            decompressors(input.len() - 1).into_iter().zip(&compressed)
+        {
+            assert!(decompressor.encode(compressed, true).is_err());
+        }
+        for (mut decompressor, compressed) in
+            decompressors(input.len()).into_iter().zip(&compressed)
+        {
+            assert_eq!(decompressor.encode(compressed, true).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn zstd_round_trip() {
+        let input = b"hello hello hello hello zstd".repeat(64);
+        let mut compressor = ZstdCompressor::new(6).unwrap();
+        let compressed = compressor.encode(&input, true).unwrap();
+        // zstd frame magic number
+        assert_eq!(&compressed[..4], &[0x28, 0xb5, 0x2f, 0xfd]);
+
+        let mut decompressor = ZstdDecompressor::new().unwrap();
+        let (head, tail) = compressed.split_at(compressed.len() / 2);
+        let mut decompressed = decompressor.encode(head, false).unwrap().to_vec();
+        decompressed.extend_from_slice(&decompressor.encode(tail, true).unwrap());
+        assert_eq!(decompressed, input);
+        assert_eq!(decompressor.total_in, compressed.len());
+        assert_eq!(decompressor.total_out, input.len());
+    }
+
+    #[test]
+    fn zstd_truncated_data() {
+        let input = b"hello hello hello hello zstd".repeat(64);
+        let compressed = ZstdCompressor::new(6)
+            .unwrap()
+            .encode(&input, true)
+            .unwrap();
+
+        let mut decompressor = ZstdDecompressor::new().unwrap();
+        assert!(
+            decompressor
+                .encode(&compressed[..compressed.len() - 4], true)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn zstd_dictionary() {
+        let dictionary = br#"{"id": 0, "name": "", "tags": [], "enabled": true}"#.repeat(4);
+        let input = br#"{"id": 42, "name": "proxy", "tags": [], "enabled": true}"#;
+        let compressed = ZstdCompressor::with_dictionary(6, &dictionary)
+            .unwrap()
+            .encode(input, true)
+            .unwrap();
+        assert!(
+            compressed.len()
+                < ZstdCompressor::new(6)
+                    .unwrap()
+                    .encode(input, true)
+                    .unwrap()
+                    .len()
+        );
+
+        let mut decompressor = ZstdDecompressor::with_dictionary(&dictionary, usize::MAX).unwrap();
+        assert_eq!(decompressor.encode(&compressed, true).unwrap(), &input[..]);
+        // the frame can't be decoded without the dictionary
+        assert!(
+            ZstdDecompressor::new()
+                .unwrap()
+                .encode(&compressed, true)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn flush_interval() {
+        let pairs: Vec<(Box<dyn Encode>, Box<dyn Encode>)> = vec![
+            (
+                Box::new(Compressor::new(6).with_flush_interval(100)),
+                Box::new(Decompressor::new()),
+            ),
+            (
+                Box::new(ZstdCompressor::new(6).unwrap().with_flush_interval(100)),
+                Box::new(ZstdDecompressor::new().unwrap()),
+            ),
+            (
+                Box::new(BrotliCompressor::new(5).with_flush_interval(100)),
+                Box::new(BrotliDecompressor::new()),
+            ),
+            (
+                Box::new(DeflateCompressor::new(6).with_flush_interval(100)),
+                Box::new(DeflateDecompressor::new()),
+            ),
+        ];
+        for (mut compressor, mut decompressor) in pairs {
+            let name = compressor.stat().0;
+            let chunk = b"a streamed chunk of text, ".repeat(3);
+            // below the interval nothing has to come out yet
+            let mut decoded = decompressor
+                .encode(&compressor.encode(&chunk, false).unwrap(), false)
+                .unwrap()
+                .to_vec();
+            // past it all input so far can be decoded before the body ends
+            let flushed = compressor.encode(&chunk, false).unwrap();
+            decoded.extend_from_slice(&decompressor.encode(&flushed, false).unwrap());
+            assert_eq!(decoded, chunk.repeat(2), "{name}");
+
+            let rest = compressor.encode(&chunk, true).unwrap();
+            decoded.extend_from_slice(&decompressor.encode(&rest, true).unwrap());
+            assert_eq!(decoded, chunk.repeat(3), "{name}");
+        }
+    }
+
+    #[test]
+    fn chain() {
+        let input = b"layer upon layer ".repeat(100);
+        let gzipped = Compressor::new(6).encode(&input, true).unwrap();
+        let layered = ZstdCompressor::new(3)
+            .unwrap()
+            .encode(&gzipped, true)
+            .unwrap();
+        let mut chain = Chain::new(vec![
+            Box::new(ZstdDecompressor::new().unwrap()),
+            Box::new(Decompressor::new()),
+        ]);
+        let (first, rest) = layered.split_at(layered.len() / 2);
+        let mut decoded = chain.encode(first, false).unwrap().to_vec();
+        decoded.extend_from_slice(&chain.encode(rest, true).unwrap());
+        assert_eq!(decoded, input);
+        let (name, total_in, total_out, _) = chain.stat();
+        assert_eq!(
+            (name, total_in, total_out),
+            ("chain", layered.len(), input.len())
+        );
+        let stats: Vec<_> = chain
+            .stats()
+            .into_iter()
+            .map(|(name, total_in, total_out, _)| (name, total_in, total_out))
+            .collect();
+        assert_eq!(
+            stats,
+            [
+                ("de-zstd", layered.len(), gzipped.len()),
+                ("de-gzip", gzipped.len(), input.len())
+            ]
+        );
+
+        // a stage holding output back is drained through the ones after it
+        let input = vec![0u8; 1024 * 1024];
+        let layered = ZstdCompressor::new(3)
+            .unwrap()
+            .encode(&Compressor::new(9).encode(&input, true).unwrap(), true)
+            .unwrap();
+        let mut chain = Chain::new(vec![
+            Box::new(ZstdDecompressor::new().unwrap()),
+            Box::new(Decompressor::new().with_output_chunk(64 * 1024)),
+        ]);
+        let mut decoded = chain.encode(&layered, true).unwrap().to_vec();
+        assert!(chain.has_pending());
+        while chain.has_pending() {
+            let chunk = chain.encode(&[], true).unwrap();
+            assert!(chunk.len() <= 64 * 1024);
+            decoded.extend_from_slice(&chunk);
+        }
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    fn zstd_window() {
+        let input = b"a window of its own ".repeat(1000);
+        let compressed = ZstdCompressor::new(3)
+            .unwrap()
+            .with_window_log(ZSTD_WINDOW_LOG_MAX + 1)
+            .unwrap()
+            .with_long_distance_matching()
+            .unwrap()
+            .encode(&input, true)
+            .unwrap();
+        assert!(
+            ZstdDecompressor::new()
+                .unwrap()
+                .encode(&compressed, true)
+                .is_err()
+        );
+        let decoded = ZstdDecompressor::new()
+            .unwrap()
+            .with_window_log_max(ZSTD_WINDOW_LOG_MAX + 1)
+            .unwrap()
+            .encode(&compressed, true)
+            .unwrap();
+        assert_eq!(decoded, input);
+
+        assert!(ZstdCompressor::new(3).unwrap().with_window_log(40).is_err());
+    }
+
+    #[test]
+    fn output_chunks() {
+        let input = vec![0u8; 8 * 1024 * 1024];
+        let compressed = Compressor::new(9).encode(&input, true).unwrap();
+        // in two parts, the first one all but the end of the input
+        let (first, last) = compressed.split_at(compressed.len() - 10);
+
+        let mut decompressor = Decompressor::new().with_output_chunk(64 * 1024);
+        let mut decoded = decompressor.encode(first, false).unwrap().to_vec();
+        assert_eq!(decoded.len(), 64 * 1024);
+        assert!(decompressor.has_pending());
+        let mut chunk = decompressor.encode(last, true).unwrap();
+        while !chunk.is_empty() {
+            assert!(chunk.len() <= 64 * 1024);
+            assert!(decompressor.decompress.get_ref().capacity() <= 4 * 64 * 1024);
+            decoded.extend_from_slice(&chunk);
+            chunk = decompressor.encode(&[], true).unwrap();
+        }
+        assert!(!decompressor.has_pending());
+        assert_eq!(decoded, input);
+        assert_eq!(decompressor.stat().2, input.len());
+
+        // the limit applies as without chunks
+        let mut decompressor = Decompressor::with_limit(1024 * 1024).with_output_chunk(64 * 1024);
+        let mut result = decompressor.encode(&compressed, true);
+        while let Ok(chunk) = &result
+            && !chunk.is_empty()
+        {
+            result = decompressor.encode(&[], true);
+        }
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn sliced_input() {
+        // incompressible, so the compressed chunk spans several slices
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let input: Vec<u8> = (0..4 * MAX_INPUT_SLICE)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let mut decompressors: Vec<(Box<dyn Encode>, Bytes)> = vec![
+            (
+                Box::new(Decompressor::new()),
+                Compressor::new(6).encode(&input, true).unwrap(),
+            ),
+            (
+                Box::new(ZstdDecompressor::new().unwrap()),
+                ZstdCompressor::new(6)
+                    .unwrap()
+                    .encode(&input, true)
+                    .unwrap(),
+            ),
+            (
+                Box::new(BrotliDecompressor::new()),
+                BrotliCompressor::new(5).encode(&input, true).unwrap(),
+            ),
+            (
+                Box::new(DeflateDecompressor::new()),
+                DeflateCompressor::new(6).encode(&input, true).unwrap(),
+            ),
+        ];
+        for (decompressor, compressed) in &mut decompressors {
+            assert!(compressed.len() > MAX_INPUT_SLICE);
+            assert_eq!(decompressor.encode(compressed, true).unwrap(), input);
+        }
+
+        // a bomb in a single chunk is stopped before all of it has expanded
+        let zeros = vec![0; 32 * 1024 * 1024];
+        let bomb = Compressor::new(9).encode(&zeros, true).unwrap();
+        assert!(bomb.len() > MAX_INPUT_SLICE);
+        let mut decompressor = Decompressor::with_limit(1024 * 1024);
+        assert!(decompressor.encode(&bomb, true).is_err());
+        // what one slice expands to at most, deflate can't do better than 1032:1
+        assert!(decompressor.decompress.get_ref().len() <= MAX_INPUT_SLICE * 1032);
+        assert!(decompressor.decompress.get_ref().len() < zeros.len());
+    }
+
+    #[test]
+    fn transcoding() {
+        let plain = b"transcoded between algorithms ".repeat(64);
+        let gzipped = Compressor::new(6).encode(&plain, true).unwrap();
+        let zstd = transcode(&gzipped, Algorithm::Gzip, Algorithm::Zstd, Some(19)).unwrap();
+        assert_eq!(&zstd[..4], &[0x28, 0xb5, 0x2f, 0xfd]);
+        assert_eq!(zstd::decode_all(&zstd[..]).unwrap(), plain);
+
+        let back = transcode(&zstd, Algorithm::Zstd, Algorithm::Gzip, None).unwrap();
+        assert_eq!(&back[..2], &[0x1f, 0x8b]);
+        assert_eq!(back, gzipped);
+        let brotli = transcode(&back, Algorithm::Gzip, Algorithm::Brotli, None).unwrap();
+        let deflate = transcode(&brotli, Algorithm::Brotli, Algorithm::Deflate, Some(9)).unwrap();
+        let mut decompressor = DeflateDecompressor::new();
+        assert_eq!(decompressor.encode(&deflate, true).unwrap(), plain);
+
+        assert!(transcode(&gzipped, Algorithm::Gzip, Algorithm::Gzip, Some(10)).is_err());
+        assert!(transcode(&gzipped, Algorithm::Gzip, Algorithm::Brotli, Some(-1)).is_err());
+        assert!(transcode(b"not gzip", Algorithm::Gzip, Algorithm::Zstd, None).is_err());
+    }
+
+    #[test]
+    fn registered_codecs() {
+        // none of the algorithms, the registry is shared by all tests
+        assert!(registered_codec("x-registered", 1).is_none());
+        register_codec(
+            "x-registered",
+            Box::new(|level| {
+                assert_eq!(level, 3);
+                Box::new(Identity::default())
+            }),
+        );
+        let mut codec = registered_codec("X-Registered", 3).unwrap();
+        assert_eq!(codec.encode(b"abc", true).unwrap(), &b"abc"[..]);
+        assert_eq!(codec.stat().0, "identity");
+
+        // registered again, the new one replaces it
+        register_codec(
+            "x-registered",
+            Box::new(|level| Box::new(Compressor::new(level as u32))),
+        );
+        let mut codec = registered_codec("x-registered", 9).unwrap();
+        assert_eq!(&codec.encode(b"abc", true).unwrap()[..2], &[0x1f, 0x8b]);
+        assert_eq!(codec.stat().0, "gzip");
+        assert!(registered_codec("x-other", 1).is_none());
+    }
+
+    #[test]
+    fn algorithms() {
+        for algorithm in Algorithm::value_variants() {
+            let token = algorithm.to_string();
+            assert_eq!(token.parse::<Algorithm>(), Ok(*algorithm));
+            assert_eq!(token.to_uppercase().parse::<Algorithm>(), Ok(*algorithm));
+
+            let plain = b"built from the algorithm ".repeat(16);
+            let encoded = algorithm
+                .compressor(None)
+                .unwrap()
+                .encode(&plain, true)
+                .unwrap();
+            let mut decompressor = algorithm.decompressor(1024 * 1024).unwrap();
+            assert_eq!(decompressor.encode(&encoded, true).unwrap(), plain);
+        }
+        assert_eq!("x-gzip".parse(), Ok(Algorithm::Gzip));
+        assert!("lz4".parse::<Algorithm>().is_err());
+
+        let mut identity = Algorithm::Identity.compressor(None).unwrap();
+        assert_eq!(identity.encode(b"as is", true).unwrap(), &b"as is"[..]);
+        assert_eq!(identity.stat().0, "identity");
+        assert!(Algorithm::Identity.compressor(Some(1)).is_err());
+    }
+
+    #[test]
+    fn split_at_every_byte() {
+        let plain = b"split anywhere, decoded the same ".repeat(40);
+        type NewDecompressor = fn() -> Box<dyn Encode>;
+        let mut cases: Vec<(Bytes, NewDecompressor)> = vec![
+            (Compressor::new(6).encode(&plain, true).unwrap(), || {
+                Box::new(Decompressor::new())
+            }),
+            (Compressor::new(6).encode(&plain, true).unwrap(), || {
+                Box::new(Decompressor::new().with_output_chunk(64))
+            }),
+            (
+                ZstdCompressor::new(6)
+                    .unwrap()
+                    .encode(&plain, true)
+                    .unwrap(),
+                || Box::new(ZstdDecompressor::new().unwrap()),
+            ),
+        ];
+        // two gzip members, which is a valid gzip body as well
+        let mut members = Compressor::new(6)
+            .encode(&plain[..500], true)
+            .unwrap()
+            .to_vec();
+        members.extend_from_slice(&Compressor::new(6).encode(&plain[500..], true).unwrap());
+        let members = Bytes::from(members);
+        cases.push((members.clone(), || Box::new(Decompressor::new())));
+        cases.push((members, || {
+            Box::new(Decompressor::new().with_output_chunk(64))
+        }));
+        // flushed along the way, as a streamed body is
+        let mut zstd = ZstdCompressor::new(6).unwrap().with_flush_interval(100);
+        let mut flushed = zstd.encode(&plain[..500], false).unwrap().to_vec();
+        flushed.extend_from_slice(&zstd.encode(&plain[500..], true).unwrap());
+        cases.push((flushed.into(), || {
+            Box::new(ZstdDecompressor::new().unwrap())
+        }));
+        // two frames, split at every byte of the boundary between them as well
+        let mut frames = ZstdCompressor::new(6)
+            .unwrap()
+            .encode(&plain[..500], true)
+            .unwrap()
+            .to_vec();
+        frames.extend_from_slice(
+            &ZstdCompressor::new(6)
+                .unwrap()
+                .encode(&plain[500..], true)
+                .unwrap(),
+        );
+        cases.push((frames.into(), || Box::new(ZstdDecompressor::new().unwrap())));
+
+        for (compressed, decompressor) in &cases {
+            for at in 0..=compressed.len() {
+                let (head, tail) = compressed.split_at(at);
+                let mut decompressor = decompressor();
+                let mut decompressed = decompressor.encode(head, false).unwrap().to_vec();
+                decompressed.extend_from_slice(&decompressor.encode(tail, true).unwrap());
+                while decompressor.has_pending() {
+                    decompressed.extend_from_slice(&decompressor.encode(&[], true).unwrap());
+                }
+                assert_eq!(decompressed, plain, "split at {at}");
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_input() {
+        // what the fuzz target does, for a few deterministic inputs
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let plain = b"some body that compresses some body that compresses".repeat(50);
+        let valid: [Bytes; 4] = [
+            Compressor::new(6).encode(&plain, true).unwrap(),
+            ZstdCompressor::new(6)
+                .unwrap()
+                .encode(&plain, true)
+                .unwrap(),
+            BrotliCompressor::new(5).encode(&plain, true).unwrap(),
+            DeflateCompressor::new(6).encode(&plain, true).unwrap(),
+        ];
+        let limit = 64 * 1024;
+        for case in 0..400 {
+            let mut input = valid[case % 4].to_vec();
+            match case / 4 % 3 {
+                // garbage
+                0 => input.iter_mut().for_each(|b| *b = next() as u8),
+                // a few flipped bytes
+                1 => {
+                    for _ in 0..1 + next() % 4 {
+                        let at = next() as usize % input.len();
+                        input[at] ^= 1 << (next() % 8);
+                    }
+                }
+                // cut off
+                _ => input.truncate(next() as usize % input.len()),
+            }
+            let mut decompressor: Box<dyn Encode> = match case % 4 {
+                0 => Box::new(Decompressor::with_limit(limit).with_output_chunk(1024)),
+                1 => Box::new(ZstdDecompressor::with_limit(limit).unwrap()),
+                2 => Box::new(BrotliDecompressor::with_limit(limit)),
+                _ => Box::new(DeflateDecompressor::with_limit(limit)),
+            };
+            let mut rest = &input[..];
+            let mut total = 0;
+            // errors are fine, panics and unbounded output aren't. `end` may come early
+            // and more may follow, as a misbehaving caller would do.
+            while !rest.is_empty() {
+                let (chunk, tail) = rest.split_at((next() as usize % 300).min(rest.len()));
+                rest = tail;
+                let end = next() % 8 == 0;
+                let Ok(output) = decompressor.encode(chunk, end) else {
+                    break;
+                };
+                total += output.len();
+                while decompressor.has_pending()
+                    && let Ok(more) = decompressor.encode(&[], end)
+                {
+                    total += more.len();
+                }
+                assert!(total <= limit, "case {case}");
+            }
+        }
+    }
+
+    #[test]
+    fn pooled_gzip() {
+        let input = b"pooled buffers pooled buffers".repeat(100);
+        for _ in 0..2 {
+            let mut compressor = Compressor::new(6).with_buffer_pool();
+            let (head, tail) = input.split_at(input.len() / 2);
+            let mut compressed = compressor.encode(head, false).unwrap().to_vec();
+            compressed.extend_from_slice(&compressor.encode(tail, true).unwrap());
+
+            let mut decompressor = Decompressor::new().with_buffer_pool();
+            let decompressed = decompressor.encode(&compressed, true).unwrap();
+            assert_eq!(decompressed, input);
+        }
+    }
 }
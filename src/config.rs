@@ -1,13 +1,1451 @@
-use clap::Parser;
+use crate::compress::Algorithm;
+use crate::forwarded::Cidr;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgAction, CommandFactory, Parser, ValueEnum};
+use serde_yaml::Value;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 
 /// Simple program to greet a person
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 pub struct Config {
+    /// YAML file with defaults for any of these options, keyed by their long name. Options on
+    /// the command line take precedence.
+    #[arg(long)]
+    pub config_file: Option<PathBuf>,
 
-    #[arg(short, long)]
-    pub target: String,
+    /// Log more with each use: info, debug and then trace instead of only warnings and errors.
+    /// `RUST_LOG` takes precedence when set. Also prints where each option was taken from at
+    /// startup.
+    #[arg(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Validate the configuration, print a summary of it and exit without listening
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Upstream address, repeat to balance requests round-robin over several upstreams. An
+    /// upstream given as `host:port=weight` gets requests in proportion to its weight, 1 if
+    /// it has none.
+    #[arg(short, long, required = true, value_parser = parse_target)]
+    pub target: Vec<(String, u32)>,
 
     #[arg(short, long, default_value_t = 18081)]
     pub port: u16,
-}
\ No newline at end of file
+
+    /// Address to listen on, like `127.0.0.1` or `[::1]:8080`. Without a port `--port` is used.
+    #[arg(long, default_value = "0.0.0.0", value_parser = parse_bind)]
+    pub bind: (IpAddr, Option<u16>),
+
+    /// Also listen on a Unix socket at this path, removed again on shutdown
+    #[arg(long)]
+    pub listen_uds: Option<PathBuf>,
+
+    /// Permissions of the `--listen-uds` socket, in octal
+    #[arg(long, default_value = "666", value_parser = parse_mode)]
+    pub uds_mode: u32,
+
+    /// Only listen on `--listen-uds`, not on `--bind`
+    #[arg(long, requires = "listen_uds")]
+    pub no_tcp: bool,
+
+    /// Accept TCP Fast Open on `--bind`, with a queue of this many connections that haven't
+    /// completed the handshake. Pingora always sets `TCP_NODELAY` on accepted connections and
+    /// listens with a backlog of 65535, capped by `net.core.somaxconn`.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "no_tcp")]
+    pub tcp_fastopen: Option<u32>,
+
+    /// PEM certificate chain to serve HTTPS with on `--bind`, instead of plain HTTP. Needs a
+    /// build with one of the TLS backends of pingora, without one it is refused.
+    #[arg(long, requires = "tls_key", conflicts_with = "no_tcp")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `--tls-cert`
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Seconds in-flight requests get to finish after `SIGTERM`, once new connections are no
+    /// longer accepted. Whatever is still running then is cut off.
+    #[arg(long, default_value_t = 60)]
+    pub shutdown_timeout: u64,
+
+    /// pingora server configuration file to use instead of the one generated from `--threads`,
+    /// `--listener-tasks-per-fd`, `--shutdown-timeout` and `--upstream-pool-size`
+    #[arg(long)]
+    pub pingora_conf: Option<PathBuf>,
+
+    /// Worker threads of each service, defaults to the number of CPUs
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub threads: Option<u64>,
+
+    /// Tasks accepting connections on each listening socket
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+    pub listener_tasks_per_fd: u64,
+
+    /// Format of the access log, written at info level to the `access` log target
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Path answered with 200 by the proxy itself, for load balancer health checks
+    #[arg(long, default_value = "/healthz")]
+    pub health_path: String,
+
+    /// Path polled on every `--target`, those failing the check get no requests until they
+    /// pass again. No checks are made without it.
+    #[arg(long)]
+    pub health_check_path: Option<String>,
+
+    /// Milliseconds between two health checks of a target, also the time a check may take
+    #[arg(long, default_value_t = 5_000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub health_check_interval: u64,
+
+    /// Failed health checks in a row that take a target out of rotation
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub unhealthy_threshold: u32,
+
+    /// Seconds over which a target that passes its health check again gets back to its full
+    /// share of requests, starting from almost none
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub slow_start_seconds: Option<u64>,
+
+    /// Serve Prometheus metrics on `/metrics` of this port, and the codec totals as JSON on
+    /// `/stats`. A `POST` to `/drain` makes the `--health-path` answer 503 until one to
+    /// `/undrain`.
+    #[arg(long)]
+    pub metrics_port: Option<u16>,
+
+    /// Address of the `--metrics-port` listener. Anyone who can reach it can drain the proxy,
+    /// so it only listens on loopback unless told otherwise.
+    #[arg(long, default_value = "127.0.0.1")]
+    pub metrics_bind: IpAddr,
+
+    /// Connect to the upstream over TLS. Needs a build with one of the TLS backends of pingora,
+    /// without one it is refused.
+    #[arg(long)]
+    pub upstream_tls: bool,
+
+    /// SNI sent to a TLS upstream, defaults to the host of the target. Refused without a TLS
+    /// backend, like `--upstream-tls`.
+    #[arg(long, requires = "upstream_tls")]
+    pub upstream_sni: Option<String>,
+
+    /// Reach upstreams through a tunnel of the HTTP CONNECT proxy listening on this Unix
+    /// socket, rather than connecting to them directly. Health checks still connect directly.
+    #[arg(long)]
+    pub egress_proxy: Option<PathBuf>,
+
+    /// `user:password` sent to the `--egress-proxy` with Basic authentication
+    #[arg(long, requires = "egress_proxy", value_parser = parse_credentials)]
+    pub egress_proxy_auth: Option<String>,
+
+    /// Don't verify the certificate of a TLS upstream, for self-signed backends. Refused
+    /// without a TLS backend, like `--upstream-tls`.
+    #[arg(long, requires = "upstream_tls")]
+    pub upstream_insecure: bool,
+
+    /// PEM bundle of the CAs a TLS upstream certificate is verified against, instead of the
+    /// system ones. Refused without a TLS backend, like `--upstream-tls`.
+    #[arg(long, requires = "upstream_tls", conflicts_with = "upstream_insecure")]
+    pub upstream_ca_file: Option<PathBuf>,
+
+    /// Speak HTTP/2 to the upstream, negotiated with ALPN over TLS and assumed (h2c) without
+    #[arg(long)]
+    pub upstream_http2: bool,
+
+    /// Milliseconds to wait for a connection to the upstream
+    #[arg(long, default_value_t = 10_000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub connect_timeout: u64,
+
+    /// Milliseconds to wait for each read from the upstream, a timeout answers with 504
+    #[arg(long, default_value_t = 60_000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub read_timeout: u64,
+
+    /// Milliseconds to wait for each write to the upstream
+    #[arg(long, default_value_t = 60_000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub write_timeout: u64,
+
+    /// Milliseconds an idle upstream connection is kept around for reuse, unlimited by default
+    #[arg(long)]
+    pub upstream_idle_timeout: Option<u64>,
+
+    /// Seconds an idle client connection is kept open for its next request, for as long as
+    /// the client likes by default
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub downstream_keepalive_timeout: Option<u64>,
+
+    /// Requests a client connection may carry, the response to the last one closes it
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub downstream_max_requests: Option<u64>,
+
+    /// Idle upstream connections kept around for reuse. A connection that failed is never put
+    /// back, so a retry doesn't get it again.
+    #[arg(long, default_value_t = 128, value_parser = clap::value_parser!(u64).range(1..))]
+    pub upstream_pool_size: u64,
+
+    /// Status the client gets when the upstream can't be reached or fails, instead of 502 or
+    /// 504 for a timeout
+    #[arg(long, value_parser = clap::value_parser!(u16).range(400..=599))]
+    pub upstream_error_status: Option<u16>,
+
+    /// HTML file sent as the body of the response to a failed upstream request
+    #[arg(long)]
+    pub error_page: Option<PathBuf>,
+
+    /// How many times a request that failed upstream is retried
+    #[arg(long, default_value_t = 1)]
+    pub max_retries: usize,
+
+    /// Also retry POST, PATCH and other methods that aren't idempotent
+    #[arg(long)]
+    pub retry_non_idempotent: bool,
+
+    /// zstd compression level, negative levels trade ratio for speed
+    #[arg(
+        long,
+        default_value_t = 6,
+        allow_negative_numbers = true,
+        value_parser = clap::value_parser!(i32).range(-7..=22)
+    )]
+    pub zstd_level: i32,
+
+    /// zstd window as a power of two. A larger one finds matches further back, and takes
+    /// `2^N` bytes of memory per body on both the compressing and the decompressing side.
+    /// Frames with a window above 27 (128 MiB) are only accepted by a peer proxy given the same
+    /// value.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(10..=31))]
+    pub zstd_window_log: Option<u32>,
+
+    /// zstd long distance matching, for big bodies with repeats far apart. It uses a 128 MiB
+    /// window unless `--zstd-window-log` sets another one.
+    #[arg(long)]
+    pub zstd_long_mode: bool,
+
+    /// gzip compression level, 0 stores the body uncompressed
+    #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u32).range(0..=9))]
+    pub gzip_level: u32,
+
+    /// File name written into the header of gzip streams
+    #[arg(long)]
+    pub gzip_filename: Option<String>,
+
+    /// Write the time a body is compressed at and the OS (Unix) into the header of gzip
+    /// streams, as `gzip` does
+    #[arg(long)]
+    pub gzip_mtime: bool,
+
+    /// Keep gzip output reproducible, the same body always compressing to the same bytes: a
+    /// zero mtime and 255 (unknown) as the OS. This is the default, the flag overrides a
+    /// `--gzip-mtime` given before it, e.g. in `--config-file`.
+    #[arg(long, overrides_with = "gzip_mtime")]
+    pub gzip_deterministic: bool,
+
+    /// Algorithm used to compress request bodies and to ask the upstream for: gzip, zstd, brotli,
+    /// deflate, identity or the name of a codec an embedding crate registered
+    #[arg(short, long, value_parser = Algorithm::by_name, default_value_t = Algorithm::Zstd)]
+    pub algorithm: Algorithm,
+
+    /// Header the original `Content-Length` of a compressed body travels in to the peer proxy.
+    /// Clients can't set it, it is removed from everything the proxy compresses or passes on.
+    #[arg(long, default_value = "x-crd-content-length", value_parser = parse_header_name)]
+    pub length_stash_header: String,
+
+    /// Header requests are traced by. The id a client sends is passed on, requests without one
+    /// get a new UUID. Either way it goes upstream and comes back on the response.
+    #[arg(long, default_value = "x-request-id", value_parser = parse_extra_header_name)]
+    pub request_id_header: String,
+
+    /// Header set on every upstream request, as `name:value`, repeatable. Applied after the
+    /// proxy encoded the request, the headers it manages for that can't be set this way.
+    #[arg(long, value_parser = parse_extra_header)]
+    pub add_request_header: Vec<(String, String)>,
+
+    /// Header removed from every upstream request, repeatable. Removed before
+    /// `--add-request-header` is applied.
+    #[arg(long, value_parser = parse_extra_header_name)]
+    pub remove_request_header: Vec<String>,
+
+    /// Header set on every response to the client, as `name:value`, repeatable. Like
+    /// `--add-request-header` it can't touch the headers the proxy encodes bodies with.
+    #[arg(long, value_parser = parse_extra_header)]
+    pub add_response_header: Vec<(String, String)>,
+
+    /// Header removed from every response to the client, repeatable. Removed before
+    /// `--add-response-header` is applied.
+    #[arg(long, value_parser = parse_extra_header_name)]
+    pub remove_response_header: Vec<String>,
+
+    /// `Access-Control-Allow-Origin` of every response, `*` or an origin. CORS preflight
+    /// requests are then answered by the proxy instead of the upstream.
+    #[arg(long, value_parser = parse_header_value)]
+    pub cors_allow_origin: Option<String>,
+
+    /// Methods CORS preflight requests are told are allowed, separated by commas
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "GET,HEAD,POST,PUT,PATCH,DELETE",
+        requires = "cors_allow_origin"
+    )]
+    pub cors_allow_methods: Vec<http::Method>,
+
+    /// Peers whose `X-Forwarded-For` and `X-Forwarded-Proto` are passed on, as addresses or
+    /// CIDR ranges separated by commas. Anyone else's are replaced.
+    #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(Cidr))]
+    pub trusted_proxies: Vec<Cidr>,
+
+    /// Requests per second each client IP may make on average, more are answered with 429
+    #[arg(long, value_parser = parse_positive)]
+    pub rate_limit: Option<f64>,
+
+    /// Requests a client IP may make in a burst before `--rate-limit` applies
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rate_burst: u32,
+
+    /// Requests that may be in flight at once, more are answered with 503 and a `Retry-After`
+    /// rather than queued
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_concurrent_requests: Option<u64>,
+
+    /// Credentials clients have to send with HTTP Basic authentication, as `user:password`,
+    /// repeatable. Others are answered with 401, and the `Authorization` isn't passed upstream.
+    #[arg(long, value_parser = parse_credentials)]
+    pub basic_auth: Vec<String>,
+
+    /// Reject requests whose request line and headers take more than this many bytes with 431
+    #[arg(long)]
+    pub max_header_size: Option<usize>,
+
+    /// Reject request bodies larger than this many bytes, as received from the client, with 413
+    #[arg(long)]
+    pub max_request_body: Option<usize>,
+
+    /// Read and compress request bodies whole before sending them on, with the `Content-Length`
+    /// of the compressed body rather than chunked, for upstreams that turn chunked requests
+    /// away. Only bodies pingora can keep around to replay, up to 64 KiB, can be buffered;
+    /// longer ones are answered with 413 like those over `--max-request-body`.
+    #[arg(long)]
+    pub buffer_request_body: bool,
+
+    /// Keep up to this many bytes of 200 responses to GET requests in memory, and answer
+    /// repeated requests for the same host and path from there. Responses are kept as sent, so
+    /// compressed ones aren't compressed again, and the least recently used go first. Nothing
+    /// with `Cache-Control: no-store` is kept.
+    #[arg(long)]
+    pub cache_size: Option<usize>,
+
+    /// Maximum number of bytes a request body may decompress to
+    #[arg(long, default_value_t = 256 * 1024 * 1024)]
+    pub max_decompressed_size: usize,
+
+    /// Maximum number of bytes a response body the proxy decompresses for a client may
+    /// decompress to. The response is aborted with a 502 beyond that.
+    #[arg(long, default_value_t = 256 * 1024 * 1024)]
+    pub max_response_body: usize,
+
+    /// Report the algorithm and body sizes of the request transform in `x-proxy-*` response
+    /// headers
+    #[arg(long)]
+    pub debug_stats: bool,
+
+    /// Let an `X-Proxy-Compress-Level` request header set the level bodies of the request are
+    /// compressed at, to compare levels without a restart. The header isn't passed upstream.
+    #[arg(long)]
+    pub allow_header_overrides: bool,
+
+    /// Directory the request and response bodies of requests with an `X-Tap` header or a `tap`
+    /// query parameter are copied to, as they are before compression and after decompression
+    #[arg(long)]
+    pub tap_dir: Option<PathBuf>,
+
+    /// Bytes all `--tap-dir` files together may hold, what comes after is left out
+    #[arg(long, default_value_t = 64 * 1024 * 1024, requires = "tap_dir")]
+    pub tap_max_bytes: usize,
+
+    /// What to do with request bodies that are already encoded
+    #[arg(long, value_enum, default_value_t = OnPreencoded::Decompress)]
+    pub on_preencoded: OnPreencoded,
+
+    /// Pick the decoder of an encoded request body by its magic bytes instead of trusting its
+    /// `Content-Encoding`, which clients sometimes get wrong. Brotli has none, a body without
+    /// gzip, zlib or zstd magic is decoded as the header says.
+    #[arg(long)]
+    pub sniff_encoding: bool,
+
+    /// Forward requests and responses exactly as received, to compare against the transforms
+    #[arg(long)]
+    pub no_transform: bool,
+
+    /// Let pingora decode upstream responses in an encoding the upstream request didn't
+    /// accept, so the response transform only sees encodings the proxy asked for. Turn it off
+    /// to have responses reach the transform as the upstream sent them, which decodes what
+    /// the client can't and passes on the rest.
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub adjust_downstream_decompression: bool,
+
+    /// Flush the compressor every time this many body bytes came in, so streamed bodies reach
+    /// the other side before they end. Off by default, flushing costs ratio.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub flush_interval: Option<u64>,
+
+    /// Reuse the output buffers of gzip codecs, from a free list per worker thread. This cuts
+    /// the allocations per body, not the time spent on it, see `benches/buffer_pool.rs`.
+    #[arg(long)]
+    pub buffer_pool: bool,
+
+    /// Request bodies with a `Content-Length` below this many bytes are forwarded uncompressed
+    #[arg(long, default_value_t = 1024)]
+    pub min_compress_size: usize,
+
+    /// Content types of request bodies worth compressing, `type/*` matches a whole type
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "text/*,application/json,application/xml"
+    )]
+    pub compress_content_types: Vec<String>,
+
+    /// Leave the bodies of requests whose path starts with this, and of their responses, as
+    /// they are, repeatable. Like `--no-compress-method` this applies before anything else is
+    /// considered: a matching request is never compressed, whatever its `Content-Type`, nor
+    /// decompressed.
+    #[arg(long)]
+    pub no_compress_path: Vec<String>,
+
+    /// Leave the bodies of requests with these methods, and of their responses, as they are.
+    /// Separated by commas.
+    #[arg(long, value_delimiter = ',')]
+    pub no_compress_method: Vec<http::Method>,
+
+    /// Leave requests with a `Range` header, and their responses, as they are. The offsets of
+    /// a range are into the representation the client asked for: compressing a 206 body, or
+    /// compressing the resource in a different way than the upstream would, makes them point
+    /// at the wrong bytes, and the parts can't be put back together.
+    #[arg(long)]
+    pub compression_off_for_range_requests: bool,
+
+    /// Forward request bodies uncompressed unless a sample of them shrinks at least this many
+    /// times when compressed, e.g. `1.1`. The sample is read before the upstream is
+    /// connected, which delays every sampled request until it has arrived, and is compressed
+    /// twice. Only bodies with a `Content-Length` are sampled.
+    #[arg(long, value_parser = parse_positive)]
+    pub compression_min_ratio: Option<f64>,
+
+    /// Bytes of the request body `--compression-min-ratio` samples
+    #[arg(
+        long,
+        default_value_t = 16 * 1024,
+        value_parser = clap::value_parser!(u32).range(1..=32 * 1024)
+    )]
+    pub compression_sample_size: u32,
+
+    /// Trained zstd dictionary used for both compressing and decompressing zstd bodies
+    #[arg(long)]
+    pub zstd_dict: Option<PathBuf>,
+
+    /// Send requests for a `Host` to its own upstream, as `host=upstream`, repeatable. An
+    /// `upstream,algorithm[:level]` compresses its bodies with something else than `--algorithm`,
+    /// an `upstream,host=name` sends it another `Host` than `--upstream-host-header`.
+    #[arg(long, value_parser = parse_route)]
+    pub route: Vec<(String, Route)>,
+
+    /// Send requests under a path prefix to their own upstream, as `/prefix=upstream`,
+    /// repeatable. The longest matching prefix wins, a matching `--route` takes precedence.
+    /// Takes an algorithm and a host like `--route` does.
+    #[arg(long, value_parser = parse_path_route)]
+    pub path_route: Vec<(String, Route)>,
+
+    /// `Host` sent upstream instead of the client's, for backends addressed by IP that serve
+    /// virtual hosts. The connection still goes to the target.
+    #[arg(long, value_parser = parse_header_value)]
+    pub upstream_host_header: Option<String>,
+
+    /// Name an upstream is logged by, as `upstream=name`, repeatable. Others are logged by their
+    /// address.
+    #[arg(long, value_parser = parse_upstream_name)]
+    pub upstream_name: Vec<(String, String)>,
+
+    /// What happens to requests matching neither a `--route` nor a `--path-route`
+    #[arg(long, value_enum, default_value_t = RouteDefault::Target)]
+    pub route_default: RouteDefault,
+
+    /// Send the same client to the same `--target` every time, as long as that one is healthy
+    #[arg(long, value_enum)]
+    pub sticky: Option<Sticky>,
+
+    /// Cookie that holds the affinity of `--sticky cookie`
+    #[arg(long, default_value = "proxy-affinity", value_parser = parse_cookie_name)]
+    pub sticky_cookie: String,
+}
+
+impl Config {
+    /// Value of `--threads`, or the number of CPUs when it isn't given
+    pub fn threads(&self) -> usize {
+        match self.threads {
+            Some(threads) => threads as usize,
+            None => std::thread::available_parallelism().map_or(1, usize::from),
+        }
+    }
+
+    /// Where the proxy listens for TCP connections
+    pub fn listen_addr(&self) -> SocketAddr {
+        let (ip, port) = self.bind;
+        SocketAddr::new(ip, port.unwrap_or(self.port))
+    }
+
+    /// Fail on options that need TLS. Pingora is built without a TLS backend here, the stand-in
+    /// it has instead can neither handshake nor verify a certificate.
+    pub fn check_tls(&self) -> Result<(), String> {
+        let tls = [
+            ("--tls-cert", self.tls_cert.is_some()),
+            ("--upstream-ca-file", self.upstream_ca_file.is_some()),
+            ("--upstream-insecure", self.upstream_insecure),
+            ("--upstream-sni", self.upstream_sni.is_some()),
+            ("--upstream-tls", self.upstream_tls),
+        ];
+        match tls.iter().find(|(_, given)| *given) {
+            Some((flag, _)) => Err(format!(
+                "{flag} needs pingora built with a TLS backend (rustls, openssl or boringssl), \
+                 this build has none"
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Where the `--metrics-port` listener listens, if there is one
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        let port = self.metrics_port?;
+        Some(SocketAddr::new(self.metrics_bind, port))
+    }
+
+    /// Parse the process arguments together with `--config-file`, exiting on errors
+    pub fn load() -> Self {
+        Self::load_from(std::env::args_os()).unwrap_or_else(|e| e.exit())
+    }
+
+    /// Parse `args` together with the `--config-file` they name. The file is turned into
+    /// arguments placed before the command line ones, so it goes through the same validation.
+    pub fn load_from<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let cli: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        // only to find the config file and what's already on the command line
+        let matches = Self::command()
+            .ignore_errors(true)
+            .try_get_matches_from(&cli)?;
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        let mut file_args = Vec::new();
+        let mut from_file = Vec::new();
+        if let Some(path) = matches.get_one::<PathBuf>("config_file") {
+            let invalid = |msg: String| {
+                Self::command().error(
+                    ErrorKind::InvalidValue,
+                    format!("{}: {msg}", path.display()),
+                )
+            };
+            let text = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+            let file: BTreeMap<String, Value> =
+                serde_yaml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+            for (key, value) in file {
+                let id = key.replace('-', "_");
+                let (long, switch) = Self::command()
+                    .get_arguments()
+                    .find(|arg| arg.get_id() == id.as_str() && id != "config_file")
+                    .and_then(|arg| {
+                        let switch =
+                            matches!(arg.get_action(), ArgAction::SetTrue | ArgAction::Count);
+                        Some((arg.get_long()?.to_string(), switch))
+                    })
+                    .ok_or_else(|| invalid(format!("unknown option {key:?}")))?;
+                if from_cli(&id) {
+                    continue;
+                }
+                file_args.extend(
+                    file_value_args(&long, switch, value)
+                        .map_err(|e| invalid(format!("option {key:?}: {e}")))?,
+                );
+                from_file.push(id);
+            }
+        }
+
+        let mut args = cli;
+        let after_bin = args.len().min(1);
+        args.splice(after_bin..after_bin, file_args);
+        let config = Self::try_parse_from(args)?;
+        config
+            .check_tls()
+            .map_err(|msg| Self::command().error(ErrorKind::ArgumentConflict, msg))?;
+        if config.verbose > 0 {
+            for arg in Self::command().get_arguments() {
+                let id = arg.get_id().as_str();
+                let source = if from_cli(id) {
+                    "command line"
+                } else if from_file.iter().any(|f| f == id) {
+                    "config file"
+                } else {
+                    "default"
+                };
+                eprintln!("{id}: {source}");
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Command line arguments equivalent to `long: value` in the config file. A `switch` is given
+/// without a value, other options that take a bool get `true` or `false`.
+fn file_value_args(long: &str, switch: bool, value: Value) -> Result<Vec<OsString>, String> {
+    let flag = OsString::from(format!("--{long}"));
+    let scalar = |value: Value| match value {
+        Value::String(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err("expected a string or a number".to_string()),
+    };
+    Ok(match value {
+        Value::Null => Vec::new(),
+        Value::Bool(false) if switch => Vec::new(),
+        Value::Bool(true) if switch => vec![flag],
+        Value::Sequence(values) => values
+            .into_iter()
+            .map(|value| Ok([flag.clone(), scalar(value)?.into()]))
+            .collect::<Result<Vec<_>, String>>()?
+            .concat(),
+        // `route: {host: upstream}` and the like
+        Value::Mapping(entries) => entries
+            .into_iter()
+            .map(|(key, value)| {
+                Ok([
+                    flag.clone(),
+                    format!("{}={}", scalar(key)?, scalar(value)?).into(),
+                ])
+            })
+            .collect::<Result<Vec<_>, String>>()?
+            .concat(),
+        value => vec![flag, scalar(value)?.into()],
+    })
+}
+
+/// Handling of request bodies that arrive with a `Content-Encoding`
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnPreencoded {
+    /// Decode the body for the upstream, the peer side of a proxy pair
+    Decompress,
+    /// Forward the body as is
+    Passthrough,
+    /// Decode the body and encode it again with `--algorithm`
+    Transcode,
+}
+
+/// Format of the access log lines
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Fallback for requests no route matches
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteDefault {
+    /// Forward to `--target`
+    Target,
+    /// Answer with 404 Not Found
+    NotFound,
+}
+
+/// What `--sticky` keeps clients on their target by
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sticky {
+    /// The address of the client
+    Ip,
+    /// A cookie the proxy sets on the first response, see `--sticky-cookie`
+    Cookie,
+}
+
+/// Where a `--route` or `--path-route` sends requests to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub upstream: String,
+    /// Compression of request bodies for this upstream, `--algorithm` when `None`
+    pub codec: Option<Codec>,
+    /// `Host` sent to this upstream instead of `--upstream-host-header` or the client's
+    pub host: Option<String>,
+}
+
+impl Route {
+    /// A route compressing as configured globally
+    pub fn to(upstream: &str) -> Self {
+        Self {
+            upstream: upstream.to_string(),
+            codec: None,
+            host: None,
+        }
+    }
+}
+
+/// An algorithm and, unless its global level applies, a level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Codec {
+    pub algorithm: Algorithm,
+    pub level: Option<i32>,
+}
+
+/// `upstream` or `upstream,algorithm[:level]`
+fn parse_route_target(target: &str) -> Result<Route, String> {
+    let mut parts = target.split(',');
+    let mut route = Route::to(parts.next().unwrap_or_default());
+    for part in parts {
+        match part.trim().strip_prefix("host=") {
+            Some(host) if !host.is_empty() => route.host = Some(parse_header_value(host)?),
+            Some(_) => return Err(format!("empty host in {target:?}")),
+            None => route.codec = Some(parse_codec(part)?),
+        }
+    }
+    Ok(route)
+}
+
+fn parse_codec(codec: &str) -> Result<Codec, String> {
+    let (algorithm, level) = match codec.split_once(':') {
+        Some((algorithm, level)) => (algorithm, Some(level)),
+        None => (codec, None),
+    };
+    let algorithm = Algorithm::by_name(algorithm)?;
+    let levels = algorithm.levels();
+    let level = level
+        .map(|level| {
+            level
+                .trim()
+                .parse::<i32>()
+                .ok()
+                .filter(|level| levels.contains(level))
+                .ok_or_else(|| {
+                    format!(
+                        "{algorithm:?} level must be {} to {}, got {level:?}",
+                        levels.start(),
+                        levels.end()
+                    )
+                })
+        })
+        .transpose()?;
+    Ok(Codec { algorithm, level })
+}
+
+fn parse_route(route: &str) -> Result<(String, Route), String> {
+    match route.split_once('=') {
+        Some((host, target)) if !host.is_empty() && !target.is_empty() => {
+            Ok((host.to_ascii_lowercase(), parse_route_target(target)?))
+        }
+        _ => Err(format!("expected host=upstream, got {route:?}")),
+    }
+}
+
+fn parse_cookie_name(name: &str) -> Result<String, String> {
+    // a token as RFC 6265 has it
+    let separator = |b: u8| b"()<>@,;:\\\"/[]?={} \t".contains(&b);
+    if !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic() && !separator(b)) {
+        Ok(name.to_string())
+    } else {
+        Err(format!("not a cookie name: {name:?}"))
+    }
+}
+
+fn parse_target(target: &str) -> Result<(String, u32), String> {
+    let Some((upstream, weight)) = target.split_once('=') else {
+        return Ok((target.to_string(), 1));
+    };
+    match weight.trim().parse::<u32>() {
+        Ok(weight) if weight > 0 && !upstream.is_empty() => Ok((upstream.to_string(), weight)),
+        _ => Err(format!(
+            "expected upstream=weight, weight 1 or more, got {target:?}"
+        )),
+    }
+}
+
+fn parse_upstream_name(name: &str) -> Result<(String, String), String> {
+    match name.split_once('=') {
+        Some((upstream, name)) if !upstream.is_empty() && !name.is_empty() => {
+            Ok((upstream.to_string(), name.to_string()))
+        }
+        _ => Err(format!("expected upstream=name, got {name:?}")),
+    }
+}
+
+fn parse_bind(bind: &str) -> Result<(IpAddr, Option<u16>), String> {
+    if let Ok(addr) = bind.parse::<SocketAddr>() {
+        return Ok((addr.ip(), Some(addr.port())));
+    }
+    // `[::1]` is as good as `::1`
+    let ip = bind.strip_prefix('[').and_then(|ip| ip.strip_suffix(']'));
+    ip.unwrap_or(bind)
+        .parse()
+        .map(|ip| (ip, None))
+        .map_err(|_| format!("expected an address, optionally with a port, got {bind:?}"))
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("expected an octal mode like 660, got {mode:?}"))
+}
+
+fn parse_positive(number: &str) -> Result<f64, String> {
+    match number.parse::<f64>() {
+        Ok(parsed) if parsed > 0.0 && parsed.is_finite() => Ok(parsed),
+        _ => Err(format!("expected a positive number, got {number:?}")),
+    }
+}
+
+fn parse_header_name(name: &str) -> Result<String, String> {
+    http::HeaderName::from_bytes(name.as_bytes())
+        .map(|name| name.as_str().to_string())
+        .map_err(|e| format!("{name:?}: {e}"))
+}
+
+/// Headers framing and encoding bodies, which only the proxy itself sets
+const MANAGED_HEADERS: [&str; 3] = ["content-encoding", "content-length", "transfer-encoding"];
+
+fn parse_credentials(credentials: &str) -> Result<String, String> {
+    match credentials.split_once(':') {
+        Some((user, _)) if !user.is_empty() => Ok(credentials.to_string()),
+        _ => Err("expected user:password".to_string()),
+    }
+}
+
+fn parse_header_value(value: &str) -> Result<String, String> {
+    http::HeaderValue::from_str(value)
+        .map(|_| value.to_string())
+        .map_err(|e| format!("{value:?}: {e}"))
+}
+
+fn parse_extra_header_name(name: &str) -> Result<String, String> {
+    let name = parse_header_name(name)?;
+    if MANAGED_HEADERS.contains(&name.as_str()) {
+        return Err(format!("{name} is managed by the proxy"));
+    }
+    Ok(name)
+}
+
+/// `name:value`, or `name=value` as config file mappings are passed
+fn parse_extra_header(header: &str) -> Result<(String, String), String> {
+    // neither can be part of a header name
+    let (name, value) = header
+        .split_once([':', '='])
+        .ok_or_else(|| format!("expected name:value, got {header:?}"))?;
+    let value = value.trim();
+    http::HeaderValue::from_str(value).map_err(|e| format!("{value:?}: {e}"))?;
+    Ok((parse_extra_header_name(name.trim())?, value.to_string()))
+}
+
+fn parse_path_route(route: &str) -> Result<(String, Route), String> {
+    match route.split_once('=') {
+        Some((prefix, target)) if prefix.starts_with('/') && !target.is_empty() => {
+            Ok((prefix.to_string(), parse_route_target(target)?))
+        }
+        _ => Err(format!("expected /prefix=upstream, got {route:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zstd_level() {
+        let config = Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80"]).unwrap();
+        assert_eq!(config.zstd_level, 6);
+
+        let config =
+            Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80", "--zstd-level", "-7"])
+                .unwrap();
+        assert_eq!(config.zstd_level, -7);
+
+        for level in ["-8", "23"] {
+            assert!(
+                Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80", "--zstd-level", level])
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn gzip_header() {
+        let parse = |args: &[&str]| {
+            let args = ["http-proxy", "-t", "127.0.0.1:80"].iter().chain(args);
+            Config::try_parse_from(args).unwrap()
+        };
+        let config = parse(&["--gzip-filename", "body", "--gzip-mtime"]);
+        assert_eq!(config.gzip_filename.as_deref(), Some("body"));
+        assert!(config.gzip_mtime);
+        let config = parse(&["--gzip-mtime", "--gzip-deterministic"]);
+        assert!(!config.gzip_mtime && config.gzip_deterministic);
+    }
+
+    #[test]
+    fn gzip_level() {
+        let config = Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80"]).unwrap();
+        assert_eq!(config.gzip_level, 6);
+
+        let config =
+            Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80", "--gzip-level", "9"])
+                .unwrap();
+        assert_eq!(config.gzip_level, 9);
+
+        assert!(
+            Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80", "--gzip-level", "10"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn weighted_target() {
+        let config =
+            Config::try_parse_from(["http-proxy", "-t", "a:80=3", "-t", "[::1]:80"]).unwrap();
+        assert_eq!(
+            config.target,
+            [("a:80".to_string(), 3), ("[::1]:80".to_string(), 1)]
+        );
+        for bad in ["a:80=0", "a:80=x", "=2", "a:80="] {
+            assert!(
+                Config::try_parse_from(["http-proxy", "-t", bad]).is_err(),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn sticky() {
+        let config =
+            Config::try_parse_from(["http-proxy", "-t", "a:80", "--sticky", "cookie"]).unwrap();
+        assert_eq!(config.sticky, Some(Sticky::Cookie));
+        assert_eq!(config.sticky_cookie, "proxy-affinity");
+        for bad in ["", "a b", "a=b", "a;b", "\"a\""] {
+            let parsed =
+                Config::try_parse_from(["http-proxy", "-t", "a:80", "--sticky-cookie", bad]);
+            assert!(parsed.is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn algorithm() {
+        let config = Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80"]).unwrap();
+        assert_eq!(config.algorithm, Algorithm::Zstd);
+
+        let config =
+            Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80", "--algorithm", "gzip"])
+                .unwrap();
+        assert_eq!(config.algorithm, Algorithm::Gzip);
+
+        let config =
+            Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80", "-a", "br"]).unwrap();
+        assert_eq!(config.algorithm, Algorithm::Brotli);
+        assert_eq!(config.algorithm.content_encoding(), "br");
+
+        let config =
+            Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80", "-a", "identity"]).unwrap();
+        assert_eq!(config.algorithm, Algorithm::Identity);
+        let config = Config::try_parse_from([
+            "http-proxy",
+            "-t",
+            "127.0.0.1:80",
+            "--route",
+            "a.example.com=10.0.0.1:80,identity",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.route[0].1.codec.unwrap().algorithm,
+            Algorithm::Identity
+        );
+
+        assert!(
+            Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80", "--algorithm", "lz4"])
+                .is_err()
+        );
+
+        // an embedding crate's codec, at whatever levels it has
+        crate::compress::register_codec(
+            "x-config-codec",
+            Box::new(|level| {
+                assert_eq!(level, 42);
+                Box::new(crate::compress::Identity::default())
+            }),
+        );
+        let config = Config::try_parse_from([
+            "http-proxy",
+            "-t",
+            "127.0.0.1:80",
+            "-a",
+            "x-config-codec",
+            "--route",
+            "a.example.com=10.0.0.1:80,X-Config-Codec:42",
+        ])
+        .unwrap();
+        assert_eq!(config.algorithm, Algorithm::Registered("x-config-codec"));
+        assert_eq!(config.algorithm.content_encoding(), "x-config-codec");
+        let codec = config.route[0].1.codec.unwrap();
+        assert_eq!(codec.level, Some(42));
+        assert!(codec.algorithm.compressor(codec.level).is_ok());
+        assert!(codec.algorithm.decompressor(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn compression_exemptions() {
+        let config = Config::try_parse_from([
+            "http-proxy",
+            "-t",
+            "127.0.0.1:80",
+            "--no-compress-path",
+            "/upload",
+            "--no-compress-path",
+            "/stream/",
+            "--no-compress-method",
+            "PUT,PATCH",
+        ])
+        .unwrap();
+        assert_eq!(config.no_compress_path, ["/upload", "/stream/"]);
+        assert_eq!(
+            config.no_compress_method,
+            [http::Method::PUT, http::Method::PATCH]
+        );
+        let bad = ["http-proxy", "-t", "x:80", "--no-compress-method", "P UT"];
+        assert!(Config::try_parse_from(bad).is_err());
+    }
+
+    #[test]
+    fn compress_content_types() {
+        let config = Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80"]).unwrap();
+        assert_eq!(
+            config.compress_content_types,
+            ["text/*", "application/json", "application/xml"]
+        );
+
+        let config = Config::try_parse_from([
+            "http-proxy",
+            "-t",
+            "127.0.0.1:80",
+            "--compress-content-types",
+            "text/html,application/*",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.compress_content_types,
+            ["text/html", "application/*"]
+        );
+    }
+
+    #[test]
+    fn route() {
+        let config = Config::try_parse_from([
+            "http-proxy",
+            "-t",
+            "127.0.0.1:80",
+            "--route",
+            "API.example.com=10.0.0.1:80",
+            "--route",
+            "static.example.com=10.0.0.2:80",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.route,
+            [
+                ("api.example.com".to_string(), Route::to("10.0.0.1:80")),
+                ("static.example.com".to_string(), Route::to("10.0.0.2:80"))
+            ]
+        );
+        assert_eq!(config.route_default, RouteDefault::Target);
+
+        let config = Config::try_parse_from([
+            "http-proxy",
+            "-t",
+            "127.0.0.1:80",
+            "--upstream-name",
+            "127.0.0.1:80=primary",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.upstream_name,
+            [("127.0.0.1:80".to_string(), "primary".to_string())]
+        );
+        for bad in ["127.0.0.1:80", "=primary", "127.0.0.1:80="] {
+            let parsed =
+                Config::try_parse_from(["http-proxy", "-t", "x:80", "--upstream-name", bad]);
+            assert!(parsed.is_err(), "{bad}");
+        }
+
+        let config = Config::try_parse_from([
+            "http-proxy",
+            "-t",
+            "127.0.0.1:80",
+            "--route",
+            "a.example.com=10.0.0.1:80,gzip",
+            "--path-route",
+            "/b=10.0.0.2:80,br:11",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.route[0].1.codec,
+            Some(Codec {
+                algorithm: Algorithm::Gzip,
+                level: None
+            })
+        );
+        assert_eq!(config.path_route[0].1.upstream, "10.0.0.2:80");
+        assert_eq!(
+            config.path_route[0].1.codec,
+            Some(Codec {
+                algorithm: Algorithm::Brotli,
+                level: Some(11)
+            })
+        );
+
+        let config = Config::try_parse_from([
+            "http-proxy",
+            "-t",
+            "127.0.0.1:80",
+            "--route",
+            "a.example.com=10.0.0.1:80,host=internal.example.com",
+            "--path-route",
+            "/b=10.0.0.2:80,gzip,host=b.internal",
+        ])
+        .unwrap();
+        assert_eq!(config.route[0].1.upstream, "10.0.0.1:80");
+        assert_eq!(config.route[0].1.codec, None);
+        assert_eq!(
+            config.route[0].1.host.as_deref(),
+            Some("internal.example.com")
+        );
+        assert_eq!(config.path_route[0].1.host.as_deref(), Some("b.internal"));
+        assert!(config.path_route[0].1.codec.is_some());
+
+        for route in [
+            "example.com",
+            "=10.0.0.1:80",
+            "example.com=",
+            "example.com=10.0.0.1:80,host=",
+            "example.com=10.0.0.1:80,host=a\nb",
+            "example.com=10.0.0.1:80,lz4",
+            "example.com=10.0.0.1:80,gzip:10",
+            "example.com=10.0.0.1:80,zstd:x",
+        ] {
+            assert!(
+                Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80", "--route", route])
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn bool_option() {
+        let config = Config::try_parse_from(["http-proxy", "-t", "a:1"]).unwrap();
+        assert!(config.adjust_downstream_decompression);
+        let config = Config::try_parse_from([
+            "http-proxy",
+            "-t",
+            "a:1",
+            "--adjust-downstream-decompression",
+            "false",
+        ])
+        .unwrap();
+        assert!(!config.adjust_downstream_decompression);
+
+        let path =
+            std::env::temp_dir().join(format!("http-proxy-bool-{}.yaml", std::process::id()));
+        std::fs::write(
+            &path,
+            "target: a:1
+adjust-downstream-decompression: false
+no-transform: true
+",
+        )
+        .unwrap();
+        let config = Config::load_from(["http-proxy", "--config-file", path.to_str().unwrap()]);
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+        assert!(!config.adjust_downstream_decompression);
+        assert!(config.no_transform);
+    }
+
+    #[test]
+    fn basic_auth() {
+        let parse = |credentials: &str| {
+            Config::try_parse_from(["http-proxy", "-t", "a:1", "--basic-auth", credentials])
+                .map(|config| config.basic_auth)
+        };
+        assert_eq!(parse("alice:pass:word").unwrap(), ["alice:pass:word"]);
+        assert_eq!(parse("alice:").unwrap(), ["alice:"]);
+        assert!(parse("alice").is_err());
+        assert!(parse(":secret").is_err());
+    }
+
+    #[test]
+    fn rate_limit() {
+        let parse = |rate: &str| {
+            Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80", "--rate-limit", rate])
+                .map(|config| config.rate_limit)
+        };
+        assert_eq!(parse("2.5").unwrap(), Some(2.5));
+        for rate in ["0", "-1", "NaN", "inf", "fast"] {
+            assert!(parse(rate).is_err(), "{rate}");
+        }
+    }
+
+    #[test]
+    fn extra_headers() {
+        let config = Config::try_parse_from([
+            "http-proxy",
+            "-t",
+            "127.0.0.1:80",
+            "--add-request-header",
+            "X-Api-Key: secret",
+            "--add-request-header",
+            "x-origin=http://a:80",
+            "--remove-request-header",
+            "Cookie",
+            "--add-response-header",
+            "Strict-Transport-Security:max-age=63072000",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.add_request_header,
+            [
+                ("x-api-key".to_string(), "secret".to_string()),
+                ("x-origin".to_string(), "http://a:80".to_string())
+            ]
+        );
+        assert_eq!(config.remove_request_header, ["cookie"]);
+        assert_eq!(
+            config.add_response_header,
+            [(
+                "strict-transport-security".to_string(),
+                "max-age=63072000".to_string()
+            )]
+        );
+
+        for (flag, header) in [
+            ("--add-request-header", "x-api-key"),
+            ("--add-request-header", "bad name:x"),
+            ("--add-request-header", "x-api-key:a\nb"),
+            ("--add-request-header", "Content-Encoding:gzip"),
+            ("--remove-request-header", "transfer-encoding"),
+            ("--add-response-header", "content-length:1"),
+            ("--remove-response-header", "content-encoding"),
+        ] {
+            assert!(
+                Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80", flag, header]).is_err(),
+                "{header}"
+            );
+        }
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        let config = Config::try_parse_from([
+            "http-proxy",
+            "-t",
+            "127.0.0.1:80",
+            "--trusted-proxies",
+            "10.0.0.0/8,::1",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.trusted_proxies,
+            ["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()]
+        );
+        assert!(
+            Config::try_parse_from([
+                "http-proxy",
+                "-t",
+                "127.0.0.1:80",
+                "--trusted-proxies",
+                "10.0.0.0/40"
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn listen_uds() {
+        let defaults = Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80"]).unwrap();
+        assert_eq!(defaults.listen_uds, None);
+        assert_eq!(defaults.uds_mode, 0o666);
+
+        let config = Config::try_parse_from([
+            "http-proxy",
+            "-t",
+            "127.0.0.1:80",
+            "--listen-uds",
+            "/run/http-proxy.sock",
+            "--uds-mode",
+            "660",
+            "--no-tcp",
+        ])
+        .unwrap();
+        assert_eq!(config.uds_mode, 0o660);
+        assert!(config.no_tcp);
+
+        let parse = |args: &[&str]| {
+            let args = ["http-proxy", "-t", "127.0.0.1:80"].iter().chain(args);
+            Config::try_parse_from(args)
+        };
+        assert!(parse(&["--no-tcp"]).is_err());
+        assert!(parse(&["--uds-mode", "888"]).is_err());
+        assert!(parse(&["--uds-mode", "1777"]).is_err());
+    }
+
+    #[test]
+    fn tls_listener() {
+        let parse = |args: &[&str]| {
+            let args = ["http-proxy", "-t", "127.0.0.1:80"].iter().chain(args);
+            Config::try_parse_from(args)
+        };
+        let config = parse(&["--tls-cert", "cert.pem", "--tls-key", "key.pem"]).unwrap();
+        assert_eq!(config.tls_cert, Some(PathBuf::from("cert.pem")));
+        assert_eq!(config.tls_key, Some(PathBuf::from("key.pem")));
+        assert!(parse(&["--tls-cert", "cert.pem"]).is_err());
+        assert!(parse(&["--tls-key", "key.pem"]).is_err());
+        let uds_only = [
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem",
+            "--listen-uds",
+            "/run/http-proxy.sock",
+            "--no-tcp",
+        ];
+        assert!(parse(&uds_only).is_err());
+
+        // there is no TLS backend to serve it with
+        assert!(
+            config
+                .check_tls()
+                .unwrap_err()
+                .starts_with("--tls-cert needs")
+        );
+        assert!(parse(&[]).unwrap().check_tls().is_ok());
+        let args = [
+            "http-proxy",
+            "-t",
+            "127.0.0.1:80",
+            "--tls-cert",
+            "c",
+            "--tls-key",
+            "k",
+        ];
+        assert!(Config::load_from(args).is_err());
+    }
+
+    #[test]
+    fn bind() {
+        let listen_addr = |args: &[&str]| {
+            let args = ["http-proxy", "-t", "127.0.0.1:80"].iter().chain(args);
+            Config::try_parse_from(args).map(|config| config.listen_addr().to_string())
+        };
+        assert_eq!(listen_addr(&[]).unwrap(), "0.0.0.0:18081");
+        assert_eq!(
+            listen_addr(&["--bind", "127.0.0.1", "-p", "8080"]).unwrap(),
+            "127.0.0.1:8080"
+        );
+        assert_eq!(
+            listen_addr(&["--bind", "[::1]:9000", "-p", "8080"]).unwrap(),
+            "[::1]:9000"
+        );
+        assert_eq!(listen_addr(&["--bind", "::"]).unwrap(), "[::]:18081");
+        assert_eq!(listen_addr(&["--bind", "[::1]"]).unwrap(), "[::1]:18081");
+        for bind in ["localhost", "127.0.0.1:", "127.0.0.1:99999", "[::1"] {
+            assert!(listen_addr(&["--bind", bind]).is_err(), "{bind}");
+        }
+
+        // the admin listener stays on loopback whatever --bind says
+        let metrics_addr = |args: &[&str]| {
+            let args = ["http-proxy", "-t", "127.0.0.1:80"].iter().chain(args);
+            Config::try_parse_from(args).unwrap().metrics_addr()
+        };
+        assert_eq!(metrics_addr(&[]), None);
+        let addr = metrics_addr(&["--bind", "0.0.0.0", "--metrics-port", "9100"]);
+        assert_eq!(addr.unwrap().to_string(), "127.0.0.1:9100");
+        let addr = metrics_addr(&["--metrics-port", "9100", "--metrics-bind", "::"]);
+        assert_eq!(addr.unwrap().to_string(), "[::]:9100");
+    }
+
+    #[test]
+    fn threads() {
+        let defaults = Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80"]).unwrap();
+        assert!(defaults.threads() >= 1);
+        assert_eq!(defaults.listener_tasks_per_fd, 2);
+
+        let config = Config::try_parse_from([
+            "http-proxy",
+            "-t",
+            "127.0.0.1:80",
+            "--threads",
+            "4",
+            "--listener-tasks-per-fd",
+            "1",
+        ])
+        .unwrap();
+        assert_eq!(config.threads(), 4);
+        assert_eq!(config.listener_tasks_per_fd, 1);
+        assert!(
+            Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80", "--threads", "0"]).is_err()
+        );
+    }
+
+    #[test]
+    fn config_file() {
+        let path = std::env::temp_dir().join(format!("http-proxy-{}.yaml", std::process::id()));
+        std::fs::write(
+            &path,
+            "target: [10.0.0.1:80, 10.0.0.2:80]\n\
+             zstd-level: 3\n\
+             debug_stats: true\n\
+             route:\n  api.example.com: 10.0.0.3:80\n\
+             port: 8080\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        let config = Config::load_from(["http-proxy", "--config-file", path]).unwrap();
+        assert_eq!(
+            config.target,
+            [
+                ("10.0.0.1:80".to_string(), 1),
+                ("10.0.0.2:80".to_string(), 1)
+            ]
+        );
+        assert_eq!(config.zstd_level, 3);
+        assert!(config.debug_stats);
+        assert_eq!(
+            config.route,
+            [("api.example.com".to_string(), Route::to("10.0.0.3:80"))]
+        );
+        assert_eq!(config.port, 8080);
+
+        // the command line wins, lists included
+        let config = Config::load_from([
+            "http-proxy",
+            "--config-file",
+            path,
+            "-t",
+            "127.0.0.1:80",
+            "--zstd-level",
+            "9",
+        ])
+        .unwrap();
+        assert_eq!(config.target, [("127.0.0.1:80".to_string(), 1)]);
+        assert_eq!(config.zstd_level, 9);
+        assert_eq!(config.port, 8080);
+
+        std::fs::write(path, "zstd-level: 99\ntarget: [a:80]\n").unwrap();
+        assert!(Config::load_from(["http-proxy", "--config-file", path]).is_err());
+        std::fs::write(path, "no-such-option: 1\ntarget: [a:80]\n").unwrap();
+        assert!(Config::load_from(["http-proxy", "--config-file", path]).is_err());
+        std::fs::remove_file(path).unwrap();
+        assert!(Config::load_from(["http-proxy", "--config-file", path]).is_err());
+    }
+}
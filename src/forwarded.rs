@@ -0,0 +1,112 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An address range like `10.0.0.0/8`, a plain address is a range of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // v4 clients of a dual stack listener show up as mapped v6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|e| format!("{s:?}: {e}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => max,
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("{s:?}: prefix length must be 0 to {max}"))?,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// The `X-Forwarded-For` to send upstream for a request from `client` that came with `chain`.
+/// The chain is only extended when `client` is one of the `trusted` proxies, anyone else could
+/// have made it up.
+pub fn forwarded_for(chain: Option<&str>, client: IpAddr, trusted: &[Cidr]) -> String {
+    match chain.map(str::trim).filter(|chain| !chain.is_empty()) {
+        Some(chain) if trusted.iter().any(|cidr| cidr.contains(client)) => {
+            format!("{chain}, {client}")
+        }
+        _ => client.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cidr() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(!net.contains("::1".parse().unwrap()));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("192.168.1.1".parse().unwrap()));
+        let single: Cidr = "fd00::1".parse().unwrap();
+        assert!(single.contains("fd00::1".parse().unwrap()));
+        assert!(!single.contains("fd00::2".parse().unwrap()));
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains("fd12::1".parse().unwrap()));
+
+        for bad in ["10.0.0.0/33", "fd00::/129", "10.0.0/8", "10.0.0.0/x"] {
+            assert!(bad.parse::<Cidr>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn chain() {
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let proxy = "10.0.0.1".parse().unwrap();
+        let client = "203.0.113.7".parse().unwrap();
+        assert_eq!(
+            forwarded_for(Some("198.51.100.1"), proxy, &trusted),
+            "198.51.100.1, 10.0.0.1"
+        );
+        assert_eq!(forwarded_for(None, proxy, &trusted), "10.0.0.1");
+        // made up by the client
+        assert_eq!(
+            forwarded_for(Some("198.51.100.1"), client, &trusted),
+            "203.0.113.7"
+        );
+        assert_eq!(forwarded_for(Some("198.51.100.1"), proxy, &[]), "10.0.0.1");
+    }
+}
@@ -0,0 +1,155 @@
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Health of one upstream
+#[derive(Default)]
+struct TargetHealth {
+    unhealthy: AtomicBool,
+    /// Failed checks in a row
+    failures: AtomicU32,
+    /// When it last turned healthy again
+    recovered: Mutex<Option<Instant>>,
+}
+
+/// Health of the `--target` upstreams as seen by the active checks. Targets that weren't
+/// checked yet count as healthy.
+#[derive(Default)]
+pub struct Health {
+    targets: RwLock<HashMap<String, Arc<TargetHealth>>>,
+}
+
+impl Health {
+    pub fn is_healthy(&self, target: &str) -> bool {
+        self.targets
+            .read()
+            .unwrap()
+            .get(target)
+            .is_none_or(|health| !health.unhealthy.load(Ordering::Relaxed))
+    }
+
+    /// The part of its share of requests `target` gets, rising from 0 to 1 over `window` after
+    /// it turned healthy again. Targets that never were unhealthy get all of it.
+    pub fn ramp(&self, target: &str, window: Duration) -> f64 {
+        let targets = self.targets.read().unwrap();
+        match targets
+            .get(target)
+            .and_then(|health| *health.recovered.lock().unwrap())
+        {
+            Some(recovered) => (recovered.elapsed().as_secs_f64() / window.as_secs_f64()).min(1.0),
+            None => 1.0,
+        }
+    }
+
+    /// Record the outcome of a check of `target`. It turns unhealthy after `threshold` failures
+    /// in a row and healthy again with the first check that passes.
+    pub fn record(&self, target: &str, passed: bool, threshold: u32) {
+        let health = self.targets.read().unwrap().get(target).cloned();
+        let health = match health {
+            Some(health) => health,
+            None => self
+                .targets
+                .write()
+                .unwrap()
+                .entry(target.to_string())
+                .or_default()
+                .clone(),
+        };
+        if passed {
+            health.failures.store(0, Ordering::Relaxed);
+            if health.unhealthy.swap(false, Ordering::Relaxed) {
+                info!("upstream {target} is healthy again");
+                *health.recovered.lock().unwrap() = Some(Instant::now());
+            }
+        } else {
+            let failures = health.failures.fetch_add(1, Ordering::Relaxed) + 1;
+            if failures >= threshold && !health.unhealthy.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "upstream {target} failed {failures} health checks, taking it out of rotation"
+                );
+            }
+        }
+    }
+}
+
+/// Whether `target` answers a `GET path` with a 2xx within `timeout`. Without a `path` only a
+/// connection is made, for upstreams that don't speak plain HTTP.
+pub async fn probe(target: &str, host: &str, path: Option<&str>, timeout: Duration) -> bool {
+    let check = async {
+        let mut stream = TcpStream::connect(target).await?;
+        let Some(path) = path else {
+            return Ok(true);
+        };
+        let request = format!(
+            "GET {path} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: http-proxy\r\nConnection: close\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes()).await?;
+        // `HTTP/1.1 200` is all that's needed
+        let mut status_line = [0; 12];
+        stream.read_exact(&mut status_line).await?;
+        Ok::<_, std::io::Error>(status_line.starts_with(b"HTTP/1.") && status_line[9] == b'2')
+    };
+    matches!(tokio::time::timeout(timeout, check).await, Ok(Ok(true)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threshold() {
+        let health = Health::default();
+        assert!(health.is_healthy("a:80"));
+        health.record("a:80", false, 2);
+        assert!(health.is_healthy("a:80"));
+        health.record("a:80", false, 2);
+        assert!(!health.is_healthy("a:80"));
+        assert!(health.is_healthy("b:80"));
+        health.record("a:80", true, 2);
+        assert!(health.is_healthy("a:80"));
+        // the count starts over after a passing check
+        health.record("a:80", false, 2);
+        assert!(health.is_healthy("a:80"));
+    }
+
+    #[test]
+    fn ramp() {
+        let health = Health::default();
+        let window = Duration::from_secs(60);
+        assert_eq!(health.ramp("a:80", window), 1.0);
+        health.record("a:80", true, 1);
+        assert_eq!(health.ramp("a:80", window), 1.0);
+
+        health.record("a:80", false, 1);
+        health.record("a:80", true, 1);
+        assert!(health.ramp("a:80", window) < 0.1);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(health.ramp("a:80", Duration::from_millis(10)), 1.0);
+    }
+
+    #[tokio::test]
+    async fn probe_status() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            for status in ["200 OK", "503 Service Unavailable"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await.unwrap();
+                let response = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n");
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let timeout = Duration::from_secs(5);
+        assert!(probe(&target, "localhost", Some("/healthz"), timeout).await);
+        assert!(!probe(&target, "localhost", Some("/healthz"), timeout).await);
+        server.await.unwrap();
+
+        // nothing listens anymore
+        assert!(!probe(&target, "localhost", None, timeout).await);
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+/// Requests seen per downstream connection, for `--downstream-max-requests`. A connection is
+/// told apart by a value it shares between its requests, like its socket digest, and forgotten
+/// once nothing holds that value anymore.
+pub struct RequestCounts<T> {
+    counts: Mutex<Counts<T>>,
+}
+
+struct Counts<T> {
+    by_connection: HashMap<usize, (Weak<T>, u64)>,
+    /// Entries at the last pruning of closed connections
+    pruned_at: usize,
+}
+
+impl<T> Default for RequestCounts<T> {
+    fn default() -> Self {
+        Self {
+            counts: Mutex::new(Counts {
+                by_connection: HashMap::new(),
+                pruned_at: 0,
+            }),
+        }
+    }
+}
+
+impl<T> RequestCounts<T> {
+    /// Count a request on the connection of `shared`, returning how many it has had so far
+    pub fn count(&self, shared: &Arc<T>) -> u64 {
+        let counts = &mut *self.counts.lock().unwrap();
+        if counts.by_connection.len() > 2 * counts.pruned_at.max(64) {
+            counts
+                .by_connection
+                .retain(|_, (connection, _)| connection.strong_count() > 0);
+            counts.pruned_at = counts.by_connection.len();
+        }
+        // the weak reference keeps the address from being reused until the entry is pruned
+        let entry = counts
+            .by_connection
+            .entry(Arc::as_ptr(shared) as usize)
+            .or_insert_with(|| (Arc::downgrade(shared), 0));
+        entry.1 += 1;
+        entry.1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_connection() {
+        let counts = RequestCounts::default();
+        let a = Arc::new(1);
+        let b = Arc::new(2);
+        assert_eq!(counts.count(&a), 1);
+        assert_eq!(counts.count(&a.clone()), 2);
+        assert_eq!(counts.count(&b), 1);
+        assert_eq!(counts.count(&a), 3);
+
+        // closed connections go away eventually
+        drop((a, b));
+        let closed: Vec<_> = (0..100).map(Arc::new).collect();
+        for connection in &closed {
+            assert_eq!(counts.count(connection), 1);
+        }
+        drop(closed);
+        let open: Vec<_> = (0..40).map(Arc::new).collect();
+        for connection in &open {
+            assert_eq!(counts.count(connection), 1);
+        }
+        assert!(counts.counts.lock().unwrap().by_connection.len() <= 40);
+    }
+}
@@ -1,2 +1,15 @@
+pub mod accept_encoding;
+pub mod access_log;
+pub mod auth;
+pub mod cache;
 pub mod compress;
-pub mod config;
\ No newline at end of file
+pub mod config;
+pub mod forwarded;
+pub mod health;
+pub mod keepalive;
+pub mod metrics;
+pub mod pool;
+pub mod rate_limit;
+pub mod request_id;
+pub mod sticky;
+pub mod tap;
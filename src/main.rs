@@ -1,231 +1,3713 @@
 use async_trait::async_trait;
-use bytes::Bytes;
-use clap::Parser;
-use flate2::{GzBuilder, write::GzEncoder};
-use http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING};
-use http_proxy::compress::{Compressor, Decompressor, Encode, ZstdCompressor, ZstdDecompressor};
-use http_proxy::config::{self, Config};
+use bytes::{Bytes, BytesMut};
+use http::header::{
+    ACCEPT_ENCODING, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD,
+    AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HOST, RANGE, RETRY_AFTER,
+    SET_COOKIE, TRANSFER_ENCODING, VARY, WWW_AUTHENTICATE,
+};
+use http_proxy::accept_encoding::{self, Coding};
+use http_proxy::access_log::AccessLog;
+use http_proxy::auth;
+use http_proxy::cache::{self, Cache, Cached};
+use http_proxy::compress::{
+    self, Algorithm, BrotliCompressor, Chain, Compressor, Decompressor, DeflateCompressor, Encode,
+    GzipHeader, ZSTD_WINDOW_LOG_MAX, ZstdCompressor, ZstdDecompressor,
+};
+use http_proxy::config::{Codec, Config, OnPreencoded, Route, RouteDefault, Sticky};
+use http_proxy::forwarded::forwarded_for;
+use http_proxy::health::{Health, probe};
+use http_proxy::keepalive::RequestCounts;
+use http_proxy::metrics::{Metrics, MetricsApp};
+use http_proxy::rate_limit::RateLimiter;
+use http_proxy::request_id;
+use http_proxy::sticky::{self, Ring};
+use http_proxy::tap::{self, Tap};
+use log::{debug, info, warn};
+use pingora::listeners::TcpSocketOptions;
+use pingora::protocols::SocketDigest;
 use pingora::server::configuration::ServerConf;
+use pingora::server::{RunArgs, ShutdownWatch};
+use pingora::services::background::{BackgroundService, background_service};
+use pingora::services::listening::Service;
+use pingora::upstreams::peer::{Peer, Proxy as EgressProxy};
 use pingora::{
-    Result,
+    Error, ErrorSource,
+    ErrorType::{
+        ConnectTimedout, ConnectionClosed, HTTPStatus, InternalError, ReadError, ReadTimedout,
+        WriteError, WriteTimedout,
+    },
+    OkOrErr, Result,
     http::{RequestHeader, ResponseHeader},
     prelude::{HttpPeer, Opt},
-    proxy::{ProxyHttp, Session},
+    proxy::{FailToProxy, ProxyHttp, Session},
     server::Server,
 };
-use std::fs::File;
-use std::io::Write;
-use std::{env, sync::Arc};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-fn main() {
-    env_logger::init();
-    let server_conf = ServerConf {
-        threads: 128,
-        listener_tasks_per_fd: 2,
-        ..Default::default()
-    };
+/// The level `--verbose` given `count` times logs at
+fn log_level(count: u8) -> log::LevelFilter {
+    match count {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
+}
 
-    let config = Config::parse();
-    let mut opt = Opt::default();
-    if let Ok(mut file) = File::create("config.yaml") {
-        let _ = file.write_all(server_conf.to_yaml().as_bytes());
-        let _ = file.flush();
+fn main() {
+    let config = Config::load();
+    env_logger::Builder::new()
+        .filter_level(log_level(config.verbose))
+        .parse_env(env_logger::Env::default())
+        .init();
+    if config.check_config {
+        match check_config(&config) {
+            Ok(summary) => {
+                print!("{summary}");
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("invalid configuration: {e}");
+                std::process::exit(1);
+            }
+        }
     }
-    opt.conf = Some("config.yaml".to_string());
-    let mut my_server = Server::new(Some(opt)).unwrap();
+    // pingora's own server configuration, unrelated to `--config-file`
+    let mut my_server = match &config.pingora_conf {
+        Some(path) => {
+            let opt = Opt {
+                conf: Some(path.to_string_lossy().into_owned()),
+                ..Default::default()
+            };
+            Server::new(Some(opt)).unwrap_or_else(|e| {
+                eprintln!("failed to load --pingora-conf: {e}");
+                std::process::exit(1);
+            })
+        }
+        None => {
+            let server_conf = ServerConf {
+                threads: config.threads(),
+                listener_tasks_per_fd: config.listener_tasks_per_fd as usize,
+                upstream_keepalive_pool_size: config.upstream_pool_size as usize,
+                grace_period_seconds: Some(config.shutdown_timeout),
+                // the grace period is all requests get
+                graceful_shutdown_timeout_seconds: Some(0),
+                ..Default::default()
+            };
+            Server::new_with_opt_and_conf(Opt::default(), server_conf)
+        }
+    };
     my_server.bootstrap();
-    let mut my_proxy = pingora::proxy::http_proxy_service(
-        &Arc::new(server_conf),
-        Proxy0 {
-            config: config.clone(),
-            zstd: true,
+    let proxy = Proxy0::new(config.clone(), std::env::args_os().collect()).unwrap_or_else(|e| {
+        eprintln!("failed to read {e}");
+        std::process::exit(1);
+    });
+    my_server.add_service(background_service(
+        "config reload",
+        Reloader(proxy.settings.clone()),
+    ));
+    my_server.add_service(background_service(
+        "health check",
+        HealthChecker {
+            settings: proxy.settings.clone(),
+            health: proxy.health.clone(),
         },
-    );
-    my_proxy.add_tcp(&format!("0.0.0.0:{}", config.port));
+    ));
+    my_server.add_service(background_service(
+        "shutdown",
+        Drainer {
+            in_flight: proxy.in_flight.clone(),
+            uds: config.listen_uds.clone(),
+        },
+    ));
+    if let Some(addr) = config.metrics_addr() {
+        let app = MetricsApp {
+            metrics: proxy.metrics.clone(),
+            draining: proxy.draining.clone(),
+        };
+        let mut metrics = Service::new("metrics".to_string(), app);
+        metrics.add_tcp(&addr.to_string());
+        my_server.add_service(metrics);
+    }
+    let mut my_proxy = pingora::proxy::http_proxy_service(&my_server.configuration, proxy);
+    if !config.no_tcp {
+        let addr = config.listen_addr().to_string();
+        my_proxy.add_tcp_with_settings(&addr, tcp_options(&config));
+    }
+    if let Some(path) = &config.listen_uds {
+        // pingora replaces a stale socket left at the path
+        my_proxy.add_uds(
+            &path.to_string_lossy(),
+            Some(Permissions::from_mode(config.uds_mode)),
+        );
+    }
     my_server.add_service(my_proxy);
-    my_server.run_forever();
+    my_server.run(RunArgs::default());
+    exit(config.listen_uds.as_deref());
 }
 
-pub enum Compreessor0 {
-    Gzip(Compressor),
-    Zstd(ZstdCompressor),
+/// Options of the `--bind` listener
+fn tcp_options(config: &Config) -> TcpSocketOptions {
+    let mut options = TcpSocketOptions::default();
+    options.tcp_fastopen = config.tcp_fastopen.map(|queue| queue as usize);
+    options
 }
 
-impl Compreessor0 {
-    fn encode(&mut self, input: &[u8], end: bool) -> Result<Bytes> {
-        match self {
-            Compreessor0::Gzip(compressor) => compressor.encode(input, end),
-            Compreessor0::Zstd(zstd_compressor) => zstd_compressor.encode(input, end),
-        }
+/// Resolve every upstream of `config`, the targets and those of routes. Returns how many there
+/// are.
+fn resolve_upstreams(config: &Config) -> std::result::Result<usize, String> {
+    let upstreams: Vec<&str> = config
+        .target
+        .iter()
+        .map(|(target, _)| target.as_str())
+        .chain(
+            config
+                .route
+                .iter()
+                .map(|(_, route)| route.upstream.as_str()),
+        )
+        .chain(
+            config
+                .path_route
+                .iter()
+                .map(|(_, route)| route.upstream.as_str()),
+        )
+        .collect();
+    for upstream in &upstreams {
+        std::net::ToSocketAddrs::to_socket_addrs(upstream)
+            .map_err(|e| format!("upstream {upstream}: {e}"))?;
+    }
+    Ok(upstreams.len())
+}
+
+/// What `--check-config` validates beyond parsing: the files the proxy reads at startup and
+/// that every upstream resolves. Returns a summary of the configuration.
+fn check_config(config: &Config) -> std::result::Result<String, String> {
+    let settings = Settings::new(config.clone())?;
+    if let Some(path) = &config.pingora_conf {
+        ServerConf::load_from_yaml(path.to_string_lossy().into_owned())
+            .map_err(|e| format!("--pingora-conf: {e}"))?;
+    }
+    let upstreams = resolve_upstreams(config)?;
+
+    let mut summary = String::new();
+    if !config.no_tcp {
+        summary += &format!("listen: {}\n", config.listen_addr());
+    }
+    if let Some(path) = &config.listen_uds {
+        summary += &format!("listen: unix:{}\n", path.display());
+    }
+    let targets: Vec<_> = config
+        .target
+        .iter()
+        .map(|(target, weight)| match weight {
+            1 => target.clone(),
+            weight => format!("{target}={weight}"),
+        })
+        .collect();
+    summary += &format!("targets: {}\n", targets.join(", "));
+    summary += &format!(
+        "routes: {} by host, {} by path\n",
+        settings.routes.len(),
+        settings.path_routes.len()
+    );
+    let scheme = if config.upstream_tls { "https" } else { "http" };
+    summary += &format!("upstream: {scheme}, {upstreams} upstreams resolved\n");
+    let transform = if config.no_transform {
+        "none".to_string()
+    } else {
+        format!("{:?}", config.algorithm).to_lowercase()
+    };
+    summary += &format!("compression: {transform}\n");
+    Ok(summary)
+}
+
+/// Remove the `--listen-uds` socket and exit the process
+fn exit(uds: Option<&Path>) -> ! {
+    if let Some(path) = uds {
+        let _ = std::fs::remove_file(path);
+    }
+    info!("all requests done, exiting");
+    std::process::exit(0)
+}
+
+/// The OS byte of a gzip header for Unix
+const GZIP_OS_UNIX: u8 = 3;
+
+/// `Retry-After` of a request over `--max-concurrent-requests`
+const RETRY_AFTER_SECS: u64 = 1;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// Log how much a finished body was transformed by `encoder`
+fn log_stat(encoder: &dyn Encode) {
+    let (name, total_in, total_out, duration) = encoder.stat();
+    // an empty body is passed along as is
+    let ratio = if total_in == 0 {
+        1.0
+    } else {
+        total_out as f64 / total_in as f64
+    };
+    info!("{name}: {total_in} -> {total_out} bytes, ratio {ratio:.3}, took {duration:?}");
+}
+
+/// Steps of `--target` weights, see `--slow-start-seconds`
+const WEIGHT_STEPS: i64 = 100;
+
+/// Request header overriding the compression level, see `--allow-header-overrides`
+const COMPRESS_LEVEL_HEADER: &str = "x-proxy-compress-level";
+
+/// Expose what `encoder` did to the request body, for `--debug-stats`
+fn insert_stat_headers(encoder: &dyn Encode, response: &mut ResponseHeader) -> Result<()> {
+    let (name, total_in, total_out, _) = encoder.stat();
+    response.insert_header("x-proxy-compress-algo", name)?;
+    response.insert_header("x-proxy-bytes-in", total_in)?;
+    response.insert_header("x-proxy-bytes-out", total_out)?;
+    Ok(())
+}
+
+/// The parsed `Accept-Encoding` of `req`, `None` when it has no such header
+fn accepted_codings(req: &RequestHeader) -> Option<Vec<Coding<'_>>> {
+    let mut values = req.headers.get_all(ACCEPT_ENCODING).iter().peekable();
+    values.peek()?;
+    Some(accept_encoding::parse(
+        values.filter_map(|value| value.to_str().ok()),
+    ))
+}
+
+/// The host part of a `host:port` target, without the brackets of an IPv6 address
+fn target_host(target: &str) -> &str {
+    let host = match target.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => target,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// The host `req` is addressed to, without a port
+fn request_host(req: &RequestHeader) -> Option<&str> {
+    match req.headers.get(http::header::HOST) {
+        Some(host) => host.to_str().ok().map(target_host),
+        // HTTP/2 carries it in the `:authority` pseudo header
+        None => req.uri.host(),
+    }
+}
+
+/// Whether `req` asks to switch protocols, as WebSockets do. What follows isn't a body.
+fn is_upgrade(req: &RequestHeader) -> bool {
+    req.headers.contains_key(http::header::UPGRADE)
+        && req
+            .headers
+            .get_all(http::header::CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+/// Send the body of `req` chunked, now that its length isn't known up front. HTTP/2 has no
+/// `Transfer-Encoding`, upstreams reject it, DATA frames delimit the body there.
+fn set_chunked(req: &mut RequestHeader) -> Result<()> {
+    req.remove_header(&TRANSFER_ENCODING);
+    // pingora neither reads nor writes request trailers, the upstream won't get what this
+    // announces
+    req.remove_header(&http::header::TRAILER);
+    if req.version != http::Version::HTTP_2 {
+        req.insert_header(TRANSFER_ENCODING, "chunked")?;
+    }
+    Ok(())
+}
+
+/// Say the body of `req` is encoded with `algorithm`, in one `Content-Encoding` header however
+/// many the client sent
+fn set_content_encoding(req: &mut RequestHeader, algorithm: Algorithm) -> Result<()> {
+    req.remove_header(&CONTENT_ENCODING);
+    req.insert_header(CONTENT_ENCODING, algorithm.content_encoding())
+}
+
+/// The `Content-Length` of `req`, if it has a valid one
+fn content_length(req: &RequestHeader) -> Option<usize> {
+    req.headers
+        .get(CONTENT_LENGTH)
+        .and_then(|cl| cl.to_str().ok())
+        .and_then(|cl| cl.parse::<usize>().ok())
+}
+
+/// Bytes of the request line and headers of `req` as sent over HTTP/1.1, line ends included
+fn header_size(req: &RequestHeader) -> usize {
+    let target = req.uri.path_and_query().map_or("/", |pq| pq.as_str());
+    // "GET /path HTTP/1.1\r\n"
+    let request_line = req.method.as_str().len() + 1 + target.len() + 1 + 8 + 2;
+    let headers: usize = req
+        .headers
+        .iter()
+        // "name: value\r\n"
+        .map(|(name, value)| name.as_str().len() + 2 + value.len() + 2)
+        .sum();
+    request_line + headers + 2
+}
+
+/// Whether `req` comes with a body. Without a `Content-Length` or `Transfer-Encoding` it has
+/// none, whatever the method.
+fn has_body(req: &RequestHeader) -> bool {
+    match content_length(req) {
+        Some(cl) => cl > 0,
+        None => req.headers.contains_key(TRANSFER_ENCODING),
     }
 }
 
-pub enum Decompreessor0 {
-    Gzip(Decompressor),
-    Zstd(ZstdDecompressor),
+/// Request body bytes pingora keeps to replay them upstream
+const RETRY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Time the rest of a request body turned away with 413 is read and dropped for
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether a body of `content_length` bytes can have its first `sample_size` bytes read ahead.
+/// Pingora keeps what is read ahead in a 64 KiB buffer and sends it on by itself, otherwise
+/// `request_body_filter` has to. Neither can happen when the body ended while the buffer
+/// overflowed, which the last read can only do for lengths in between.
+fn can_sample(content_length: usize, sample_size: usize) -> bool {
+    content_length <= RETRY_BUFFER_SIZE || content_length >= RETRY_BUFFER_SIZE + sample_size
+}
+
+/// The answer to `req` when it is a CORS preflight and `--cors-allow-origin` is set
+fn cors_preflight(config: &Config, req: &RequestHeader) -> Result<Option<ResponseHeader>> {
+    let Some(origin) = &config.cors_allow_origin else {
+        return Ok(None);
+    };
+    if req.method != http::Method::OPTIONS
+        || !req.headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        return Ok(None);
+    }
+    let mut response = ResponseHeader::build(204, Some(5))?;
+    insert_cors_origin(&mut response, origin)?;
+    let methods: Vec<&str> = config
+        .cors_allow_methods
+        .iter()
+        .map(http::Method::as_str)
+        .collect();
+    response.insert_header(ACCESS_CONTROL_ALLOW_METHODS, methods.join(", "))?;
+    // whatever the page wants to send, the upstream decides what it makes of it
+    if let Some(headers) = req.headers.get(ACCESS_CONTROL_REQUEST_HEADERS) {
+        response.insert_header(ACCESS_CONTROL_ALLOW_HEADERS, headers)?;
+    }
+    Ok(Some(response))
+}
+
+fn insert_cors_origin(response: &mut ResponseHeader, origin: &str) -> Result<()> {
+    response.insert_header(ACCESS_CONTROL_ALLOW_ORIGIN, origin)?;
+    // caches have to tell apart responses for other origins
+    if origin != "*" {
+        add_vary(response, "Origin")?;
+    }
+    Ok(())
+}
+
+/// Add `name` to the `Vary` of `response`, unless it's listed already or the response varies
+/// on everything
+fn add_vary(response: &mut ResponseHeader, name: &str) -> Result<()> {
+    let listed = response
+        .headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|field| field.trim() == "*" || field.trim().eq_ignore_ascii_case(name));
+    if !listed {
+        response.append_header(VARY, name)?;
+    }
+    Ok(())
+}
+
+/// Bytes `sniff_encoding` looks at
+const SNIFF_LEN: usize = 4;
+
+/// The algorithm the magic bytes at the start of `body` belong to. Brotli streams have none.
+fn sniff_encoding(body: &[u8]) -> Option<Algorithm> {
+    match body {
+        [0x1f, 0x8b, ..] => Some(Algorithm::Gzip),
+        [0x28, 0xb5, 0x2f, 0xfd, ..] => Some(Algorithm::Zstd),
+        // zlib: method 8 in the low bits of the first byte, both bytes a multiple of 31
+        [cmf, flg, ..] if cmf & 0x0f == 8 && u16::from_be_bytes([*cmf, *flg]) % 31 == 0 => {
+            Some(Algorithm::Deflate)
+        }
+        _ => None,
+    }
 }
 
-impl Decompreessor0 {
-    fn encode(&mut self, input: &[u8], end: bool) -> Result<Bytes> {
-        match self {
-            Decompreessor0::Gzip(compressor) => compressor.encode(input, end),
-            Decompreessor0::Zstd(zstd_compressor) => zstd_compressor.encode(input, end),
+/// Set up the decoder of a request body in the `layers` of codings, or leave it to
+/// `request_body_filter` until a body with a single one can be sniffed
+fn install_decoder(ctx: &mut ProxyCtx, settings: &Settings, layers: &[Algorithm]) -> Result<()> {
+    let limit = settings.config.max_decompressed_size;
+    match layers {
+        [encoded] if settings.config.sniff_encoding => ctx.sniff = Some(*encoded),
+        [encoded] => ctx.decompressor = Some(settings.decompressor(*encoded, limit)?),
+        // the last coding applied is the first to undo
+        _ => {
+            let stages = layers
+                .iter()
+                .rev()
+                .map(|algorithm| settings.decompressor(*algorithm, limit))
+                .collect::<Result<_>>()?;
+            ctx.decompressor = Some(Box::new(Chain::new(stages)));
         }
     }
+    Ok(())
+}
+
+/// What a `Content-Encoding` value says about a body
+#[derive(Debug, PartialEq, Eq)]
+enum BodyEncoding {
+    /// Not encoded at all
+    Identity,
+    /// The codings in the order they were applied
+    Encoded(Vec<Algorithm>),
+    /// Has a coding the proxy has no decoder for
+    Unsupported,
+}
+
+fn parse_content_encoding(value: &str) -> BodyEncoding {
+    let layers: Option<Vec<Algorithm>> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case("identity"))
+        .map(Algorithm::from_content_encoding)
+        .collect();
+    match layers {
+        Some(layers) if layers.is_empty() => BodyEncoding::Identity,
+        Some(layers) => BodyEncoding::Encoded(layers),
+        None => BodyEncoding::Unsupported,
+    }
+}
+
+/// The status the client gets for a request that failed with `e`. Same as pingora's default,
+/// except that an upstream timing out is a 504 rather than a 502.
+fn error_status(e: &Error) -> u16 {
+    match (e.etype(), e.esource()) {
+        (HTTPStatus(code), _) => *code,
+        (ConnectTimedout | ReadTimedout | WriteTimedout, ErrorSource::Upstream) => 504,
+        (_, ErrorSource::Upstream) => 502,
+        // the connection is already gone
+        (WriteError | ReadError | ConnectionClosed, ErrorSource::Downstream) => 0,
+        (_, ErrorSource::Downstream) => 400,
+        (_, ErrorSource::Internal | ErrorSource::Unset) => 500,
+    }
+}
+
+/// Whether `e` is the upstream failing rather than the client or the proxy
+fn is_upstream_failure(e: &Error) -> bool {
+    !matches!(e.etype(), HTTPStatus(_)) && e.esource() == &ErrorSource::Upstream
+}
+
+/// Whether `content_type` is listed in `patterns`, where `type/*` matches every subtype
+fn content_type_matches(patterns: &[String], content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix("/*") {
+            Some(main_type) => mime
+                .split_once('/')
+                .is_some_and(|(t, _)| t.eq_ignore_ascii_case(main_type) || main_type == "*"),
+            None => mime.eq_ignore_ascii_case(pattern),
+        })
 }
 
 pub struct ProxyCtx {
     op: Op,
-    compressor: Option<Compreessor0>,
-    decompressor: Option<Decompreessor0>,
+    compressor: Option<Box<dyn Encode + Send + Sync>>,
+    decompressor: Option<Box<dyn Encode + Send + Sync>>,
+    response_op: Op,
+    response_compressor: Option<Box<dyn Encode + Send + Sync>>,
+    response_decompressor: Option<Box<dyn Encode + Send + Sync>>,
+    /// Retries of the upstream request so far
+    retries: usize,
+    /// Request body bytes of the current upstream attempt so far
+    request_body_bytes: usize,
+    /// The request switches protocols, see `is_upgrade`
+    upgrade: bool,
+    /// Body bytes `sample_request_body` read, until `request_body_filter` sees the body
+    sampled: Option<Bytes>,
+    /// The compressed request body of `--buffer-request-body`
+    buffered: Option<Bytes>,
+    /// The sample didn't shrink enough for `--compression-min-ratio`
+    incompressible: bool,
+    /// `Content-Length` restored from `--length-stash-header`, which the decoded body must match
+    restored_length: Option<usize>,
+    /// Decoded request body bytes sent upstream so far
+    decoded_bytes: usize,
+    /// Compression the matched route asks for
+    codec: Option<Codec>,
+    /// `Host` the matched route sends upstream
+    upstream_host: Option<String>,
+    /// The `Content-Encoding` algorithm while `--sniff-encoding` waits for the first body bytes
+    sniff: Option<Algorithm>,
+    /// Body bytes held back until there are enough to sniff
+    sniffed: BytesMut,
+    /// The `--request-id-header` of the request
+    request_id: Option<String>,
+    /// Names the `--tap-dir` files of a tapped request
+    tap_id: Option<String>,
+    request_tap: Option<Tap>,
+    response_tap: Option<Tap>,
+    /// What a `--cache-size` miss would be cached under
+    cache_key: Option<String>,
+    /// The response being cached so far, with its key
+    cache_fill: Option<(String, ResponseHeader, BytesMut)>,
+    /// When `request_filter` saw the request, for the access log
+    start: Option<Instant>,
+    /// When the request last went upstream
+    upstream_start: Option<Instant>,
+    /// From `upstream_start` to the upstream response header
+    upstream_ttfb: Option<Duration>,
+    /// The upstream the request was last sent to
+    upstream: Option<String>,
+    /// What `--sticky` picks the target by
+    affinity: Option<String>,
+    /// The affinity is new, the response sets the `--sticky-cookie`
+    new_affinity: bool,
+    /// Settings at the time the request came in, a reload doesn't affect it
+    settings: Arc<Settings>,
+    _in_flight: InFlight,
+    /// Held for as long as the request lives, see `--max-concurrent-requests`
+    slot: Option<InFlight>,
+}
+
+impl ProxyCtx {
+    /// Time spent in all codecs of the request and the response so far
+    fn codec_time(&self) -> Duration {
+        [
+            &self.compressor,
+            &self.decompressor,
+            &self.response_compressor,
+            &self.response_decompressor,
+        ]
+        .into_iter()
+        .flatten()
+        .map(|encoder| encoder.stat().3)
+        .sum()
+    }
+
+    /// The codec transforming the request body, if any
+    fn encoder(&self) -> Option<&(dyn Encode + Send + Sync)> {
+        match self.op {
+            Op::None => None,
+            Op::Compress => self.compressor.as_deref(),
+            Op::Decompress => self.decompressor.as_deref(),
+            Op::Transcode => self.compressor.as_deref(),
+        }
+    }
+
+    /// Get ready for an upstream attempt, which gets the request body from its start again
+    fn new_attempt(&mut self) {
+        self.request_body_bytes = 0;
+        if let Some(tap) = &mut self.request_tap {
+            tap.replay();
+        }
+    }
 }
 
 pub enum Op {
     None,
     Compress,
     Decompress,
+    /// Decompress, then compress with another algorithm
+    Transcode,
 }
 
-pub struct Proxy0 {
+/// The part of the proxy a `SIGHUP` reloads
+pub struct Settings {
     config: Config,
-    zstd: bool,
+    /// Contents of `--zstd-dict`
+    zstd_dict: Option<Vec<u8>>,
+    /// `--route` entries by lowercase host
+    routes: HashMap<String, Route>,
+    /// `--path-route` entries, longest prefix first
+    path_routes: Vec<(String, Route)>,
+    /// Contents of `--error-page`
+    error_page: Option<Bytes>,
+    /// `--target` for `--sticky`
+    ring: Ring,
 }
 
-#[async_trait]
-impl ProxyHttp for Proxy0 {
-    type CTX = ProxyCtx;
+impl Settings {
+    fn new(config: Config) -> std::result::Result<Self, String> {
+        config.check_tls()?;
+        let zstd_dict = config
+            .zstd_dict
+            .as_ref()
+            .map(std::fs::read)
+            .transpose()
+            .map_err(|e| format!("--zstd-dict: {e}"))?;
+        let error_page = config
+            .error_page
+            .as_ref()
+            .map(std::fs::read)
+            .transpose()
+            .map_err(|e| format!("--error-page: {e}"))?
+            .map(Bytes::from);
+        let ring = Ring::new(&config.target);
+        let mut path_routes = config.path_route.clone();
+        // stable, so equal prefixes keep the order they were given in
+        path_routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Ok(Self {
+            routes: config.route.iter().cloned().collect(),
+            path_routes,
+            config,
+            zstd_dict,
+            error_page,
+            ring,
+        })
+    }
 
-    fn new_ctx(&self) -> Self::CTX {
-        ProxyCtx {
-            op: Op::None,
-            compressor: None,
-            decompressor: None,
+    fn peer(&self, target: &str) -> HttpPeer {
+        let sni = match &self.config.upstream_sni {
+            Some(sni) => sni.clone(),
+            None => target_host(target).to_string(),
+        };
+        let mut peer = HttpPeer::new(target, self.config.upstream_tls, sni);
+        if let Some(path) = &self.config.egress_proxy {
+            let mut headers = BTreeMap::new();
+            if let Some(credentials) = &self.config.egress_proxy_auth {
+                headers.insert(
+                    "proxy-authorization".to_string(),
+                    auth::basic(credentials).into_bytes(),
+                );
+            }
+            let address = peer.address().as_inet().copied();
+            peer.proxy = Some(EgressProxy {
+                next_hop: path.clone().into_boxed_path(),
+                host: target_host(target).to_string(),
+                port: address.map_or(0, |address| address.port()),
+                headers,
+            });
+        }
+        peer.options.connection_timeout = Some(Duration::from_millis(self.config.connect_timeout));
+        peer.options.read_timeout = Some(Duration::from_millis(self.config.read_timeout));
+        peer.options.write_timeout = Some(Duration::from_millis(self.config.write_timeout));
+        peer.options.idle_timeout = self.config.upstream_idle_timeout.map(Duration::from_millis);
+        if self.config.upstream_http2 {
+            // without TLS there is no ALPN to fall back to HTTP/1.1 with
+            let min = if self.config.upstream_tls { 1 } else { 2 };
+            peer.options.set_http_version(2, min);
         }
+        peer
     }
 
-    async fn upstream_peer(
-        &self,
-        session: &mut pingora::prelude::Session,
-        ctx: &mut Self::CTX,
-    ) -> Result<Box<HttpPeer>> {
-        Ok(Box::new(HttpPeer::new(
-            &self.config.target,
-            false,
-            "one".to_string(),
-        )))
+    /// Whether `--no-compress-path`, `--no-compress-method` or
+    /// `--compression-off-for-range-requests` leave the bodies of `req` and its response alone
+    fn exempt(&self, req: &RequestHeader) -> bool {
+        self.config.no_compress_method.contains(&req.method)
+            || (self.config.compression_off_for_range_requests && req.headers.contains_key(RANGE))
+            || self
+                .config
+                .no_compress_path
+                .iter()
+                .any(|prefix| req.uri.path().starts_with(prefix.as_str()))
     }
 
-    async fn request_filter(&self, session: &mut Session, _ctx: &mut Self::CTX) -> Result<bool>
-    where
-        Self::CTX: Send + Sync,
-    {
-        // println!("Header:{:?}", session.as_downstream().req_header());
-        Ok(false)
+    /// What `target` is called in logs, its `--upstream-name` or else its address
+    fn upstream_name<'a>(&'a self, target: &'a str) -> &'a str {
+        self.config
+            .upstream_name
+            .iter()
+            .find(|(upstream, _)| upstream == target)
+            .map_or(target, |(_, name)| name)
     }
 
-    async fn upstream_request_filter(
+    /// The route of a request to `host` and `path`, host routes before path routes
+    fn routed(&self, host: Option<&str>, path: &str) -> Option<&Route> {
+        host.and_then(|host| self.routes.get(&host.to_ascii_lowercase()))
+            .or_else(|| {
+                self.path_routes
+                    .iter()
+                    .find(|(prefix, _)| path.starts_with(prefix.as_str()))
+                    .map(|(_, route)| route)
+            })
+    }
+
+    /// How request bodies are compressed, for a route that may say otherwise
+    fn request_codec(&self, routed: Option<Codec>) -> Codec {
+        routed.unwrap_or(Codec {
+            algorithm: self.config.algorithm,
+            level: None,
+        })
+    }
+
+    /// Whether `sample` shrinks by `--compression-min-ratio` when compressed with `codec`
+    fn worth_compressing(&self, sample: &[u8], codec: Codec, min_ratio: f64) -> Result<bool> {
+        let compressed = self
+            .compressor(codec.algorithm, codec.level)?
+            .encode(sample, true)?;
+        Ok(sample.len() as f64 >= compressed.len() as f64 * min_ratio)
+    }
+
+    /// The header of the gzip streams the proxy writes, see `--gzip-mtime`
+    fn gzip_header(&self) -> GzipHeader {
+        let config = &self.config;
+        let mut header = GzipHeader {
+            filename: config.gzip_filename.clone().map(String::into_bytes),
+            ..GzipHeader::default()
+        };
+        if config.gzip_mtime {
+            header.mtime = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs() as u32);
+            header.os = Some(GZIP_OS_UNIX);
+        }
+        header
+    }
+
+    /// The level `req` asks `algorithm` to be compressed at, see `--allow-header-overrides`
+    fn level_override(&self, req: &RequestHeader, algorithm: Algorithm) -> Option<i32> {
+        if !self.config.allow_header_overrides {
+            return None;
+        }
+        let value = req.headers.get(COMPRESS_LEVEL_HEADER)?;
+        let levels = algorithm.levels();
+        let level = value
+            .to_str()
+            .ok()
+            .and_then(|level| level.trim().parse::<i32>().ok())
+            .filter(|level| levels.contains(level));
+        if level.is_none() {
+            warn!(
+                "ignoring {COMPRESS_LEVEL_HEADER} {value:?}, {algorithm:?} levels are {} to {}",
+                levels.start(),
+                levels.end()
+            );
+        }
+        level
+    }
+
+    /// A compressor for `algorithm`, at `level` or else the configured one
+    fn compressor(
         &self,
-        session: &mut Session,
-        upstream_request: &mut RequestHeader,
-        ctx: &mut Self::CTX,
-    ) -> Result<()>
-    where
-        Self::CTX: Send + Sync,
-    {
-        if let None = upstream_request.headers.get(CONTENT_ENCODING) {
-            ctx.op = Op::Compress;
+        algorithm: Algorithm,
+        level: Option<i32>,
+    ) -> Result<Box<dyn Encode + Send + Sync>> {
+        // levels of a route are checked when parsed
+        let unsigned = |default| level.map_or(default, |level| level as u32);
+        let flush_interval = self
+            .config
+            .flush_interval
+            .map_or(usize::MAX, |bytes| bytes as usize);
+        let configured = match algorithm {
+            Algorithm::Gzip | Algorithm::Deflate => self.config.gzip_level as i32,
+            Algorithm::Zstd => self.config.zstd_level,
+            Algorithm::Brotli | Algorithm::Identity | Algorithm::Registered(_) => {
+                algorithm.default_level()
+            }
+        };
+        let name = algorithm.content_encoding();
+        if let Some(registered) = compress::registered_codec(name, level.unwrap_or(configured)) {
+            return Ok(registered);
+        }
+        Ok(match algorithm {
+            Algorithm::Gzip => {
+                let compressor =
+                    Compressor::with_header(unsigned(self.config.gzip_level), &self.gzip_header())
+                        .with_flush_interval(flush_interval);
+                if self.config.buffer_pool {
+                    Box::new(compressor.with_buffer_pool())
+                } else {
+                    Box::new(compressor)
+                }
+            }
+            Algorithm::Zstd => {
+                let level = level.unwrap_or(self.config.zstd_level);
+                let mut compressor = match &self.zstd_dict {
+                    Some(dict) => ZstdCompressor::with_dictionary(level, dict)?,
+                    None => ZstdCompressor::new(level)?,
+                };
+                if let Some(log) = self.config.zstd_window_log {
+                    compressor = compressor.with_window_log(log)?;
+                }
+                if self.config.zstd_long_mode {
+                    compressor = compressor.with_long_distance_matching()?;
+                }
+                Box::new(compressor.with_flush_interval(flush_interval))
+            }
+            Algorithm::Brotli => {
+                Box::new(BrotliCompressor::new(unsigned(5)).with_flush_interval(flush_interval))
+            }
+            Algorithm::Deflate => Box::new(
+                DeflateCompressor::new(unsigned(self.config.gzip_level))
+                    .with_flush_interval(flush_interval),
+            ),
+            Algorithm::Identity => algorithm.compressor(None)?,
+            Algorithm::Registered(_) => algorithm.compressor(level)?,
+        })
+    }
 
-            if let Some(cl) = upstream_request.remove_header(&CONTENT_LENGTH) {
-                upstream_request.insert_header("crd-content-length", cl);
+    /// A decompressor for `algorithm` producing at most `limit` bytes
+    fn decompressor(
+        &self,
+        algorithm: Algorithm,
+        limit: usize,
+    ) -> Result<Box<dyn Encode + Send + Sync>> {
+        Ok(match algorithm {
+            Algorithm::Gzip if self.config.buffer_pool => {
+                Box::new(Decompressor::with_limit(limit).with_buffer_pool())
             }
-            if self.zstd {
-                upstream_request.insert_header(CONTENT_ENCODING, "zstd");
-                ctx.compressor = Some(Compreessor0::Zstd(ZstdCompressor::new(6)));
-            } else {
-                upstream_request.insert_header(CONTENT_ENCODING, "gzip");
-                ctx.compressor = Some(Compreessor0::Gzip(Compressor::new(6)));
+            Algorithm::Gzip => Box::new(Decompressor::with_limit(limit)),
+            Algorithm::Zstd => {
+                let decompressor = match &self.zstd_dict {
+                    Some(dict) => ZstdDecompressor::with_dictionary(dict, limit)?,
+                    None => ZstdDecompressor::with_limit(limit)?,
+                };
+                match self.config.zstd_window_log {
+                    Some(log) if log > ZSTD_WINDOW_LOG_MAX => {
+                        Box::new(decompressor.with_window_log_max(log)?)
+                    }
+                    _ => Box::new(decompressor),
+                }
             }
+            Algorithm::Brotli
+            | Algorithm::Deflate
+            | Algorithm::Identity
+            | Algorithm::Registered(_) => algorithm.decompressor(limit)?,
+        })
+    }
+}
 
-            upstream_request.insert_header(TRANSFER_ENCODING, "Chunked");
-        } else {
-            ctx.op = Op::Decompress;
-            if self.zstd {
-                ctx.decompressor = Some(Decompreessor0::Zstd(ZstdDecompressor::new()));
-                upstream_request.insert_header(ACCEPT_ENCODING, "zstd");
-            } else {
-                ctx.decompressor = Some(Decompreessor0::Gzip(Decompressor::new()));
-                upstream_request.insert_header(ACCEPT_ENCODING, "gzip");
+/// The current `Settings`, shared between the proxy and the `Reloader`
+#[derive(Clone)]
+pub struct SharedSettings {
+    current: Arc<RwLock<Arc<Settings>>>,
+    /// The process arguments, parsed again together with the config file on reload
+    args: Arc<Vec<OsString>>,
+}
+
+impl SharedSettings {
+    fn get(&self) -> Arc<Settings> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Re-read `--config-file` and swap in the result, unless it has an upstream that doesn't
+    /// resolve, as `--check-config` checks. Listener ports can't change this way.
+    fn reload(&self) -> std::result::Result<(), String> {
+        let config = Config::load_from(self.args.iter()).map_err(|e| e.to_string())?;
+        resolve_upstreams(&config)?;
+        let settings = Settings::new(config)?;
+        *self.current.write().unwrap() = Arc::new(settings);
+        Ok(())
+    }
+}
+
+/// Reloads the settings on `SIGHUP`
+pub struct Reloader(SharedSettings);
+
+#[async_trait]
+impl BackgroundService for Reloader {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                log::error!("can't listen for SIGHUP, config reload disabled: {e}");
+                return;
             }
+        };
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => match self.0.reload() {
+                    Ok(()) => info!("reloaded config"),
+                    // keep running with the old settings
+                    Err(e) => log::error!("failed to reload config: {e}"),
+                },
+                _ = shutdown.changed() => return,
+            }
+        }
+    }
+}
+
+/// Counts a request as in flight for as long as it lives
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count.clone())
+    }
+
+    /// Like `new`, `None` when `count` already is at `max`
+    fn within(count: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()?;
+        Some(Self(count.clone()))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Exits as soon as the last in-flight request is done after a graceful shutdown began, rather
+/// than at the end of `--shutdown-timeout`
+pub struct Drainer {
+    in_flight: Arc<AtomicUsize>,
+    uds: Option<PathBuf>,
+}
+
+#[async_trait]
+impl BackgroundService for Drainer {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        // only a graceful shutdown is announced, a fast one doesn't wait for anything
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+        info!("shutting down, waiting for in-flight requests");
+        while self.in_flight.load(Ordering::Relaxed) > 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        exit(self.uds.as_deref());
+    }
+}
+
+/// Polls `--health-check-path` on every target
+pub struct HealthChecker {
+    settings: SharedSettings,
+    health: Arc<Health>,
+}
 
-            if let Some(cl) = upstream_request.headers.get("crd-content-length") {
-                upstream_request.insert_header(CONTENT_LENGTH, cl.clone());
-                upstream_request.remove_header(&TRANSFER_ENCODING);
+#[async_trait]
+impl BackgroundService for HealthChecker {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            // a reload may turn the checks on or off
+            let settings = self.settings.get();
+            let config = &settings.config;
+            let interval = Duration::from_millis(config.health_check_interval);
+            if let Some(path) = &config.health_check_path {
+                let mut checks = tokio::task::JoinSet::new();
+                for (target, _) in &config.target {
+                    let target = target.clone();
+                    // a plain HTTP request can't tell whether a TLS upstream is up
+                    let path = (!config.upstream_tls).then(|| path.clone());
+                    checks.spawn(async move {
+                        let host = target_host(&target).to_string();
+                        let passed = probe(&target, &host, path.as_deref(), interval).await;
+                        (target, passed)
+                    });
+                }
+                while let Some(Ok((target, passed))) = checks.join_next().await {
+                    self.health
+                        .record(&target, passed, config.unhealthy_threshold);
+                }
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = shutdown.changed() => return,
             }
         }
+    }
+}
 
-        session.upstream_compression.adjust_decompression(true);
-        Ok(())
+pub struct Proxy0 {
+    settings: SharedSettings,
+    /// Current weights of smooth weighted round-robin, one per `config.target`
+    next_target: Mutex<Vec<i64>>,
+    /// Shared with the `--metrics-port` service
+    metrics: Arc<Metrics>,
+    /// Kept up to date by the `HealthChecker`
+    health: Arc<Health>,
+    /// Set by `/drain` on the `--metrics-port`
+    draining: Arc<AtomicBool>,
+    /// Buckets of `--rate-limit`
+    rate_limiter: RateLimiter,
+    /// Requests with a live `ProxyCtx`, waited for on shutdown
+    in_flight: Arc<AtomicUsize>,
+    /// Requests holding a `--max-concurrent-requests` slot
+    concurrent: Arc<AtomicUsize>,
+    /// Requests per client connection, see `--downstream-max-requests`
+    connection_requests: RequestCounts<SocketDigest>,
+    /// Bytes written to `--tap-dir` so far
+    tapped: Arc<AtomicUsize>,
+    /// Responses of `--cache-size`
+    cache: Cache,
+}
+
+impl Proxy0 {
+    /// `args` are what `config` was parsed from, to parse again on reload
+    fn new(config: Config, args: Vec<OsString>) -> std::result::Result<Self, String> {
+        Ok(Self {
+            settings: SharedSettings {
+                current: Arc::new(RwLock::new(Arc::new(Settings::new(config)?))),
+                args: Arc::new(args),
+            },
+            next_target: Mutex::default(),
+            metrics: Arc::default(),
+            health: Arc::default(),
+            draining: Arc::default(),
+            rate_limiter: RateLimiter::default(),
+            in_flight: Arc::default(),
+            concurrent: Arc::default(),
+            connection_requests: RequestCounts::default(),
+            tapped: Arc::default(),
+            cache: Cache::default(),
+        })
     }
 
-    async fn request_body_filter(
-        &self,
-        session: &mut Session,
-        body: &mut Option<Bytes>,
-        end: bool,
-        ctx: &mut Self::CTX,
-    ) -> Result<()>
-    where
-        Self::CTX: Send + Sync,
-    {
-        if let Some(compresser) = ctx.compressor.as_mut() {
-            let data = if let Some(b) = body.as_ref() {
-                b.as_ref()
-            } else {
-                &[]
-            };
-            *body = Some(compresser.encode(data, end)?);
+    /// Start copying a body of the tapped request of `ctx` to `--tap-dir`, `side` telling
+    /// request and response apart
+    fn tap(&self, ctx: &ProxyCtx, side: &str) -> Option<Tap> {
+        let config = &ctx.settings.config;
+        let (dir, id) = (config.tap_dir.as_ref()?, ctx.tap_id.as_ref()?);
+        Some(Tap::create(
+            dir,
+            &format!("{id}.{side}"),
+            self.tapped.clone(),
+            config.tap_max_bytes,
+        ))
+    }
+
+    /// Answer a GET from `--cache-size` when its response is cached in an encoding the client
+    /// accepts. Returns whether it was, otherwise the response is cached once it's complete.
+    async fn serve_cached(&self, session: &mut Session, ctx: &mut ProxyCtx) -> Result<bool> {
+        let req = session.req_header();
+        if ctx.settings.config.cache_size.is_none()
+            || req.method != http::Method::GET
+            || cache::no_store(&req.headers)
+        {
+            return Ok(false);
+        }
+        let path = req.uri.path_and_query().map_or("/", |path| path.as_str());
+        let key = cache::key(&req.method, request_host(req), path);
+        let Some(cached) = self.cache.get(&key) else {
+            ctx.cache_key = Some(key);
+            return Ok(false);
+        };
+        let content_encoding = cached.header.headers.get(CONTENT_ENCODING);
+        let acceptable = match content_encoding.and_then(|ce| ce.to_str().ok()) {
+            None => true,
+            Some(ce) if ce.eq_ignore_ascii_case("identity") => true,
+            Some(ce) => Algorithm::from_content_encoding(ce).is_some_and(|algorithm| {
+                accepted_codings(req)
+                    .is_some_and(|codings| accept_encoding::accepts(&codings, algorithm))
+            }),
+        };
+        if !acceptable {
+            // fetched again, and cached in what this client accepts
+            ctx.cache_key = Some(key);
+            return Ok(false);
+        }
+        let mut response = cached.header.clone();
+        response.remove_header(&TRANSFER_ENCODING);
+        response.insert_header(CONTENT_LENGTH, cached.body.len())?;
+        if let Some(id) = &ctx.request_id {
+            response.insert_header(ctx.settings.config.request_id_header.clone(), id)?;
         }
+        session
+            .write_response_header(Box::new(response), false)
+            .await?;
+        session
+            .write_response_body(Some(cached.body.clone()), true)
+            .await?;
+        Ok(true)
+    }
 
-        if let Some(decompressor) = ctx.decompressor.as_mut() {
-            let data = if let Some(b) = body.as_ref() {
-                b.as_ref()
-            } else {
-                &[]
-            };
-            *body = Some(decompressor.encode(data, end)?);
+    /// The upstream for the next request, cycling through the healthy targets so that each
+    /// gets its share by weight, spread out rather than in runs. `None` when none of them is
+    /// healthy.
+    fn next_target<'a>(&self, settings: &'a Settings) -> Option<&'a str> {
+        let targets = &settings.config.target;
+        let slow_start = settings.config.slow_start_seconds.map(Duration::from_secs);
+        let mut current = self.next_target.lock().unwrap();
+        // a reload may have changed the targets
+        current.resize(targets.len(), 0);
+        let mut total = 0;
+        let mut next: Option<usize> = None;
+        for (i, (target, weight)) in targets.iter().enumerate() {
+            if !self.health.is_healthy(target) {
+                continue;
+            }
+            // in steps, so that a recovering target can get a fraction of its weight
+            let mut weight = i64::from(*weight) * WEIGHT_STEPS;
+            if let Some(window) = slow_start {
+                let ramp = self.health.ramp(target, window);
+                weight = ((weight as f64 * ramp) as i64).max(1);
+            }
+            current[i] += weight;
+            total += weight;
+            if next.is_none_or(|next| current[i] > current[next]) {
+                next = Some(i);
+            }
         }
-        Ok(())
+        let next = next?;
+        current[next] -= total;
+        Some(&targets[next].0)
     }
 
-    fn upstream_response_filter(
-        &self,
-        session: &mut Session,
-        upstream_response: &mut ResponseHeader,
-        _ctx: &mut Self::CTX,
-    ) -> Result<()> {
-        Ok(())
+    /// The healthy target for the `--sticky` `affinity` of a client
+    fn sticky_target<'a>(&self, settings: &'a Settings, affinity: &str) -> Option<&'a str> {
+        let targets = &settings.config.target;
+        let i = settings
+            .ring
+            .get(affinity, |i| self.health.is_healthy(&targets[i].0))?;
+        Some(&targets[i].0)
     }
 
-    fn upstream_response_body_filter(
+    /// The upstream for a request to `host` and `path`, for a client with `affinity` under
+    /// `--sticky`. Fails with a 404 when nothing matches and with a 503 when no target is
+    /// healthy. Host routes are consulted before path routes.
+    fn route<'a>(
         &self,
-        session: &mut Session,
-        body: &mut Option<Bytes>,
-        end_of_stream: bool,
-        _ctx: &mut Self::CTX,
-    ) -> Result<()> {
-        Ok(())
+        settings: &'a Settings,
+        host: Option<&str>,
+        path: &str,
+        affinity: Option<&str>,
+    ) -> Result<&'a str> {
+        match (settings.routed(host, path), settings.config.route_default) {
+            (Some(route), _) => Ok(&route.upstream),
+            (None, RouteDefault::Target) => match affinity {
+                Some(affinity) => self.sticky_target(settings, affinity),
+                None => self.next_target(settings),
+            }
+            .or_err(HTTPStatus(503), "no healthy target"),
+            (None, RouteDefault::NotFound) => {
+                Error::e_explain(HTTPStatus(404), "no route for host")
+            }
+        }
     }
 
-    fn response_body_filter(
-        &self,
-        session: &mut Session,
-        body: &mut Option<Bytes>,
-        end_of_stream: bool,
-        ctx: &mut Self::CTX,
-    ) -> Result<Option<std::time::Duration>>
-    where
-        Self::CTX: Send + Sync,
-    {
+    /// Whether a failed upstream request may be tried again, counting the retry when it may
+    fn retry(&self, method: &http::Method, ctx: &mut ProxyCtx) -> bool {
+        use http::Method;
+        let idempotent = matches!(
+            *method,
+            Method::GET | Method::HEAD | Method::PUT | Method::DELETE
+        );
+        let config = &ctx.settings.config;
+        if ctx.retries >= config.max_retries || !(idempotent || config.retry_non_idempotent) {
+            return false;
+        }
+        ctx.retries += 1;
+        true
+    }
+
+    /// Whether the client of `session` is within `--rate-limit`. Clients without an IP address,
+    /// on a Unix socket, aren't limited.
+    fn allow(&self, session: &Session, ctx: &ProxyCtx) -> bool {
+        let config = &ctx.settings.config;
+        let Some(rate) = config.rate_limit else {
+            return true;
+        };
+        match session.client_addr().and_then(|addr| addr.as_inet()) {
+            Some(addr) => {
+                self.rate_limiter
+                    .allow(addr.ip(), Instant::now(), rate, config.rate_burst)
+            }
+            None => true,
+        }
+    }
+
+    /// What `--sticky` routes the request by. Under `--sticky cookie` a client without the
+    /// cookie gets a new affinity, for the response to set.
+    fn affinity(&self, session: &Session, ctx: &mut ProxyCtx) -> Option<String> {
+        let config = &ctx.settings.config;
+        match config.sticky? {
+            Sticky::Ip => session
+                .client_addr()
+                .and_then(|addr| addr.as_inet())
+                .map(|addr| addr.ip().to_string()),
+            Sticky::Cookie => {
+                let sent = sticky::cookie(&session.req_header().headers, &config.sticky_cookie)
+                    .filter(|value| !value.is_empty());
+                ctx.new_affinity = sent.is_none();
+                Some(sent.map_or_else(request_id::generate, str::to_string))
+            }
+        }
+    }
+
+    /// Apply `--downstream-keepalive-timeout` and `--downstream-max-requests` to the connection
+    /// of the request, unless it isn't kept alive anyway
+    fn limit_keepalive(&self, session: &mut Session, ctx: &ProxyCtx) {
+        let config = &ctx.settings.config;
+        if session.get_keepalive().is_none() {
+            return;
+        }
+        if let Some(timeout) = config.downstream_keepalive_timeout {
+            session.set_keepalive(Some(timeout));
+        }
+        let socket = session
+            .digest()
+            .and_then(|digest| digest.socket_digest.clone());
+        if let (Some(max), Some(socket)) = (config.downstream_max_requests, socket)
+            && self.connection_requests.count(&socket) >= max
+        {
+            session.set_keepalive(None);
+        }
+    }
+
+    /// Decode and encode a chunk of the request body as `request_transform` decided. A missing
+    /// chunk that doesn't end the body stays missing, rather than turning into an empty one the
+    /// encoders would flush for.
+    fn transform_request_body(
+        &self,
+        ctx: &mut ProxyCtx,
+        body: &mut Option<Bytes>,
+        end: bool,
+    ) -> Result<()> {
+        if body.is_none() && !end {
+            return Ok(());
+        }
+        // failing here aborts the upstream request: the final chunk is never sent, so the
+        // upstream can't mistake what it got so far for the whole body
+        self.count_request_body(ctx, body.as_ref().map_or(0, Bytes::len))?;
+
+        if let Some(named) = ctx.sniff {
+            ctx.sniffed
+                .extend_from_slice(body.as_deref().unwrap_or_default());
+            if ctx.sniffed.len() < SNIFF_LEN && !end {
+                // nothing goes upstream yet
+                *body = Some(Bytes::new());
+                return Ok(());
+            }
+            let algorithm = sniff_encoding(&ctx.sniffed).unwrap_or(named);
+            if algorithm != named {
+                debug!("request body claims to be {named:?} but is {algorithm:?}");
+            }
+            let limit = ctx.settings.config.max_decompressed_size;
+            ctx.decompressor = Some(ctx.settings.decompressor(algorithm, limit)?);
+            ctx.sniff = None;
+            *body = Some(std::mem::take(&mut ctx.sniffed).freeze());
+        }
+
+        let data = body.as_deref().unwrap_or_default();
+        let decoded = match (&ctx.op, ctx.decompressor.as_mut()) {
+            (Op::Decompress | Op::Transcode, Some(decompressor)) => {
+                let decoded = decompressor.encode(data, end)?;
+                if end {
+                    self.body_done(decompressor.as_ref());
+                }
+                Some(decoded)
+            }
+            _ => None,
+        };
+        if let Some(decoded) = &decoded {
+            self.count_decoded_body(ctx, decoded.len(), end)?;
+        }
+        let data = decoded.as_deref().unwrap_or(data);
+        if let Some(tap) = &mut ctx.request_tap {
+            tap.write(data);
+        }
+        let encoded = match (&ctx.op, ctx.compressor.as_mut()) {
+            (Op::Compress | Op::Transcode, Some(compressor)) => {
+                let encoded = compressor.encode(data, end)?;
+                if end {
+                    self.body_done(compressor.as_ref());
+                }
+                Some(encoded)
+            }
+            _ => None,
+        };
+        if let Some(transformed) = encoded.or(decoded) {
+            *body = Some(transformed);
+        }
+        Ok(())
+    }
+
+    /// Count `len` more request body bytes, failing with a 413 once `--max-request-body` is
+    /// exceeded
+    fn count_request_body(&self, ctx: &mut ProxyCtx, len: usize) -> Result<()> {
+        ctx.request_body_bytes += len;
+        match ctx.settings.config.max_request_body {
+            Some(max) if ctx.request_body_bytes > max => {
+                Error::e_explain(HTTPStatus(413), "request body exceeds --max-request-body")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Read the start of a body that is about to be compressed and find out whether compressing
+    /// it is worth it, for `--compression-min-ratio`. This has to happen before the request
+    /// headers go upstream, they say whether the body is compressed.
+    async fn sample_request_body(&self, session: &mut Session, ctx: &mut ProxyCtx) -> Result<()> {
+        let settings = ctx.settings.clone();
+        let config = &settings.config;
+        let Some(min_ratio) = config.compression_min_ratio else {
+            return Ok(());
+        };
+        let req = session.req_header();
+        if config.no_transform
+            || settings.exempt(req)
+            || is_upgrade(req)
+            || req.headers.contains_key(CONTENT_ENCODING)
+            || !req
+                .headers
+                .get(CONTENT_TYPE)
+                .and_then(|ct| ct.to_str().ok())
+                .is_some_and(|ct| content_type_matches(&config.compress_content_types, ct))
+        {
+            return Ok(());
+        }
+        let sample_size = config.compression_sample_size as usize;
+        match content_length(req) {
+            Some(cl) if cl >= config.min_compress_size && can_sample(cl, sample_size) => {}
+            _ => return Ok(()),
+        }
+
+        // what is read here is kept for pingora to send on
+        session.as_mut().enable_retry_buffering();
+        let mut sample = BytesMut::new();
+        while sample.len() < sample_size
+            && let Some(chunk) = session.read_request_body().await?
+        {
+            sample.extend_from_slice(&chunk);
+        }
+        let sample = sample.freeze();
+        let codec = settings.request_codec(ctx.codec);
+        ctx.incompressible = !settings.worth_compressing(&sample, codec, min_ratio)?;
+        ctx.sampled = Some(sample);
+        Ok(())
+    }
+
+    /// Read and compress the whole request body before the headers of `req` go upstream, for
+    /// `--buffer-request-body`, and then say how long it is instead of sending it chunked. A
+    /// body too long to buffer fails with a 413.
+    async fn buffer_request_body(
+        &self,
+        session: &mut Session,
+        req: &mut RequestHeader,
+        ctx: &mut ProxyCtx,
+    ) -> Result<()> {
+        if !ctx.settings.config.buffer_request_body || !matches!(ctx.op, Op::Compress) {
+            return Ok(());
+        }
+        // a retry, the body is already at hand
+        if let Some(buffered) = &ctx.buffered {
+            req.remove_header(&TRANSFER_ENCODING);
+            req.insert_header(CONTENT_LENGTH, buffered.len())?;
+            return Ok(());
+        }
+        // a body read ahead only goes upstream if pingora can replay it from its retry buffer,
+        // sending one that outgrew the buffer chunked is what the upstream can't take
+        if content_length(session.req_header()).is_some_and(|cl| cl > RETRY_BUFFER_SIZE) {
+            return self.body_too_long(session).await;
+        }
+
+        session.as_mut().enable_retry_buffering();
+        let mut raw = BytesMut::new();
+        if let Some(sampled) = ctx.sampled.take() {
+            self.count_request_body(ctx, sampled.len())?;
+            raw.extend_from_slice(&sampled);
+        }
+        loop {
+            if session.as_ref().retry_buffer_truncated() {
+                return self.body_too_long(session).await;
+            }
+            let Some(chunk) = session.read_request_body().await? else {
+                break;
+            };
+            self.count_request_body(ctx, chunk.len())?;
+            raw.extend_from_slice(&chunk);
+        }
+
+        if let Some(tap) = &mut ctx.request_tap {
+            tap.write(&raw);
+        }
+        let compressor = ctx.compressor.as_mut().or_err(
+            InternalError,
+            "compressing a request body without a compressor",
+        )?;
+        let compressed = compressor.encode(&raw, true)?;
+        self.body_done(compressor.as_ref());
+        req.remove_header(&TRANSFER_ENCODING);
+        req.insert_header(CONTENT_LENGTH, compressed.len())?;
+        ctx.buffered = Some(compressed);
+        Ok(())
+    }
+
+    /// Fail with a 413 for a body too long for `--buffer-request-body`. What is left of it is
+    /// read and dropped first, for a while: closing with bytes unread resets the connection,
+    /// and the client may never see the answer.
+    async fn body_too_long(&self, session: &mut Session) -> Result<()> {
+        let downstream = session.as_mut();
+        downstream.set_total_drain_timeout(Some(DRAIN_TIMEOUT));
+        if let Err(e) = downstream.drain_request_body().await {
+            debug!("gave up on draining the request body: {e}");
+        }
+        Error::e_explain(
+            HTTPStatus(413),
+            format!(
+                "request body exceeds the {RETRY_BUFFER_SIZE} bytes --buffer-request-body keeps"
+            ),
+        )
+    }
+
+    /// Count `len` more decoded request body bytes against the restored `Content-Length`. A
+    /// body that turns out longer or shorter fails the request, the upstream would read past
+    /// its end or wait for bytes that never come otherwise.
+    fn count_decoded_body(&self, ctx: &mut ProxyCtx, len: usize, end: bool) -> Result<()> {
+        let Some(expected) = ctx.restored_length else {
+            return Ok(());
+        };
+        ctx.decoded_bytes += len;
+        if ctx.decoded_bytes > expected || (end && ctx.decoded_bytes != expected) {
+            return Error::e_explain(
+                HTTPStatus(400),
+                format!(
+                    "request body decodes to {} bytes, its stashed length is {expected}",
+                    ctx.decoded_bytes
+                ),
+            );
+        }
+        Ok(())
+    }
+
+    /// Account for a body `encoder` is done with
+    fn body_done(&self, encoder: &dyn Encode) {
+        log_stat(encoder);
+        for (name, total_in, total_out, duration) in encoder.stats() {
+            self.metrics.body(name, total_in, total_out, duration);
+        }
+    }
+
+    /// Decide how the request body is transformed on its way to the upstream. Requests without a
+    /// `Content-Encoding` are compressed, encoded requests are decompressed.
+    fn request_transform(&self, req: &mut RequestHeader, ctx: &mut ProxyCtx) -> Result<()> {
+        // a retry replays the body from its start, nothing from the last attempt carries over
+        ctx.decoded_bytes = 0;
+        ctx.sniffed.clear();
+        let settings = ctx.settings.clone();
+        let config = &settings.config;
+        let stash = config.length_stash_header.as_str();
+        let codec = settings.request_codec(ctx.codec);
+        if config.no_transform || settings.exempt(req) {
+            ctx.op = Op::None;
+            return Ok(());
+        }
+        if is_upgrade(req) {
+            ctx.op = Op::None;
+            ctx.upgrade = true;
+            return Ok(());
+        }
+        if req.headers.get(CONTENT_ENCODING).is_none() {
+            // only the peer proxy sets it, and only on bodies it encoded
+            req.remove_header(stash);
+            // an empty stream compressed is still a stream, with headers some upstreams choke
+            // on for a GET
+            if !has_body(req) || codec.algorithm == Algorithm::Identity {
+                ctx.op = Op::None;
+                return Ok(());
+            }
+            // a chunked body has no known length up front, so it is always compressed even when
+            // it turns out to be tiny
+            if content_length(req).is_some_and(|cl| cl < config.min_compress_size) {
+                ctx.op = Op::None;
+                return Ok(());
+            }
+            let content_type = req
+                .headers
+                .get(CONTENT_TYPE)
+                .and_then(|ct| ct.to_str().ok());
+            if !content_type
+                .is_some_and(|ct| content_type_matches(&config.compress_content_types, ct))
+            {
+                ctx.op = Op::None;
+                return Ok(());
+            }
+            if ctx.incompressible {
+                debug!("forwarding request uncompressed, its sample didn't compress well");
+                ctx.op = Op::None;
+                return Ok(());
+            }
+
+            // without an Accept-Encoding anything goes, otherwise the peer has to accept the
+            // algorithm or the body is passed along as identity
+            let algorithm = match accepted_codings(req) {
+                None => codec.algorithm,
+                Some(codings) => match accept_encoding::negotiate(&codings, codec.algorithm) {
+                    Some(algorithm) => algorithm,
+                    None => {
+                        ctx.op = Op::None;
+                        return Ok(());
+                    }
+                },
+            };
+
+            ctx.op = Op::Compress;
+
+            if let Some(cl) = req.remove_header(&CONTENT_LENGTH) {
+                req.insert_header(stash.to_string(), cl)?;
+            }
+            set_content_encoding(req, algorithm)?;
+            // the level of the route is for its own algorithm
+            let level = settings
+                .level_override(req, algorithm)
+                .or(codec.level.filter(|_| algorithm == codec.algorithm));
+            ctx.compressor = Some(settings.compressor(algorithm, level)?);
+
+            // whatever framing the client used, the compressed body is chunked
+            set_chunked(req)?;
+        } else {
+            // codings in further headers are layers on top of those in the first
+            let ce = req
+                .headers
+                .get_all(CONTENT_ENCODING)
+                .iter()
+                .map(|ce| ce.to_str().unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let layers = match parse_content_encoding(&ce) {
+                BodyEncoding::Encoded(layers) => layers,
+                // nothing to decode, and the client chose to leave the body as it is
+                BodyEncoding::Identity => {
+                    ctx.op = Op::None;
+                    return Ok(());
+                }
+                BodyEncoding::Unsupported => {
+                    warn!("passing through request with unsupported Content-Encoding {ce:?}");
+                    ctx.op = Op::None;
+                    return Ok(());
+                }
+            };
+            match config.on_preencoded {
+                OnPreencoded::Passthrough => {
+                    ctx.op = Op::None;
+                    return Ok(());
+                }
+                OnPreencoded::Transcode if layers == [codec.algorithm] => {
+                    ctx.op = Op::None;
+                    return Ok(());
+                }
+                // into identity is plain decoding
+                OnPreencoded::Transcode if codec.algorithm != Algorithm::Identity => {
+                    ctx.op = Op::Transcode;
+                    install_decoder(ctx, &settings, &layers)?;
+                    ctx.compressor = Some(settings.compressor(codec.algorithm, codec.level)?);
+                    set_content_encoding(req, codec.algorithm)?;
+                    req.remove_header(&CONTENT_LENGTH);
+                    set_chunked(req)?;
+                    return Ok(());
+                }
+                OnPreencoded::Transcode | OnPreencoded::Decompress => {}
+            }
+
+            ctx.op = Op::Decompress;
+            install_decoder(ctx, &settings, &layers)?;
+            // ask the upstream to answer in the algorithm the peer proxy can decode
+            req.insert_header(ACCEPT_ENCODING, config.algorithm.content_encoding())?;
+
+            // the body that goes upstream is the decoded one, of a different length
+            req.remove_header(&CONTENT_ENCODING);
+            let stashed = req
+                .remove_header(stash)
+                .and_then(|cl| cl.to_str().ok()?.parse::<usize>().ok());
+            match stashed {
+                Some(cl) => {
+                    req.remove_header(&CONTENT_LENGTH);
+                    req.insert_header(CONTENT_LENGTH, cl)?;
+                    req.remove_header(&TRANSFER_ENCODING);
+                    ctx.restored_length = Some(cl);
+                }
+                None => {
+                    req.remove_header(&CONTENT_LENGTH);
+                    set_chunked(req)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell the upstream who the client is, whether it used TLS and the id of the request.
+    /// Clients on a Unix socket have no address to add and are trusted, they're on the same
+    /// machine.
+    fn forward_headers(
+        &self,
+        session: &Session,
+        req: &mut RequestHeader,
+        ctx: &ProxyCtx,
+    ) -> Result<()> {
+        if let Some(id) = &ctx.request_id {
+            req.insert_header(ctx.settings.config.request_id_header.clone(), id)?;
+        }
+        let trusted = &ctx.settings.config.trusted_proxies;
+        let client = session.client_addr().and_then(|addr| addr.as_inet());
+        let peer_trusted = client.is_none_or(|addr| trusted.iter().any(|c| c.contains(addr.ip())));
+        if let Some(addr) = client {
+            let chain = req
+                .headers
+                .get_all(X_FORWARDED_FOR)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let chain = forwarded_for(Some(&chain), addr.ip(), trusted);
+            req.insert_header(X_FORWARDED_FOR, chain)?;
+        }
+        if !peer_trusted || req.headers.get(X_FORWARDED_PROTO).is_none() {
+            let tls = session
+                .digest()
+                .is_some_and(|digest| digest.ssl_digest.is_some());
+            req.insert_header(X_FORWARDED_PROTO, if tls { "https" } else { "http" })?;
+        }
+        Ok(())
+    }
+
+    /// Apply `--upstream-host-header` or the host of the route, `--remove-request-header` and
+    /// `--add-request-header`, and keep the credentials of `--basic-auth` to the proxy
+    fn edit_request_headers(&self, req: &mut RequestHeader, ctx: &ProxyCtx) -> Result<()> {
+        let config = &ctx.settings.config;
+        if let Some(host) = ctx
+            .upstream_host
+            .as_ref()
+            .or(config.upstream_host_header.as_ref())
+        {
+            req.insert_header(HOST, host)?;
+        }
+        if !config.basic_auth.is_empty() {
+            req.remove_header(&AUTHORIZATION);
+        }
+        if config.allow_header_overrides {
+            req.remove_header(COMPRESS_LEVEL_HEADER);
+        }
+        for name in &config.remove_request_header {
+            req.remove_header(name);
+        }
+        for (name, value) in &config.add_request_header {
+            req.insert_header(name.clone(), value)?;
+        }
+        Ok(())
+    }
+
+    /// Apply `--remove-response-header`, `--add-response-header` and `--cors-allow-origin`
+    fn edit_response_headers(&self, response: &mut ResponseHeader, ctx: &ProxyCtx) -> Result<()> {
+        let config = &ctx.settings.config;
+        for name in &config.remove_response_header {
+            response.remove_header(name);
+        }
+        for (name, value) in &config.add_response_header {
+            response.insert_header(name.clone(), value)?;
+        }
+        if let Some(origin) = &config.cors_allow_origin {
+            insert_cors_origin(response, origin)?;
+        }
+        Ok(())
+    }
+
+    /// Decide how the response body is transformed on its way to the client. Responses without
+    /// a `Content-Encoding` are compressed when the client accepts the configured algorithm,
+    /// encoded responses are decompressed when the client doesn't accept their encoding.
+    /// Encodings the proxy can't decode are passed through as is.
+    fn response_transform(
+        &self,
+        req: &RequestHeader,
+        response: &mut ResponseHeader,
+        ctx: &mut ProxyCtx,
+    ) -> Result<()> {
+        let settings = ctx.settings.clone();
+        let config = &settings.config;
+        let stash = config.length_stash_header.as_str();
+        // these can't have a body to transform
+        if config.no_transform
+            || settings.exempt(req)
+            || (config.compression_off_for_range_requests && response.status.as_u16() == 206)
+            || req.method == http::Method::HEAD
+            || response.status.is_informational()
+            || matches!(response.status.as_u16(), 204 | 304)
+        {
+            return Ok(());
+        }
+
+        let codings = accepted_codings(req);
+        let client_accepts = |algorithm| {
+            codings
+                .as_ref()
+                .is_some_and(|codings| accept_encoding::accepts(codings, algorithm))
+        };
+        let content_encoding = response
+            .headers
+            .get(CONTENT_ENCODING)
+            .map(|ce| ce.to_str().unwrap_or_default().trim());
+        match content_encoding {
+            // what the client gets depends on its Accept-Encoding from here on
+            None => {
+                add_vary(response, ACCEPT_ENCODING.as_str())?;
+                if config.algorithm == Algorithm::Identity || !client_accepts(config.algorithm) {
+                    return Ok(());
+                }
+                let algorithm = config.algorithm;
+                ctx.response_op = Op::Compress;
+                response.remove_header(stash);
+                if let Some(cl) = response.remove_header(&CONTENT_LENGTH) {
+                    response.insert_header(stash.to_string(), cl)?;
+                }
+                response.insert_header(CONTENT_ENCODING, algorithm.content_encoding())?;
+                let level = settings.level_override(req, algorithm);
+                ctx.response_compressor = Some(settings.compressor(algorithm, level)?);
+                response.remove_header(&TRANSFER_ENCODING);
+                response.insert_header(TRANSFER_ENCODING, "chunked")?;
+            }
+            Some(ce) if !ce.eq_ignore_ascii_case("identity") => {
+                // decode whatever the upstream actually sent, not what we asked it for
+                let Some(algorithm) = Algorithm::from_content_encoding(ce) else {
+                    warn!("passing through response with unsupported Content-Encoding {ce:?}");
+                    return Ok(());
+                };
+                add_vary(response, ACCEPT_ENCODING.as_str())?;
+                if client_accepts(algorithm) {
+                    return Ok(());
+                }
+                ctx.response_op = Op::Decompress;
+                let limit = config.max_response_body;
+                ctx.response_decompressor = Some(settings.decompressor(algorithm, limit)?);
+                response.remove_header(&CONTENT_ENCODING);
+                if let Some(cl) = response.remove_header(stash) {
+                    response.insert_header(CONTENT_LENGTH, cl)?;
+                    response.remove_header(&TRANSFER_ENCODING);
+                } else {
+                    response.remove_header(&CONTENT_LENGTH);
+                    response.insert_header(TRANSFER_ENCODING, "chunked")?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ProxyHttp for Proxy0 {
+    type CTX = ProxyCtx;
+
+    fn new_ctx(&self) -> Self::CTX {
+        ProxyCtx {
+            op: Op::None,
+            compressor: None,
+            decompressor: None,
+            response_op: Op::None,
+            response_compressor: None,
+            response_decompressor: None,
+            retries: 0,
+            request_body_bytes: 0,
+            upgrade: false,
+            sampled: None,
+            buffered: None,
+            incompressible: false,
+            restored_length: None,
+            decoded_bytes: 0,
+            codec: None,
+            upstream_host: None,
+            sniff: None,
+            sniffed: BytesMut::new(),
+            request_id: None,
+            tap_id: None,
+            request_tap: None,
+            response_tap: None,
+            cache_key: None,
+            cache_fill: None,
+            start: None,
+            upstream_start: None,
+            upstream_ttfb: None,
+            upstream: None,
+            affinity: None,
+            new_affinity: false,
+            settings: self.settings.get(),
+            _in_flight: InFlight::new(&self.in_flight),
+            slot: None,
+        }
+    }
+
+    async fn upstream_peer(
+        &self,
+        session: &mut pingora::prelude::Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<Box<HttpPeer>> {
+        // unrouted requests were already answered in `request_filter`, what's left fails when
+        // no target is healthy
+        let req = session.req_header();
+        let target = self.route(
+            &ctx.settings,
+            request_host(req),
+            req.uri.path(),
+            ctx.affinity.as_deref(),
+        )?;
+        ctx.upstream = Some(target.to_string());
+        Ok(Box::new(ctx.settings.peer(target)))
+    }
+
+    async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool>
+    where
+        Self::CTX: Send + Sync,
+    {
+        ctx.start = Some(Instant::now());
+        self.limit_keepalive(session, ctx);
+        // answered before any routing or compression, and not counted as a request
+        if session.req_header().uri.path() == ctx.settings.config.health_path {
+            // drained, load balancers take the proxy out of rotation while the requests
+            // they already sent are served
+            let (status, body) = match self.draining.load(Ordering::Relaxed) {
+                false => (200, Bytes::from_static(b"ok\n")),
+                true => (503, Bytes::from_static(b"draining\n")),
+            };
+            let mut response = ResponseHeader::build(status, Some(3))?;
+            response.insert_header(CONTENT_TYPE, "text/plain")?;
+            response.insert_header(CONTENT_LENGTH, body.len())?;
+            response.insert_header(http::header::CACHE_CONTROL, "no-store")?;
+            session
+                .write_response_header(Box::new(response), false)
+                .await?;
+            session.write_response_body(Some(body), true).await?;
+            return Ok(true);
+        }
+        self.metrics.request();
+        let id_header = &ctx.settings.config.request_id_header;
+        let sent = session.req_header().headers.get(id_header);
+        ctx.request_id = Some(request_id::from_client(sent.map(|id| id.as_bytes())));
+        ctx.affinity = self.affinity(session, ctx);
+        if !self.allow(session, ctx) {
+            session.respond_error(429).await?;
+            return Ok(true);
+        }
+        if let Some(max) = ctx.settings.config.max_header_size
+            && header_size(session.req_header()) > max
+        {
+            session.respond_error(431).await?;
+            return Ok(true);
+        }
+        if let Some(max) = ctx.settings.config.max_concurrent_requests {
+            ctx.slot = InFlight::within(&self.concurrent, max as usize);
+            if ctx.slot.is_none() {
+                let mut response = ResponseHeader::build(503, Some(3))?;
+                response.insert_header(RETRY_AFTER, RETRY_AFTER_SECS)?;
+                response.insert_header(CONTENT_LENGTH, 0)?;
+                session
+                    .write_response_header(Box::new(response), true)
+                    .await?;
+                return Ok(true);
+            }
+        }
+        if let Some(response) = cors_preflight(&ctx.settings.config, session.req_header())? {
+            session
+                .write_response_header(Box::new(response), true)
+                .await?;
+            return Ok(true);
+        }
+        // after the preflights, browsers send those without credentials
+        if !ctx.settings.config.basic_auth.is_empty()
+            && !auth::authorized(
+                session
+                    .req_header()
+                    .headers
+                    .get(AUTHORIZATION)
+                    .map(|value| value.as_bytes()),
+                &ctx.settings.config.basic_auth,
+            )
+        {
+            let mut response = ResponseHeader::build(401, Some(3))?;
+            response.insert_header(WWW_AUTHENTICATE, "Basic realm=\"http-proxy\"")?;
+            response.insert_header(CONTENT_LENGTH, 0)?;
+            session
+                .write_response_header(Box::new(response), true)
+                .await?;
+            return Ok(true);
+        }
+        let content_length = content_length(session.req_header());
+        if let (Some(cl), Some(max)) = (content_length, ctx.settings.config.max_request_body)
+            && cl > max
+        {
+            session.respond_error(413).await?;
+            return Ok(true);
+        }
+        if ctx.settings.config.route_default == RouteDefault::NotFound
+            && self
+                .route(
+                    &ctx.settings,
+                    request_host(session.req_header()),
+                    session.req_header().uri.path(),
+                    None,
+                )
+                .is_err()
+        {
+            session.respond_error(404).await?;
+            return Ok(true);
+        }
+        if self.serve_cached(session, ctx).await? {
+            return Ok(true);
+        }
+        let req = session.req_header();
+        if ctx.settings.config.tap_dir.is_some() && tap::wanted(&req.headers, req.uri.query()) {
+            ctx.tap_id = ctx.request_id.as_deref().map(tap::next_id);
+            ctx.request_tap = self.tap(ctx, "request");
+        }
+        (ctx.codec, ctx.upstream_host) = ctx
+            .settings
+            .routed(request_host(req), req.uri.path())
+            .map_or((None, None), |route| (route.codec, route.host.clone()));
+        self.sample_request_body(session, ctx).await?;
+        Ok(false)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        ctx.upstream_start = Some(Instant::now());
+        ctx.new_attempt();
+        self.request_transform(upstream_request, ctx)?;
+        self.buffer_request_body(session, upstream_request, ctx)
+            .await?;
+        self.forward_headers(session, upstream_request, ctx)?;
+        self.edit_request_headers(upstream_request, ctx)?;
+
+        // see `--adjust-downstream-decompression`
+        let config = &ctx.settings.config;
+        if !config.no_transform && config.adjust_downstream_decompression {
+            session.upstream_compression.adjust_decompression(true);
+        }
+        Ok(())
+    }
+
+    async fn request_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        // the frames of an upgraded connection go both ways untouched
+        if ctx.upgrade {
+            return Ok(());
+        }
+        // what pingora replays is the body as received, which went upstream compressed
+        // already
+        if let Some(buffered) = &ctx.buffered {
+            *body = Some(if end { buffered.clone() } else { Bytes::new() });
+            return Ok(());
+        }
+        // pingora replays what was sampled as the first chunk, unless it outgrew the retry
+        // buffer that holds it
+        if let Some(sampled) = ctx.sampled.take()
+            && session.as_ref().retry_buffer_truncated()
+        {
+            let mut joined = BytesMut::from(&sampled[..]);
+            joined.extend_from_slice(body.as_deref().unwrap_or_default());
+            *body = Some(joined.freeze());
+        }
+        self.transform_request_body(ctx, body, end)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        ctx.upstream_ttfb = ctx.upstream_start.map(|start| start.elapsed());
+        self.response_transform(session.req_header(), upstream_response, ctx)?;
+        self.edit_response_headers(upstream_response, ctx)?;
+        // cached before the headers of this one client go in
+        if let Some(key) = ctx.cache_key.take()
+            && upstream_response.status == 200
+            && cache::storable(&upstream_response.headers)
+        {
+            ctx.cache_fill = Some((key, upstream_response.clone(), BytesMut::new()));
+        }
+        if ctx.new_affinity
+            && let Some(affinity) = &ctx.affinity
+        {
+            let cookie = format!(
+                "{}={affinity}; Path=/; HttpOnly",
+                ctx.settings.config.sticky_cookie
+            );
+            upstream_response.append_header(SET_COOKIE, cookie)?;
+        }
+        if let Some(id) = &ctx.request_id {
+            upstream_response.insert_header(ctx.settings.config.request_id_header.clone(), id)?;
+        }
+        ctx.response_tap = self.tap(ctx, "response");
+        if ctx.settings.config.debug_stats
+            && let Some(encoder) = ctx.encoder()
+        {
+            insert_stat_headers(encoder, upstream_response)?;
+        }
+        Ok(())
+    }
+
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<Error>,
+    ) -> Box<Error> {
+        if self.retry(&session.req_header().method, ctx) {
+            e.set_retry(true);
+        }
+        e
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<Error> {
+        let name = ctx
+            .upstream
+            .as_deref()
+            .map(|t| ctx.settings.upstream_name(t));
+        let mut e = e.more_context(format!("Peer {}: {peer}", name.unwrap_or("-")));
+        // same as the default, on top of which the retry budget applies. Only an error on a
+        // reused connection is retried, as the upstream may have closed it while it was idle,
+        // and the failed connection isn't pooled again.
+        e.retry
+            .decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+        // errors like 413 are about the request itself, another try ends the same way
+        let rejected = matches!(e.etype(), HTTPStatus(_));
+        if e.retry() && (rejected || !self.retry(&session.req_header().method, ctx)) {
+            e.set_retry(false);
+        }
+        e
+    }
+
+    async fn logging(&self, session: &mut Session, _e: Option<&Error>, ctx: &mut Self::CTX)
+    where
+        Self::CTX: Send + Sync,
+    {
+        let req = session.req_header();
+        let entry = AccessLog {
+            method: req.method.as_str(),
+            path: req.uri.path(),
+            host: request_host(req),
+            request_id: ctx.request_id.as_deref(),
+            status: session
+                .response_written()
+                .map_or(0, |response| response.status.as_u16()),
+            upstream: ctx
+                .upstream
+                .as_deref()
+                .map(|target| ctx.settings.upstream_name(target)),
+            bytes_in: session.body_bytes_read(),
+            bytes_out: session.body_bytes_sent(),
+            latency: ctx.start.map(|start| start.elapsed()).unwrap_or_default(),
+            upstream_ttfb: ctx.upstream_ttfb,
+            codec_time: ctx.codec_time(),
+        };
+        info!(target: "access", "{}", entry.format(ctx.settings.config.log_format));
+    }
+
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &Error,
+        ctx: &mut Self::CTX,
+    ) -> FailToProxy
+    where
+        Self::CTX: Send + Sync,
+    {
+        self.metrics.error();
+        let settings = &ctx.settings;
+        let mut code = error_status(e);
+        if is_upstream_failure(e) {
+            code = settings.config.upstream_error_status.unwrap_or(code);
+            if let Some(page) = &settings.error_page {
+                // straight to the client, past any compression of the response
+                let sent = async {
+                    let mut response = ResponseHeader::build(code, Some(4))?;
+                    response.insert_header(CONTENT_TYPE, "text/html; charset=utf-8")?;
+                    response.insert_header(CONTENT_LENGTH, page.len())?;
+                    response.insert_header(http::header::CACHE_CONTROL, "no-store")?;
+                    session
+                        .as_downstream_mut()
+                        .write_error_response(response, page.clone())
+                        .await
+                };
+                sent.await.unwrap_or_else(|e| {
+                    log::error!("failed to send error page to downstream: {e}");
+                });
+                return FailToProxy {
+                    error_code: code,
+                    can_reuse_downstream: false,
+                };
+            }
+        }
+        if code > 0 {
+            session.respond_error(code).await.unwrap_or_else(|e| {
+                log::error!("failed to send error response to downstream: {e}");
+            });
+        }
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
+
+    fn upstream_response_body_filter(
+        &self,
+        _session: &mut Session,
+        _body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        _ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>>
+    where
+        Self::CTX: Send + Sync,
+    {
+        // the plain body is what goes into a compressor and what comes out of a decompressor
+        let decompress = matches!(ctx.response_op, Op::Decompress);
+        if !decompress && let Some(tap) = &mut ctx.response_tap {
+            tap.write(body.as_deref().unwrap_or_default());
+        }
+        let encoder = match ctx.response_op {
+            Op::None | Op::Transcode => None,
+            Op::Compress => ctx.response_compressor.as_mut(),
+            Op::Decompress => ctx.response_decompressor.as_mut(),
+        };
+        if let Some(encoder) = encoder {
+            let data = if let Some(b) = body.as_ref() {
+                b.as_ref()
+            } else {
+                &[]
+            };
+            let encoded = encoder.encode(data, end_of_stream);
+            // a body decoding to too much, or not decoding at all, is the upstream's fault
+            *body = Some(match (decompress, encoded) {
+                (true, Err(e)) => return Err(e.into_up()),
+                (_, encoded) => encoded?,
+            });
+            if end_of_stream {
+                self.body_done(encoder.as_ref());
+            }
+        }
+        if decompress && let Some(tap) = &mut ctx.response_tap {
+            tap.write(body.as_deref().unwrap_or_default());
+        }
+        if let (Some((_, _, cached)), Some(limit)) =
+            (&mut ctx.cache_fill, ctx.settings.config.cache_size)
+        {
+            cached.extend_from_slice(body.as_deref().unwrap_or_default());
+            if cached.len() > limit {
+                ctx.cache_fill = None;
+            } else if end_of_stream && let Some((key, header, cached)) = ctx.cache_fill.take() {
+                let body = cached.freeze();
+                self.cache.insert(key, Cached { header, body }, limit);
+            }
+        }
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn proxy(args: &[&str]) -> Proxy0 {
+        let args = ["http-proxy", "-t", "127.0.0.1:80"].iter().chain(args);
+        proxy_from(args.map(OsString::from).collect())
+    }
+
+    fn proxy_from(args: Vec<OsString>) -> Proxy0 {
+        Proxy0::new(Config::load_from(&args).unwrap(), args).unwrap()
+    }
+
+    #[test]
+    fn stat_headers() {
+        let mut ctx = proxy(&[]).new_ctx();
+        ctx.compressor = Some(Box::new(Compressor::new(6)));
+        assert!(ctx.encoder().is_none());
+
+        ctx.op = Op::Compress;
+        let encoder = ctx.compressor.as_mut().unwrap();
+        encoder.encode(b"abcdefg", true).unwrap();
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        insert_stat_headers(ctx.encoder().unwrap(), &mut response).unwrap();
+        assert_eq!(response.headers["x-proxy-compress-algo"], "gzip");
+        assert_eq!(response.headers["x-proxy-bytes-in"], "7");
+        assert_eq!(response.headers["x-proxy-bytes-out"], "27");
+    }
+
+    #[test]
+    fn min_compress_size() {
+        let proxy = proxy(&["--min-compress-size", "100"]);
+        let mut ctx = proxy.new_ctx();
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header(CONTENT_TYPE, "application/json").unwrap();
+        req.insert_header(CONTENT_LENGTH, "99").unwrap();
+        proxy.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::None));
+        assert!(req.headers.get(CONTENT_ENCODING).is_none());
+        assert!(req.headers.get(TRANSFER_ENCODING).is_none());
+        assert_eq!(req.headers[CONTENT_LENGTH], "99");
+
+        let mut ctx = proxy.new_ctx();
+        req.insert_header(CONTENT_LENGTH, "100").unwrap();
+        proxy.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::Compress));
+        assert_eq!(req.headers[CONTENT_ENCODING], "zstd");
+
+        // unknown length
+        let mut ctx = proxy.new_ctx();
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header(CONTENT_TYPE, "application/json").unwrap();
+        req.insert_header(TRANSFER_ENCODING, "Chunked").unwrap();
+        req.append_header(TRANSFER_ENCODING, "chunked").unwrap();
+        proxy.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::Compress));
+        let te: Vec<_> = req.headers.get_all(TRANSFER_ENCODING).iter().collect();
+        assert_eq!(te, ["chunked"]);
+
+        // pingora turns the request into h2 for an h2 upstream before the transform
+        let mut ctx = proxy.new_ctx();
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.set_version(http::Version::HTTP_2);
+        req.insert_header(CONTENT_TYPE, "application/json").unwrap();
+        req.insert_header(CONTENT_LENGTH, "2048").unwrap();
+        proxy.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::Compress));
+        assert!(req.headers.get(TRANSFER_ENCODING).is_none());
+        assert!(req.headers.get(CONTENT_LENGTH).is_none());
+    }
+
+    #[test]
+    fn unique_framing_headers() {
+        let wire = |req: &RequestHeader| {
+            let mut buf = Vec::new();
+            req.header_to_h1_wire(&mut buf);
+            String::from_utf8(buf).unwrap()
+        };
+        let count = |req: &RequestHeader, name| req.headers.get_all(name).iter().count();
+        let transform = |proxy: &Proxy0, ce: &[&str]| {
+            let mut ctx = proxy.new_ctx();
+            let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+            req.insert_header(CONTENT_TYPE, "application/json").unwrap();
+            for ce in ce {
+                req.append_header("content-encoding", *ce).unwrap();
+            }
+            req.append_header("transfer-encoding", "chunked").unwrap();
+            req.append_header("transfer-encoding", "chunked").unwrap();
+            proxy.request_transform(&mut req, &mut ctx).unwrap();
+            assert!(count(&req, TRANSFER_ENCODING) <= 1);
+            assert!(count(&req, CONTENT_ENCODING) <= 1);
+            assert!(!wire(&req).contains("content-encoding"));
+            (ctx.op, req)
+        };
+
+        let (op, req) = transform(&proxy(&["--min-compress-size", "0"]), &[]);
+        assert!(matches!(op, Op::Compress));
+        assert_eq!(req.headers[CONTENT_ENCODING], "zstd");
+        assert_eq!(wire(&req).matches("Content-Encoding: zstd\r\n").count(), 1);
+        assert_eq!(
+            wire(&req).matches("Transfer-Encoding: chunked\r\n").count(),
+            1
+        );
+
+        // both headers are decoded, not only the first
+        let transcode = proxy(&["--on-preencoded", "transcode"]);
+        let (op, req) = transform(&transcode, &["gzip", "br"]);
+        assert!(matches!(op, Op::Transcode));
+        assert_eq!(wire(&req).matches("Content-Encoding: zstd\r\n").count(), 1);
+        assert_eq!(count(&req, TRANSFER_ENCODING), 1);
+
+        let (op, req) = transform(&proxy(&[]), &["gzip", "br"]);
+        assert!(matches!(op, Op::Decompress));
+        assert_eq!(count(&req, CONTENT_ENCODING), 0);
+        assert_eq!(count(&req, TRANSFER_ENCODING), 1);
+    }
+
+    #[test]
+    fn bodyless_request() {
+        let proxy = proxy(&["--min-compress-size", "0"]);
+        let transform = |framing: Option<(http::HeaderName, &str)>| {
+            let mut ctx = proxy.new_ctx();
+            let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+            req.insert_header(CONTENT_TYPE, "application/json").unwrap();
+            if let Some((name, value)) = framing {
+                req.insert_header(name, value).unwrap();
+            }
+            proxy.request_transform(&mut req, &mut ctx).unwrap();
+            assert_eq!(
+                matches!(ctx.op, Op::Compress),
+                req.headers.contains_key(CONTENT_ENCODING)
+            );
+            matches!(ctx.op, Op::Compress)
+        };
+        assert!(!transform(None));
+        assert!(!transform(Some((CONTENT_LENGTH, "0"))));
+        // a body is compressed whatever the method
+        assert!(transform(Some((CONTENT_LENGTH, "10"))));
+        assert!(transform(Some((TRANSFER_ENCODING, "chunked"))));
+    }
+
+    #[test]
+    fn trailer_announcement() {
+        let proxy = proxy(&["--min-compress-size", "0"]);
+        let mut ctx = proxy.new_ctx();
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header(CONTENT_TYPE, "application/json").unwrap();
+        req.insert_header(TRANSFER_ENCODING, "chunked").unwrap();
+        req.insert_header(http::header::TRAILER, "x-checksum")
+            .unwrap();
+        proxy.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::Compress));
+        assert!(!req.headers.contains_key(http::header::TRAILER));
+    }
+
+    #[test]
+    fn compression_min_ratio() {
+        // past the 64 KiB pingora buffers, the body can't end while the sample is read
+        assert!(can_sample(64 * 1024, 16 * 1024));
+        assert!(!can_sample(64 * 1024 + 1, 16 * 1024));
+        assert!(!can_sample(80 * 1024 - 1, 16 * 1024));
+        assert!(can_sample(80 * 1024, 16 * 1024));
+
+        let proxy = proxy(&["--compression-min-ratio", "2"]);
+        let settings = proxy.settings.get();
+        let codec = settings.request_codec(None);
+        assert!(
+            settings
+                .worth_compressing(&b"abc".repeat(1000), codec, 2.0)
+                .unwrap()
+        );
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let noise: Vec<u8> = (0..3000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        assert!(!settings.worth_compressing(&noise, codec, 2.0).unwrap());
+
+        let mut ctx = proxy.new_ctx();
+        ctx.incompressible = true;
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header(CONTENT_TYPE, "application/json").unwrap();
+        req.insert_header(CONTENT_LENGTH, "3000").unwrap();
+        proxy.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::None));
+        assert!(req.headers.get(CONTENT_ENCODING).is_none());
+        assert_eq!(req.headers[CONTENT_LENGTH], "3000");
+    }
+
+    #[test]
+    fn compress_content_types() {
+        let patterns = ["text/*".to_string(), "application/json".to_string()];
+        assert!(content_type_matches(&patterns, "text/html"));
+        assert!(content_type_matches(&patterns, "Text/Plain; charset=utf-8"));
+        assert!(content_type_matches(&patterns, "application/json"));
+        assert!(!content_type_matches(&patterns, "application/json-seq"));
+        assert!(!content_type_matches(&patterns, "image/png"));
+        assert!(!content_type_matches(&patterns, "text"));
+        assert!(content_type_matches(&["*/*".to_string()], "image/png"));
+
+        let proxy = proxy(&[]);
+        let mut ctx = proxy.new_ctx();
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header(CONTENT_TYPE, "image/png").unwrap();
+        proxy.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::None));
+        assert!(req.headers.get(CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    fn accept_encoding() {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        assert!(accepted_codings(&req).is_none());
+        req.insert_header(ACCEPT_ENCODING, "deflate, GZIP;q=0.5")
+            .unwrap();
+        let codings = accepted_codings(&req).unwrap();
+        assert!(accept_encoding::accepts(&codings, Algorithm::Gzip));
+        assert!(accept_encoding::accepts(&codings, Algorithm::Deflate));
+        assert!(!accept_encoding::accepts(&codings, Algorithm::Zstd));
+    }
+
+    #[test]
+    fn negotiate_request_encoding() {
+        let proxy = proxy(&[]);
+        let mut ctx = proxy.new_ctx();
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header(CONTENT_TYPE, "application/json").unwrap();
+        req.insert_header(TRANSFER_ENCODING, "chunked").unwrap();
+        req.insert_header(ACCEPT_ENCODING, "zstd;q=0.5, br")
+            .unwrap();
+        proxy.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::Compress));
+        assert_eq!(req.headers[CONTENT_ENCODING], "br");
+        assert_eq!(ctx.compressor.as_ref().unwrap().stat().0, "br");
+
+        // nothing in common, the body goes out as identity
+        let mut ctx = proxy.new_ctx();
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header(CONTENT_TYPE, "application/json").unwrap();
+        req.insert_header(CONTENT_LENGTH, "2048").unwrap();
+        req.insert_header(ACCEPT_ENCODING, "lz4, zstd;q=0").unwrap();
+        proxy.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::None));
+        assert!(req.headers.get(CONTENT_ENCODING).is_none());
+        assert_eq!(req.headers[CONTENT_LENGTH], "2048");
+    }
+
+    #[test]
+    fn cors() {
+        let preflight = |proxy: &Proxy0, headers: &[(&str, &str)]| {
+            let mut req = RequestHeader::build("OPTIONS", b"/api", None).unwrap();
+            for (name, value) in headers {
+                req.insert_header(name.to_string(), *value).unwrap();
+            }
+            cors_preflight(&proxy.settings.get().config, &req).unwrap()
+        };
+        let asking = [
+            ("access-control-request-method", "PUT"),
+            ("access-control-request-headers", "content-type, x-api-key"),
+        ];
+        assert!(preflight(&proxy(&[]), &asking).is_none());
+
+        let proxy = proxy(&[
+            "--cors-allow-origin",
+            "https://app.example.com",
+            "--cors-allow-methods",
+            "GET,PUT",
+        ]);
+        let response = preflight(&proxy, &asking).unwrap();
+        assert_eq!(response.status, 204);
+        assert_eq!(
+            response.headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(response.headers[ACCESS_CONTROL_ALLOW_METHODS], "GET, PUT");
+        assert_eq!(
+            response.headers[ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type, x-api-key"
+        );
+        assert_eq!(response.headers[VARY], "Origin");
+        // a plain OPTIONS goes upstream
+        assert!(preflight(&proxy, &[]).is_none());
+
+        let ctx = proxy.new_ctx();
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        proxy.edit_response_headers(&mut response, &ctx).unwrap();
+        assert_eq!(
+            response.headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+
+        assert!(
+            Config::load_from(["http-proxy", "-t", "a:1", "--cors-allow-methods", "GET"]).is_err()
+        );
+    }
+
+    #[test]
+    fn zstd_tuning() {
+        let tuned = proxy(&["--zstd-window-log", "28", "--zstd-long-mode"]);
+        let settings = tuned.settings.get();
+        let input = b"far apart ".repeat(1000);
+        let compressed = settings
+            .compressor(Algorithm::Zstd, None)
+            .unwrap()
+            .encode(&input, true)
+            .unwrap();
+        let decoded = settings
+            .decompressor(Algorithm::Zstd, usize::MAX)
+            .unwrap()
+            .encode(&compressed, true)
+            .unwrap();
+        assert_eq!(decoded, input);
+        // a peer without the same window log refuses the frame
+        let plain = proxy(&[]).settings.get();
+        let decompressor = plain.decompressor(Algorithm::Zstd, usize::MAX);
+        assert!(decompressor.unwrap().encode(&compressed, true).is_err());
+
+        for bad in ["9", "32"] {
+            assert!(
+                Config::load_from(["http-proxy", "-t", "a:1", "--zstd-window-log", bad]).is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn listener_options() {
+        let options = |args: &[&str]| tcp_options(&proxy(args).settings.get().config);
+        assert_eq!(options(&[]).tcp_fastopen, None);
+        assert_eq!(options(&["--tcp-fastopen", "256"]).tcp_fastopen, Some(256));
+    }
+
+    #[test]
+    fn listener_tls() {
+        // refused, pingora has no TLS backend to serve it with
+        let args = [
+            "http-proxy",
+            "-t",
+            "127.0.0.1:80",
+            "--tls-cert",
+            "c",
+            "--tls-key",
+            "k",
+        ];
+        let config = Config::try_parse_from(args).unwrap();
+        let e = Proxy0::new(config, Vec::new()).err().unwrap();
+        assert!(e.starts_with("--tls-cert needs"), "{e}");
+    }
+
+    #[test]
+    fn verbosity() {
+        let count = |args: &[&str]| proxy(args).settings.get().config.verbose;
+        assert_eq!(log_level(count(&[])), log::LevelFilter::Warn);
+        assert_eq!(log_level(count(&["-v"])), log::LevelFilter::Info);
+        assert_eq!(log_level(count(&["-vv"])), log::LevelFilter::Debug);
+        assert_eq!(
+            log_level(count(&["-v", "--verbose", "-v"])),
+            log::LevelFilter::Trace
+        );
+        assert_eq!(log_level(count(&["-vvvv"])), log::LevelFilter::Trace);
+    }
+
+    #[test]
+    fn route_codec() {
+        let proxy = proxy(&[
+            "--route",
+            "legacy.example.com=10.0.0.1:80,gzip:1",
+            "--path-route",
+            "/internal=10.0.0.2:80",
+        ]);
+        let settings = proxy.settings.get();
+        let codec = |host, path| settings.routed(host, path).and_then(|route| route.codec);
+        assert_eq!(
+            codec(Some("legacy.example.com"), "/internal"),
+            Some(Codec {
+                algorithm: Algorithm::Gzip,
+                level: Some(1)
+            })
+        );
+        assert_eq!(codec(None, "/internal"), None);
+        assert!(settings.routed(None, "/other").is_none());
+
+        let compress = |codec| {
+            let mut ctx = proxy.new_ctx();
+            ctx.codec = codec;
+            let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+            req.insert_header(CONTENT_TYPE, "application/json").unwrap();
+            req.insert_header(TRANSFER_ENCODING, "chunked").unwrap();
+            proxy.request_transform(&mut req, &mut ctx).unwrap();
+            req.headers[CONTENT_ENCODING].to_str().unwrap().to_string()
+        };
+        assert_eq!(compress(codec(Some("legacy.example.com"), "/")), "gzip");
+        // the global --algorithm
+        assert_eq!(compress(codec(None, "/internal")), "zstd");
+
+        // an identity route leaves bodies as they are
+        let mut ctx = proxy.new_ctx();
+        ctx.codec = Some(Codec {
+            algorithm: Algorithm::Identity,
+            level: None,
+        });
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header(CONTENT_TYPE, "application/json").unwrap();
+        req.insert_header(TRANSFER_ENCODING, "chunked").unwrap();
+        proxy.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::None));
+        assert!(req.headers.get(CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    fn compress_response() {
+        let proxy = proxy(&["-a", "gzip"]);
+        let mut ctx = proxy.new_ctx();
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header(ACCEPT_ENCODING, "gzip").unwrap();
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response.insert_header(CONTENT_LENGTH, "7").unwrap();
+
+        proxy
+            .response_transform(&req, &mut response, &mut ctx)
+            .unwrap();
+        assert!(matches!(ctx.response_op, Op::Compress));
+        assert_eq!(response.headers[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers[TRANSFER_ENCODING], "chunked");
+        assert_eq!(response.headers["x-crd-content-length"], "7");
+        assert!(response.headers.get(CONTENT_LENGTH).is_none());
+
+        // a client that doesn't accept gzip gets the stashed length back
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header(ACCEPT_ENCODING, "br").unwrap();
+        let mut ctx = proxy.new_ctx();
+        proxy
+            .response_transform(&req, &mut response, &mut ctx)
+            .unwrap();
+        assert!(matches!(ctx.response_op, Op::Decompress));
+        assert!(response.headers.get(CONTENT_ENCODING).is_none());
+        assert!(response.headers.get(TRANSFER_ENCODING).is_none());
+        assert_eq!(response.headers[CONTENT_LENGTH], "7");
+        // both passes saw the Accept-Encoding, it's listed once
+        assert_eq!(response.headers.get_all(VARY).iter().count(), 1);
+        assert_eq!(response.headers[VARY], "accept-encoding");
+    }
+
+    #[test]
+    fn level_override() {
+        let req = |level: &str| {
+            let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+            req.insert_header(COMPRESS_LEVEL_HEADER, level).unwrap();
+            req
+        };
+        let allowed = proxy(&["--allow-header-overrides"]);
+        let settings = allowed.settings.get();
+        assert_eq!(settings.level_override(&req("1"), Algorithm::Gzip), Some(1));
+        assert_eq!(
+            settings.level_override(&req("-7"), Algorithm::Zstd),
+            Some(-7)
+        );
+        for bad in ["10", "-1", "fast", ""] {
+            assert_eq!(settings.level_override(&req(bad), Algorithm::Gzip), None);
+        }
+        let mut upstream = req("1");
+        allowed
+            .edit_request_headers(&mut upstream, &allowed.new_ctx())
+            .unwrap();
+        assert!(upstream.headers.get(COMPRESS_LEVEL_HEADER).is_none());
+
+        // only with the flag
+        let settings = proxy(&[]).settings.get();
+        assert_eq!(settings.level_override(&req("1"), Algorithm::Gzip), None);
+    }
+
+    #[test]
+    fn gzip_header() {
+        let header = |args: &[&str]| proxy(args).settings.get().gzip_header();
+        assert_eq!(header(&[]), GzipHeader::default());
+        assert_eq!(header(&["--gzip-deterministic"]), GzipHeader::default());
+        let stamped = header(&["--gzip-mtime", "--gzip-filename", "body.json"]);
+        assert_eq!(stamped.filename.as_deref(), Some(&b"body.json"[..]));
+        assert!(stamped.mtime > 0);
+        assert_eq!(stamped.os, Some(GZIP_OS_UNIX));
+    }
+
+    #[test]
+    fn vary() {
+        let proxy = proxy(&["-a", "gzip"]);
+        let vary = |upstream: &[&str], accept: &str| {
+            let mut ctx = proxy.new_ctx();
+            let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+            req.insert_header(ACCEPT_ENCODING, accept).unwrap();
+            let mut response = ResponseHeader::build(200, None).unwrap();
+            for value in upstream {
+                response.append_header(VARY, *value).unwrap();
+            }
+            proxy
+                .response_transform(&req, &mut response, &mut ctx)
+                .unwrap();
+            let values: Vec<_> = response
+                .headers
+                .get_all(VARY)
+                .iter()
+                .map(|value| value.to_str().unwrap().to_string())
+                .collect();
+            values.join(", ")
+        };
+        assert_eq!(vary(&[], "gzip"), "accept-encoding");
+        // not compressed for this client, but it would have been for another
+        assert_eq!(vary(&[], "identity"), "accept-encoding");
+        assert_eq!(vary(&["Cookie"], "gzip"), "Cookie, accept-encoding");
+        assert_eq!(
+            vary(&["cookie, Accept-Encoding"], "gzip"),
+            "cookie, Accept-Encoding"
+        );
+        assert_eq!(vary(&["*"], "gzip"), "*");
+    }
+
+    #[test]
+    fn response_without_body() {
+        let proxy = proxy(&["-a", "gzip"]);
+        let mut ctx = proxy.new_ctx();
+        let mut req = RequestHeader::build("HEAD", b"/", None).unwrap();
+        req.insert_header(ACCEPT_ENCODING, "gzip").unwrap();
+        let mut response = ResponseHeader::build(200, None).unwrap();
+
+        proxy
+            .response_transform(&req, &mut response, &mut ctx)
+            .unwrap();
+        assert!(matches!(ctx.response_op, Op::None));
+        assert!(response.headers.get(CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    fn missing_zstd_dict() {
+        let args = [
+            "http-proxy",
+            "-t",
+            "127.0.0.1:80",
+            "--zstd-dict",
+            "/nonexistent",
+        ];
+        assert!(Proxy0::new(Config::try_parse_from(args).unwrap(), Vec::new()).is_err());
+    }
+
+    #[test]
+    fn response_encoding_from_upstream() {
+        let proxy = proxy(&["-a", "zstd"]);
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+
+        // the upstream answered with gzip although zstd is configured
+        let mut ctx = proxy.new_ctx();
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response.insert_header(CONTENT_ENCODING, "gzip").unwrap();
+        proxy
+            .response_transform(&req, &mut response, &mut ctx)
+            .unwrap();
+        assert!(matches!(ctx.response_op, Op::Decompress));
+        assert_eq!(ctx.response_decompressor.unwrap().stat().0, "de-gzip");
+        assert!(response.headers.get(CONTENT_ENCODING).is_none());
+
+        let mut ctx = proxy.new_ctx();
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response.insert_header(CONTENT_ENCODING, "lz4").unwrap();
+        proxy
+            .response_transform(&req, &mut response, &mut ctx)
+            .unwrap();
+        assert!(matches!(ctx.response_op, Op::None));
+        assert_eq!(response.headers[CONTENT_ENCODING], "lz4");
+    }
+
+    #[test]
+    fn upstream_names() {
+        let proxy = proxy(&[
+            "-t",
+            "10.0.0.1:80",
+            "--upstream-name",
+            "10.0.0.1:80=primary",
+        ]);
+        let settings = proxy.settings.get();
+        assert_eq!(settings.upstream_name("10.0.0.1:80"), "primary");
+        assert_eq!(settings.upstream_name("127.0.0.1:80"), "127.0.0.1:80");
+        // the name is only a label, the SNI is still the host of the target
+        assert_eq!(settings.peer("10.0.0.1:80").sni, "10.0.0.1");
+    }
+
+    #[test]
+    fn round_robin_targets() {
+        let args = ["http-proxy", "-t", "a:80", "-t", "b:80", "--target", "c:80"];
+        let balanced = proxy_from(args.map(OsString::from).to_vec());
+        let settings = balanced.settings.get();
+        let targets: Vec<_> = (0..10)
+            .map(|_| balanced.next_target(&settings).unwrap().to_string())
+            .collect();
+        assert_eq!(
+            targets,
+            [
+                "a:80", "b:80", "c:80", "a:80", "b:80", "c:80", "a:80", "b:80", "c:80", "a:80"
+            ]
+        );
+
+        let single = proxy(&[]);
+        let settings = single.settings.get();
+        assert!((0..3).all(|_| single.next_target(&settings) == Some("127.0.0.1:80")));
+    }
+
+    #[test]
+    fn weighted_targets() {
+        let args = ["http-proxy", "-t", "a:80=3", "-t", "b:80"];
+        let balanced = proxy_from(args.map(OsString::from).to_vec());
+        let settings = balanced.settings.get();
+        let targets: Vec<_> = (0..100)
+            .map(|_| balanced.next_target(&settings).unwrap())
+            .collect();
+        assert_eq!(targets.iter().filter(|t| **t == "a:80").count(), 75);
+        // spread out, not three in a row and then one
+        assert_eq!(targets[..4], ["a:80", "a:80", "b:80", "a:80"]);
+    }
+
+    #[test]
+    fn sticky_targets() {
+        let args = [
+            "http-proxy",
+            "-t",
+            "a:80",
+            "-t",
+            "b:80",
+            "-t",
+            "c:80",
+            "--sticky",
+            "ip",
+        ];
+        let sticky = proxy_from(args.map(OsString::from).to_vec());
+        let settings = sticky.settings.get();
+        let clients: Vec<_> = (0..30).map(|i| format!("10.0.0.{i}")).collect();
+        let route = |client: &str| sticky.route(&settings, None, "/", Some(client)).unwrap();
+        let before: Vec<_> = clients.iter().map(|client| route(client)).collect();
+        assert_eq!(
+            before,
+            clients
+                .iter()
+                .map(|client| route(client))
+                .collect::<Vec<_>>()
+        );
+        assert!(before.contains(&"a:80") && before.contains(&"b:80"));
+
+        sticky.health.record("b:80", false, 1);
+        for (client, before) in clients.iter().zip(before) {
+            match before {
+                "b:80" => assert_ne!(route(client), "b:80"),
+                before => assert_eq!(route(client), before),
+            }
+        }
+    }
+
+    #[test]
+    fn slow_start() {
+        let args = [
+            "http-proxy",
+            "-t",
+            "a:80",
+            "-t",
+            "b:80",
+            "--slow-start-seconds",
+            "60",
+        ];
+        let balanced = proxy_from(args.map(OsString::from).to_vec());
+        let settings = balanced.settings.get();
+        balanced.health.record("b:80", false, 1);
+        balanced.health.record("b:80", true, 1);
+        let to_b = (0..100)
+            .filter(|_| balanced.next_target(&settings) == Some("b:80"))
+            .count();
+        assert!(to_b < 5, "{to_b}");
+
+        // without the flag a recovered target is back in full right away
+        let args = ["http-proxy", "-t", "a:80", "-t", "b:80"];
+        let balanced = proxy_from(args.map(OsString::from).to_vec());
+        let settings = balanced.settings.get();
+        balanced.health.record("b:80", false, 1);
+        balanced.health.record("b:80", true, 1);
+        let to_b = (0..100)
+            .filter(|_| balanced.next_target(&settings) == Some("b:80"))
+            .count();
+        assert_eq!(to_b, 50);
+    }
+
+    #[test]
+    fn unhealthy_targets() {
+        let args = ["http-proxy", "-t", "a:80", "-t", "b:80", "-t", "c:80"];
+        let balanced = proxy_from(args.map(OsString::from).to_vec());
+        let settings = balanced.settings.get();
+        balanced.health.record("b:80", false, 1);
+        let targets: Vec<_> = (0..4)
+            .map(|_| balanced.next_target(&settings).unwrap())
+            .collect();
+        assert_eq!(targets, ["a:80", "c:80", "a:80", "c:80"]);
+
+        balanced.health.record("a:80", false, 1);
+        balanced.health.record("c:80", false, 1);
+        assert_eq!(balanced.next_target(&settings), None);
+        let e = balanced.route(&settings, None, "/", None).unwrap_err();
+        assert_eq!(error_status(&e), 503);
+    }
+
+    #[test]
+    fn upstream_tls() {
+        assert_eq!(target_host("example.com:443"), "example.com");
+        assert_eq!(target_host("[::1]:443"), "::1");
+        assert_eq!(target_host("example.com"), "example.com");
+
+        let plain = proxy(&[]);
+        assert!(!plain.settings.get().peer("127.0.0.1:80").is_tls());
+
+        // refused, pingora has no TLS backend to connect with
+        let args = ["http-proxy", "-t", "127.0.0.1:443", "--upstream-tls"];
+        assert!(Config::load_from(args).is_err());
+        let config = Config::try_parse_from(args).unwrap();
+        let e = Proxy0::new(config, Vec::new()).err().unwrap();
+        assert!(e.starts_with("--upstream-tls needs"), "{e}");
+
+        // an SNI is only sent over TLS
+        let sni = ["--upstream-sni", "example.com"];
+        assert!(
+            Config::try_parse_from([&["http-proxy", "-t", "127.0.0.1:443"], &sni[..]].concat())
+                .is_err()
+        );
+        let args = [&args[..], &sni[..]].concat();
+        assert!(Config::load_from(&args).is_err());
+        let config = Config::try_parse_from(&args).unwrap();
+        let e = Proxy0::new(config, Vec::new()).err().unwrap();
+        assert!(e.starts_with("--upstream-sni needs"), "{e}");
+
+        // h2c has to be spoken right away, over TLS the upstream may still pick HTTP/1.1
+        let versions = |peer: HttpPeer| {
+            let alpn = &peer.options.alpn;
+            (alpn.get_min_http_version(), alpn.get_max_http_version())
+        };
+        assert_eq!(
+            versions(
+                proxy(&["--upstream-http2"])
+                    .settings
+                    .get()
+                    .peer("127.0.0.1:80")
+            ),
+            (2, 2)
+        );
+        assert_eq!(versions(plain.settings.get().peer("127.0.0.1:80")), (1, 1));
+
+        // refused like --upstream-tls, named for the one given
+        let args = [
+            "http-proxy",
+            "-t",
+            "a:1",
+            "--upstream-tls",
+            "--upstream-insecure",
+        ];
+        let e = Config::try_parse_from(args)
+            .unwrap()
+            .check_tls()
+            .unwrap_err();
+        assert!(e.starts_with("--upstream-insecure needs"), "{e}");
+        let args = ["--upstream-tls", "--upstream-ca-file", "ca.pem"];
+        let args = ["http-proxy", "-t", "a:1"].iter().chain(&args);
+        let e = Config::try_parse_from(args)
+            .unwrap()
+            .check_tls()
+            .unwrap_err();
+        assert!(e.starts_with("--upstream-ca-file needs"), "{e}");
+
+        // only meaningful with TLS, and not both
+        assert!(Config::load_from(["http-proxy", "-t", "a:1", "--upstream-insecure"]).is_err());
+        let both = [
+            "http-proxy",
+            "-t",
+            "a:1",
+            "--upstream-tls",
+            "--upstream-insecure",
+            "--upstream-ca-file",
+            "ca.pem",
+        ];
+        assert!(Config::load_from(both).is_err());
+    }
+
+    #[test]
+    fn host_routing() {
+        let routed = proxy(&["--route", "api.example.com=10.0.0.1:80"]);
+        assert_eq!(
+            routed
+                .route(&routed.settings.get(), Some("API.example.com"), "/", None)
+                .ok(),
+            Some("10.0.0.1:80")
+        );
+        assert_eq!(
+            routed
+                .route(&routed.settings.get(), Some("other.example.com"), "/", None)
+                .ok(),
+            Some("127.0.0.1:80")
+        );
+        assert_eq!(
+            routed.route(&routed.settings.get(), None, "/", None).ok(),
+            Some("127.0.0.1:80")
+        );
+
+        let strict = proxy(&[
+            "--route",
+            "api.example.com=10.0.0.1:80",
+            "--route-default",
+            "not-found",
+        ]);
+        assert_eq!(
+            strict
+                .route(&strict.settings.get(), Some("api.example.com"), "/", None)
+                .ok(),
+            Some("10.0.0.1:80")
+        );
+        assert_eq!(
+            strict
+                .route(&strict.settings.get(), Some("other.example.com"), "/", None)
+                .ok(),
+            None
+        );
+
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header(http::header::HOST, "api.example.com:8080")
+            .unwrap();
+        assert_eq!(request_host(&req), Some("api.example.com"));
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.set_uri("https://api.example.com/".parse().unwrap());
+        assert_eq!(request_host(&req), Some("api.example.com"));
+    }
+
+    #[test]
+    fn path_routing() {
+        let routed = proxy(&[
+            "--path-route",
+            "/a=10.0.0.1:80",
+            "--path-route",
+            "/ab=10.0.0.2:80",
+            "--route",
+            "api.example.com=10.0.0.3:80",
+        ]);
+        assert_eq!(
+            routed
+                .route(&routed.settings.get(), None, "/a/x", None)
+                .ok(),
+            Some("10.0.0.1:80")
+        );
+        assert_eq!(
+            routed
+                .route(&routed.settings.get(), None, "/abc", None)
+                .ok(),
+            Some("10.0.0.2:80")
+        );
+        assert_eq!(
+            routed
+                .route(&routed.settings.get(), None, "/ab/x", None)
+                .ok(),
+            Some("10.0.0.2:80")
+        );
+        assert_eq!(
+            routed.route(&routed.settings.get(), None, "/b", None).ok(),
+            Some("127.0.0.1:80")
+        );
+        // host routes win
+        assert_eq!(
+            routed
+                .route(&routed.settings.get(), Some("api.example.com"), "/ab", None)
+                .ok(),
+            Some("10.0.0.3:80")
+        );
+        assert_eq!(
+            routed
+                .route(
+                    &routed.settings.get(),
+                    Some("other.example.com"),
+                    "/ab",
+                    None
+                )
+                .ok(),
+            Some("10.0.0.2:80")
+        );
+
+        assert!(
+            Config::try_parse_from(["http-proxy", "-t", "127.0.0.1:80", "--path-route", "a=b"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn check_config() {
+        let config = |args: &[&str]| {
+            let args = ["http-proxy", "-t", "127.0.0.1:80"].iter().chain(args);
+            Config::load_from(args).unwrap()
+        };
+        let summary = super::check_config(&config(&["--route", "a.example=127.0.0.1:81"])).unwrap();
+        assert!(summary.contains("listen: 0.0.0.0:18081\n"), "{summary}");
+        assert!(
+            summary.contains("routes: 1 by host, 0 by path\n"),
+            "{summary}"
+        );
+        assert!(summary.contains("compression: zstd\n"), "{summary}");
+
+        let missing = config(&["--zstd-dict", "/nonexistent/dict"]);
+        let e = super::check_config(&missing).unwrap_err();
+        assert!(e.starts_with("--zstd-dict: "), "{e}");
+        let e = super::check_config(&config(&["--pingora-conf", "/nonexistent.yaml"])).unwrap_err();
+        assert!(e.starts_with("--pingora-conf: "), "{e}");
+        // no port
+        let e = super::check_config(&config(&["-t", "127.0.0.1"])).unwrap_err();
+        assert!(e.starts_with("upstream 127.0.0.1: "), "{e}");
+
+        assert!(Config::load_from(["http-proxy", "-t", "a:1", "--read-timeout", "0"]).is_err());
+    }
+
+    #[test]
+    fn upstream_timeouts() {
+        let peer = proxy(&["--read-timeout", "500"])
+            .settings
+            .get()
+            .peer("127.0.0.1:80");
+        assert_eq!(
+            peer.options.connection_timeout,
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(peer.options.read_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(peer.options.write_timeout, Some(Duration::from_secs(60)));
+        assert_eq!(peer.options.idle_timeout, None);
+        let peer = proxy(&["--upstream-idle-timeout", "30000"])
+            .settings
+            .get()
+            .peer("127.0.0.1:80");
+        assert_eq!(peer.options.idle_timeout, Some(Duration::from_secs(30)));
+
+        let timeout = Error::explain(ReadTimedout, "upstream").into_up();
+        assert_eq!(error_status(&timeout), 504);
+        let refused = Error::explain(pingora::ErrorType::ConnectRefused, "upstream").into_up();
+        assert_eq!(error_status(&refused), 502);
+        assert!(is_upstream_failure(&timeout));
+        assert!(is_upstream_failure(&refused));
+        let unhealthy = Error::explain(HTTPStatus(503), "no healthy target").into_up();
+        assert!(!is_upstream_failure(&unhealthy));
+        let client = Error::explain(ReadError, "client").into_down();
+        assert!(!is_upstream_failure(&client));
+    }
+
+    #[test]
+    fn retries() {
+        let get = http::Method::GET;
+        let post = http::Method::POST;
+
+        let strict = proxy(&[]);
+        let mut ctx = strict.new_ctx();
+        assert!(!strict.retry(&post, &mut ctx));
+        assert!(strict.retry(&get, &mut ctx));
+        assert!(!strict.retry(&get, &mut ctx));
+
+        let lenient = proxy(&["--max-retries", "2", "--retry-non-idempotent"]);
+        let mut ctx = lenient.new_ctx();
+        assert!(lenient.retry(&post, &mut ctx));
+        assert!(lenient.retry(&get, &mut ctx));
+        assert!(!lenient.retry(&post, &mut ctx));
+        assert_eq!(ctx.retries, 2);
+    }
+
+    #[test]
+    fn reload() {
+        let path =
+            std::env::temp_dir().join(format!("http-proxy-reload-{}.yaml", std::process::id()));
+        std::fs::write(&path, "target: [127.0.0.1:80]\nalgorithm: gzip\n").unwrap();
+        let proxy = proxy_from(
+            ["http-proxy", "--config-file", path.to_str().unwrap()]
+                .map(OsString::from)
+                .to_vec(),
+        );
+        let in_flight = proxy.new_ctx();
+
+        std::fs::write(&path, "target: [127.0.0.2:80]\nalgorithm: br\n").unwrap();
+        proxy.settings.reload().unwrap();
+        let ctx = proxy.new_ctx();
+        assert_eq!(
+            ctx.settings.config.target,
+            [("127.0.0.2:80".to_string(), 1)]
+        );
+        assert_eq!(ctx.settings.config.algorithm, Algorithm::Brotli);
+        assert_eq!(
+            in_flight.settings.config.target,
+            [("127.0.0.1:80".to_string(), 1)]
+        );
+
+        // a broken file keeps the current settings
+        std::fs::write(&path, "target: [127.0.0.3:80]\nzstd-level: 99\n").unwrap();
+        assert!(proxy.settings.reload().is_err());
+        assert_eq!(
+            proxy.new_ctx().settings.config.target,
+            [("127.0.0.2:80".to_string(), 1)]
+        );
+
+        // so does an upstream that doesn't resolve
+        std::fs::write(
+            &path,
+            "target: [127.0.0.3:80]\nroute: [a.example=nowhere.invalid:80]\n",
+        )
+        .unwrap();
+        let e = proxy.settings.reload().unwrap_err();
+        assert!(e.starts_with("upstream nowhere.invalid:80: "), "{e}");
+        assert_eq!(
+            proxy.new_ctx().settings.config.target,
+            [("127.0.0.2:80".to_string(), 1)]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn request_header_size() {
+        let raw = "GET /a?b=c HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n";
+        let mut req = RequestHeader::build("GET", b"/a?b=c", None).unwrap();
+        req.insert_header(HOST, "example.com").unwrap();
+        req.insert_header("accept", "*/*").unwrap();
+        assert_eq!(header_size(&req), raw.len());
+    }
+
+    #[test]
+    fn missing_request_chunk() {
+        let proxy = proxy(&["-a", "gzip"]);
+        let mut ctx = proxy.new_ctx();
+        ctx.op = Op::Compress;
+        ctx.compressor = Some(
+            proxy
+                .settings
+                .get()
+                .compressor(Algorithm::Gzip, None)
+                .unwrap(),
+        );
+        let mut body = None;
+        proxy
+            .transform_request_body(&mut ctx, &mut body, false)
+            .unwrap();
+        assert_eq!(body, None);
+        assert_eq!(ctx.compressor.as_ref().unwrap().stat().1, 0);
+
+        let mut body = Some(Bytes::from_static(b"body"));
+        proxy
+            .transform_request_body(&mut ctx, &mut body, true)
+            .unwrap();
+        assert_eq!(&body.unwrap()[..2], &[0x1f, 0x8b]);
+        // the end of a body has to be flushed even without a chunk
+        let mut ctx = proxy.new_ctx();
+        ctx.op = Op::Compress;
+        ctx.compressor = Some(
+            proxy
+                .settings
+                .get()
+                .compressor(Algorithm::Gzip, None)
+                .unwrap(),
+        );
+        let mut body = None;
+        proxy
+            .transform_request_body(&mut ctx, &mut body, true)
+            .unwrap();
+        assert!(body.is_some_and(|body| !body.is_empty()));
+    }
+
+    #[test]
+    fn max_response_body() {
+        let proxy = proxy(&[
+            "--max-response-body",
+            "100",
+            "--max-decompressed-size",
+            "10",
+        ]);
+        let mut ctx = proxy.new_ctx();
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response.insert_header(CONTENT_ENCODING, "gzip").unwrap();
+        proxy
+            .response_transform(&req, &mut response, &mut ctx)
+            .unwrap();
+        assert!(matches!(ctx.response_op, Op::Decompress));
+
+        // the request side limit doesn't apply
+        let decompressor = ctx.response_decompressor.as_mut().unwrap();
+        let fits = Compressor::new(6).encode(&[0; 100], true).unwrap();
+        assert_eq!(decompressor.encode(&fits, false).unwrap().len(), 100);
+        let e = decompressor.encode(&fits, true).unwrap_err();
+        assert_eq!(error_status(&e.into_up()), 502);
+    }
+
+    #[test]
+    fn max_request_body() {
+        let limited = proxy(&["--max-request-body", "10"]);
+        let mut ctx = limited.new_ctx();
+        limited.count_request_body(&mut ctx, 6).unwrap();
+        limited.count_request_body(&mut ctx, 4).unwrap();
+        let e = limited.count_request_body(&mut ctx, 1).unwrap_err();
+        assert_eq!(error_status(&e), 413);
+
+        // a retry counts the replayed body from zero
+        let mut ctx = limited.new_ctx();
+        for _ in 0..2 {
+            ctx.new_attempt();
+            let mut body = Some(Bytes::from_static(b"0123456789"));
+            limited
+                .transform_request_body(&mut ctx, &mut body, true)
+                .unwrap();
+        }
+
+        let unlimited = proxy(&[]);
+        let mut ctx = unlimited.new_ctx();
+        unlimited.count_request_body(&mut ctx, usize::MAX).unwrap();
+    }
+
+    #[test]
+    fn length_stash_header() {
+        let client_side = proxy(&["--length-stash-header", "X-Original-Length"]);
+        let server_side = proxy(&["--length-stash-header", "x-original-length"]);
+
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header(CONTENT_TYPE, "application/json").unwrap();
+        req.insert_header(CONTENT_LENGTH, "2048").unwrap();
+        req.insert_header("x-original-length", "1").unwrap();
+        let mut ctx = client_side.new_ctx();
+        client_side.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::Compress));
+        assert!(req.headers.get(CONTENT_LENGTH).is_none());
+        assert_eq!(req.headers.get_all("x-original-length").iter().count(), 1);
+        assert_eq!(req.headers["x-original-length"], "2048");
+        let compressed = req.clone();
+
+        let mut ctx = server_side.new_ctx();
+        server_side.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::Decompress));
+        assert_eq!(req.headers[CONTENT_LENGTH], "2048");
+        assert!(req.headers.get("x-original-length").is_none());
+        assert!(req.headers.get(TRANSFER_ENCODING).is_none());
+        assert!(req.headers.get(CONTENT_ENCODING).is_none());
+        assert_eq!(ctx.restored_length, Some(2048));
+
+        // the decoded body has to be as long as the stash said
+        server_side
+            .count_decoded_body(&mut ctx, 2000, false)
+            .unwrap();
+        assert!(server_side.count_decoded_body(&mut ctx, 0, true).is_err());
+
+        // a retry decodes the whole body again
+        let mut ctx = server_side.new_ctx();
+        for _ in 0..2 {
+            let mut retried = compressed.clone();
+            server_side
+                .request_transform(&mut retried, &mut ctx)
+                .unwrap();
+            assert_eq!(ctx.restored_length, Some(2048));
+            server_side
+                .count_decoded_body(&mut ctx, 2048, true)
+                .unwrap();
+        }
+        let mut ctx = server_side.new_ctx();
+        ctx.restored_length = Some(2048);
+        assert!(
+            server_side
+                .count_decoded_body(&mut ctx, 2049, false)
+                .is_err()
+        );
+        let mut ctx = server_side.new_ctx();
+        ctx.restored_length = Some(2048);
+        server_side
+            .count_decoded_body(&mut ctx, 2048, true)
+            .unwrap();
+
+        // without a stash the decoded length isn't known up front
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header(CONTENT_ENCODING, "gzip").unwrap();
+        req.insert_header(CONTENT_LENGTH, "300").unwrap();
+        let mut ctx = server_side.new_ctx();
+        server_side.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::Decompress));
+        assert!(req.headers.get(CONTENT_LENGTH).is_none());
+        assert_eq!(req.headers[TRANSFER_ENCODING], "chunked");
+        assert!(req.headers.get(CONTENT_ENCODING).is_none());
+        assert_eq!(ctx.restored_length, None);
+
+        // a client can't smuggle a length past a body that isn't compressed
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header(CONTENT_LENGTH, "10").unwrap();
+        req.insert_header("x-original-length", "99999").unwrap();
+        let mut ctx = client_side.new_ctx();
+        client_side.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::None));
+        assert!(req.headers.get("x-original-length").is_none());
+
+        assert!(
+            Config::try_parse_from([
+                "http-proxy",
+                "-t",
+                "127.0.0.1:80",
+                "--length-stash-header",
+                "not a header"
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn no_transform() {
+        let proxy = proxy(&["--no-transform", "-a", "gzip"]);
+        let mut ctx = proxy.new_ctx();
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header(CONTENT_TYPE, "application/json").unwrap();
+        req.insert_header(TRANSFER_ENCODING, "chunked").unwrap();
+        proxy.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::None));
+        assert!(req.headers.get(CONTENT_ENCODING).is_none());
+        assert_eq!(req.headers[TRANSFER_ENCODING], "chunked");
+
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header(CONTENT_ENCODING, "gzip").unwrap();
+        proxy.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::None));
+        assert_eq!(req.headers[CONTENT_ENCODING], "gzip");
+        assert!(req.headers.get(ACCEPT_ENCODING).is_none());
+
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header(ACCEPT_ENCODING, "gzip").unwrap();
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response.insert_header(CONTENT_LENGTH, "7").unwrap();
+        proxy
+            .response_transform(&req, &mut response, &mut ctx)
+            .unwrap();
+        assert!(matches!(ctx.response_op, Op::None));
+        assert!(response.headers.get(CONTENT_ENCODING).is_none());
+        assert!(response.headers.get(TRANSFER_ENCODING).is_none());
+        assert_eq!(response.headers[CONTENT_LENGTH], "7");
+    }
+
+    #[test]
+    fn compression_exemptions() {
+        let proxy = proxy(&[
+            "--min-compress-size",
+            "0",
+            "--no-compress-path",
+            "/upload",
+            "--no-compress-method",
+            "PUT",
+        ]);
+        let transformed = |method: &str, path: &str| {
+            let mut ctx = proxy.new_ctx();
+            let mut req = RequestHeader::build(method, path.as_bytes(), None).unwrap();
+            req.insert_header(CONTENT_TYPE, "application/json").unwrap();
+            req.insert_header(CONTENT_LENGTH, "10").unwrap();
+            proxy.request_transform(&mut req, &mut ctx).unwrap();
+            let request = matches!(ctx.op, Op::Compress);
+
+            req.insert_header(ACCEPT_ENCODING, "zstd").unwrap();
+            let mut response = ResponseHeader::build(200, None).unwrap();
+            proxy
+                .response_transform(&req, &mut response, &mut ctx)
+                .unwrap();
+            assert_eq!(request, matches!(ctx.response_op, Op::Compress));
+            request
+        };
+        assert!(transformed("POST", "/api"));
+        assert!(!transformed("POST", "/upload"));
+        assert!(!transformed("POST", "/uploads/a.json"));
+        assert!(!transformed("PUT", "/api"));
+    }
+
+    #[test]
+    fn range_requests() {
+        let ranged = proxy(&["--compression-off-for-range-requests"]);
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header(ACCEPT_ENCODING, "zstd").unwrap();
+        let transform = |proxy: &Proxy0, req: &RequestHeader, status| {
+            let mut ctx = proxy.new_ctx();
+            let mut response = ResponseHeader::build(status, None).unwrap();
+            response.insert_header(CONTENT_LENGTH, "10").unwrap();
+            proxy
+                .response_transform(req, &mut response, &mut ctx)
+                .unwrap();
+            matches!(ctx.response_op, Op::Compress)
+        };
+        assert!(transform(&ranged, &req, 200));
+        assert!(!transform(&ranged, &req, 206));
+        req.insert_header(RANGE, "bytes=0-9").unwrap();
+        assert!(!transform(&ranged, &req, 200));
+
+        // ranges are compressed like anything else without the flag
+        assert!(transform(&proxy(&[]), &req, 200));
+    }
+
+    #[test]
+    fn on_preencoded() {
+        let preencoded = || {
+            let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+            req.insert_header(CONTENT_ENCODING, "gzip").unwrap();
+            req.insert_header(CONTENT_LENGTH, "2048").unwrap();
+            req
+        };
+
+        // the peer side of a proxy pair decodes whatever the body is encoded in
+        let decompress = proxy(&[]);
+        let mut ctx = decompress.new_ctx();
+        let mut req = preencoded();
+        decompress.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::Decompress));
+        assert_eq!(ctx.decompressor.as_ref().unwrap().stat().0, "de-gzip");
+
+        let passthrough = proxy(&["--on-preencoded", "passthrough"]);
+        let mut ctx = passthrough.new_ctx();
+        let mut req = preencoded();
+        passthrough.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::None));
+        assert_eq!(req.headers[CONTENT_ENCODING], "gzip");
+        assert_eq!(req.headers[CONTENT_LENGTH], "2048");
+        assert!(req.headers.get(ACCEPT_ENCODING).is_none());
+
+        let transcode = proxy(&["--on-preencoded", "transcode"]);
+        let mut ctx = transcode.new_ctx();
+        let mut req = preencoded();
+        transcode.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::Transcode));
+        assert_eq!(req.headers[CONTENT_ENCODING], "zstd");
+        assert_eq!(req.headers[TRANSFER_ENCODING], "chunked");
+        assert!(req.headers.get(CONTENT_LENGTH).is_none());
+
+        let input = b"hello hello hello".repeat(16);
+        let gzipped = Compressor::new(6).encode(&input, true).unwrap();
+        let decoder = ctx.decompressor.as_mut().unwrap();
+        let plain = decoder.encode(&gzipped, true).unwrap();
+        let zstd = ctx
+            .compressor
+            .as_mut()
+            .unwrap()
+            .encode(&plain, true)
+            .unwrap();
+        let mut unzstd = ZstdDecompressor::new().unwrap();
+        assert_eq!(unzstd.encode(&zstd, true).unwrap(), input);
+
+        // already in the target algorithm
+        let mut ctx = transcode.new_ctx();
+        let mut req = preencoded();
+        req.insert_header(CONTENT_ENCODING, "zstd").unwrap();
+        transcode.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::None));
+    }
+
+    #[test]
+    fn sniffing() {
+        let input = b"sniff sniff sniff".repeat(10);
+        let gzip = Compressor::new(6).encode(&input, true).unwrap();
+        let zstd = ZstdCompressor::new(3)
+            .unwrap()
+            .encode(&input, true)
+            .unwrap();
+        let zlib = DeflateCompressor::new(6).encode(&input, true).unwrap();
+        let br = BrotliCompressor::new(5).encode(&input, true).unwrap();
+        assert_eq!(sniff_encoding(&gzip), Some(Algorithm::Gzip));
+        assert_eq!(sniff_encoding(&zstd), Some(Algorithm::Zstd));
+        assert_eq!(sniff_encoding(&zlib), Some(Algorithm::Deflate));
+        assert_eq!(sniff_encoding(&br), None);
+        assert_eq!(sniff_encoding(b"{}"), None);
+        assert_eq!(sniff_encoding(b""), None);
+
+        let proxy = proxy(&["--sniff-encoding"]);
+        let mut ctx = proxy.new_ctx();
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header(CONTENT_ENCODING, "zstd").unwrap();
+        proxy.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::Decompress));
+        assert!(ctx.decompressor.is_none());
+        assert_eq!(ctx.sniff, Some(Algorithm::Zstd));
+    }
+
+    #[test]
+    fn content_encoding() {
+        use BodyEncoding::*;
+        assert_eq!(
+            parse_content_encoding("gzip"),
+            Encoded(vec![Algorithm::Gzip])
+        );
+        assert_eq!(
+            parse_content_encoding(" ZSTD "),
+            Encoded(vec![Algorithm::Zstd])
+        );
+        assert_eq!(parse_content_encoding("identity"), Identity);
+        assert_eq!(parse_content_encoding(""), Identity);
+        assert_eq!(parse_content_encoding("compress"), Unsupported);
+        assert_eq!(
+            parse_content_encoding("gzip, br"),
+            Encoded(vec![Algorithm::Gzip, Algorithm::Brotli])
+        );
+        assert_eq!(
+            parse_content_encoding("gzip,identity, zstd"),
+            Encoded(vec![Algorithm::Gzip, Algorithm::Zstd])
+        );
+        assert_eq!(parse_content_encoding("gzip, compress"), Unsupported);
+
+        // an identity body is neither decoded nor compressed
+        let proxy = proxy(&[]);
+        let mut ctx = proxy.new_ctx();
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header(CONTENT_ENCODING, "identity").unwrap();
+        req.insert_header(CONTENT_LENGTH, "2048").unwrap();
+        proxy.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::None));
+        assert!(ctx.decompressor.is_none());
+        assert_eq!(req.headers[CONTENT_LENGTH], "2048");
+    }
+
+    #[test]
+    fn request_headers() {
+        let edited = proxy(&[
+            "--add-request-header",
+            "x-tenant:a",
+            "--remove-request-header",
+            "x-tenant",
+            "--remove-request-header",
+            "cookie",
+            "--add-request-header",
+            "content-type:application/json",
+        ]);
+        let mut ctx = edited.new_ctx();
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header("x-tenant", "b").unwrap();
+        req.insert_header("cookie", "c").unwrap();
+        req.insert_header(CONTENT_TYPE, "text/plain").unwrap();
+        req.insert_header(CONTENT_LENGTH, "2048").unwrap();
+        edited.request_transform(&mut req, &mut ctx).unwrap();
+        edited.edit_request_headers(&mut req, &ctx).unwrap();
+        // removed first, so the added one is kept
+        assert_eq!(req.headers["x-tenant"], "a");
+        assert!(req.headers.get("cookie").is_none());
+        assert_eq!(req.headers[CONTENT_TYPE], "application/json");
+        // compressed before the headers were edited
+        assert_eq!(req.headers[CONTENT_ENCODING], "zstd");
+    }
+
+    #[test]
+    fn response_headers() {
+        let edited = proxy(&[
+            "--add-response-header",
+            "strict-transport-security:max-age=63072000",
+            "--add-response-header",
+            "access-control-allow-origin:*",
+            "--remove-response-header",
+            "server",
+        ]);
+        let mut ctx = edited.new_ctx();
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header(ACCEPT_ENCODING, "zstd").unwrap();
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response.insert_header("server", "upstream").unwrap();
+        response.insert_header(CONTENT_LENGTH, "2048").unwrap();
+        edited
+            .response_transform(&req, &mut response, &mut ctx)
+            .unwrap();
+        edited.edit_response_headers(&mut response, &ctx).unwrap();
+        assert_eq!(
+            response.headers["strict-transport-security"],
+            "max-age=63072000"
+        );
+        assert_eq!(response.headers["access-control-allow-origin"], "*");
+        assert!(response.headers.get("server").is_none());
+        assert_eq!(response.headers[CONTENT_ENCODING], "zstd");
+    }
+
+    #[test]
+    fn websocket_upgrade() {
+        let upgrading = proxy(&[]);
+        let mut ctx = upgrading.new_ctx();
+        let mut req = RequestHeader::build("GET", b"/ws", None).unwrap();
+        req.insert_header(http::header::UPGRADE, "websocket")
+            .unwrap();
+        req.insert_header(http::header::CONNECTION, "keep-alive, Upgrade")
+            .unwrap();
+        req.insert_header(CONTENT_TYPE, "application/json").unwrap();
+        upgrading.request_transform(&mut req, &mut ctx).unwrap();
+        assert!(matches!(ctx.op, Op::None));
+        assert!(ctx.upgrade);
+        assert!(req.headers.get(TRANSFER_ENCODING).is_none());
+
+        // `Upgrade` alone doesn't ask for one
+        let mut req = RequestHeader::build("GET", b"/ws", None).unwrap();
+        req.insert_header(http::header::UPGRADE, "websocket")
+            .unwrap();
+        assert!(!is_upgrade(&req));
+    }
+}
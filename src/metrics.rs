@@ -0,0 +1,214 @@
+use async_trait::async_trait;
+use http::Response;
+use pingora::apps::http_app::ServeHttp;
+use pingora::protocols::http::ServerSession;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// `stat()` names of every codec, see `compress`
+const CODECS: [&str; 8] = [
+    "gzip",
+    "de-gzip",
+    "zstd",
+    "de-zstd",
+    "br",
+    "de-br",
+    "deflate",
+    "de-deflate",
+];
+
+/// Counters exported on `--metrics-port`
+#[derive(Default)]
+pub struct Metrics {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    bytes_in: [AtomicU64; CODECS.len()],
+    bytes_out: [AtomicU64; CODECS.len()],
+    /// Time spent in `encode`, in nanoseconds
+    encode_nanos: [AtomicU64; CODECS.len()],
+}
+
+impl Metrics {
+    pub fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add a finished body to the totals of codec `name`, as returned by `Encode::stat`
+    pub fn body(&self, name: &str, total_in: usize, total_out: usize, duration: Duration) {
+        if let Some(i) = CODECS.iter().position(|codec| *codec == name) {
+            self.bytes_in[i].fetch_add(total_in as u64, Ordering::Relaxed);
+            self.bytes_out[i].fetch_add(total_out as u64, Ordering::Relaxed);
+            let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+            self.encode_nanos[i].fetch_add(nanos, Ordering::Relaxed);
+        }
+    }
+
+    /// The codec totals as JSON, for `/stats`. The ratio is bytes out per byte in, `null`
+    /// until a codec saw any input.
+    pub fn render_json(&self) -> String {
+        let codecs: Vec<_> = CODECS
+            .iter()
+            .enumerate()
+            .map(|(i, codec)| {
+                let bytes_in = self.bytes_in[i].load(Ordering::Relaxed);
+                let bytes_out = self.bytes_out[i].load(Ordering::Relaxed);
+                let ratio = match bytes_in {
+                    0 => "null".to_string(),
+                    _ => format!("{:.4}", bytes_out as f64 / bytes_in as f64),
+                };
+                let seconds = self.encode_nanos[i].load(Ordering::Relaxed) as f64 / 1e9;
+                format!(
+                    "\"{codec}\":{{\"bytes_in\":{bytes_in},\"bytes_out\":{bytes_out},\
+                     \"ratio\":{ratio},\"encode_seconds\":{seconds:.6}}}"
+                )
+            })
+            .collect();
+        format!(
+            "{{\"requests\":{},\"errors\":{},\"codecs\":{{{}}}}}\n",
+            self.requests.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+            codecs.join(",")
+        )
+    }
+
+    /// The counters in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+        };
+        counter(&mut out, "http_proxy_requests_total", "Requests received.");
+        let _ = writeln!(
+            out,
+            "http_proxy_requests_total {}",
+            self.requests.load(Ordering::Relaxed)
+        );
+        counter(&mut out, "http_proxy_errors_total", "Requests that failed.");
+        let _ = writeln!(
+            out,
+            "http_proxy_errors_total {}",
+            self.errors.load(Ordering::Relaxed)
+        );
+        // the compression ratio is bytes_out / bytes_in
+        for (metric, totals, help) in [
+            (
+                "http_proxy_codec_bytes_in_total",
+                &self.bytes_in,
+                "Body bytes fed into a codec.",
+            ),
+            (
+                "http_proxy_codec_bytes_out_total",
+                &self.bytes_out,
+                "Body bytes produced by a codec.",
+            ),
+            (
+                "http_proxy_codec_encode_nanoseconds_total",
+                &self.encode_nanos,
+                "Time a codec spent encoding.",
+            ),
+        ] {
+            counter(&mut out, metric, help);
+            for (codec, total) in CODECS.iter().zip(totals) {
+                let (direction, algorithm) = match codec.strip_prefix("de-") {
+                    Some(algorithm) => ("decompress", algorithm),
+                    None => ("compress", *codec),
+                };
+                let _ = writeln!(
+                    out,
+                    "{metric}{{algorithm=\"{algorithm}\",direction=\"{direction}\"}} {}",
+                    total.load(Ordering::Relaxed)
+                );
+            }
+        }
+        out
+    }
+}
+
+/// Serves `/metrics` and `/stats` on the admin listener, and takes the proxy out of rotation
+/// and back with a `POST` to `/drain` and `/undrain`
+pub struct MetricsApp {
+    pub metrics: Arc<Metrics>,
+    /// Set while drained, the health path of the proxy answers 503 then
+    pub draining: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl ServeHttp for MetricsApp {
+    async fn response(&self, http_session: &mut ServerSession) -> Response<Vec<u8>> {
+        let req = http_session.req_header();
+        let post = req.method == http::Method::POST;
+        let (body, content_type) = match req.uri.path() {
+            "/metrics" => (self.metrics.render(), "text/plain; version=0.0.4"),
+            "/stats" => (self.metrics.render_json(), "application/json"),
+            "/drain" | "/undrain" if !post => {
+                return Response::builder()
+                    .status(405)
+                    .header(http::header::ALLOW, "POST")
+                    .body(vec![])
+                    .unwrap();
+            }
+            "/drain" => {
+                self.draining.store(true, Ordering::Relaxed);
+                ("draining\n".to_string(), "text/plain")
+            }
+            "/undrain" => {
+                self.draining.store(false, Ordering::Relaxed);
+                ("serving\n".to_string(), "text/plain")
+            }
+            _ => return Response::builder().status(404).body(vec![]).unwrap(),
+        };
+        let body = body.into_bytes();
+        Response::builder()
+            .status(200)
+            .header(http::header::CONTENT_TYPE, content_type)
+            .header(http::header::CONTENT_LENGTH, body.len())
+            .body(body)
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.request();
+        metrics.request();
+        metrics.error();
+        metrics.body("de-zstd", 10, 100, Duration::from_millis(2));
+        metrics.body("de-zstd", 5, 50, Duration::from_millis(1));
+        metrics.body("unknown", 1, 1, Duration::ZERO);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE http_proxy_requests_total counter\n"));
+        assert!(text.contains("\nhttp_proxy_requests_total 2\n"));
+        assert!(text.contains("\nhttp_proxy_errors_total 1\n"));
+        assert!(text.contains(
+            "\nhttp_proxy_codec_bytes_in_total{algorithm=\"zstd\",direction=\"decompress\"} 15\n"
+        ));
+        assert!(text.contains(
+            "\nhttp_proxy_codec_bytes_out_total{algorithm=\"zstd\",direction=\"decompress\"} 150\n"
+        ));
+        assert!(text.contains(
+            "\nhttp_proxy_codec_bytes_out_total{algorithm=\"gzip\",direction=\"compress\"} 0\n"
+        ));
+        assert!(text.contains(
+            "\nhttp_proxy_codec_encode_nanoseconds_total{algorithm=\"zstd\",direction=\"decompress\"} 3000000\n"
+        ));
+
+        let json = metrics.render_json();
+        assert!(json.starts_with("{\"requests\":2,\"errors\":1,\"codecs\":{\"gzip\":{"));
+        assert!(json.contains(
+            "\"de-zstd\":{\"bytes_in\":15,\"bytes_out\":150,\"ratio\":10.0000,\"encode_seconds\":0.003000}"
+        ));
+        assert!(json.contains("\"ratio\":null"));
+    }
+}
@@ -0,0 +1,109 @@
+use bytes::Bytes;
+use std::cell::RefCell;
+
+/// Capacities buffers are pooled by, bigger ones are freed as usual
+const CLASSES: [usize; 4] = [4 * 1024, 16 * 1024, 64 * 1024, 256 * 1024];
+/// Buffers kept per class, beyond that they are freed
+const MAX_FREE: usize = 32;
+
+thread_local! {
+    /// Free lists of codec output buffers by class, one per worker thread so taking and giving
+    /// back needs no locking
+    static FREE: RefCell<[Vec<Vec<u8>>; CLASSES.len()]> = RefCell::new(Default::default());
+}
+
+/// An empty buffer with room for at least `capacity` bytes, from the pool when there is one
+pub fn take(capacity: usize) -> Vec<u8> {
+    let Some(class) = CLASSES.iter().position(|size| *size >= capacity) else {
+        return Vec::with_capacity(capacity);
+    };
+    FREE.with_borrow_mut(|free| {
+        // any bigger class will do as well
+        free[class..]
+            .iter_mut()
+            .find_map(Vec::pop)
+            .unwrap_or_else(|| Vec::with_capacity(CLASSES[class]))
+    })
+}
+
+/// Return `buf` to the pool of this thread
+pub fn give(mut buf: Vec<u8>) {
+    // the largest class the buffer can serve
+    let Some(class) = CLASSES.iter().rposition(|size| *size <= buf.capacity()) else {
+        return;
+    };
+    if buf.capacity() > CLASSES[CLASSES.len() - 1] {
+        return;
+    }
+    buf.clear();
+    FREE.with_borrow_mut(|free| {
+        if free[class].len() < MAX_FREE {
+            free[class].push(buf);
+        }
+    });
+}
+
+/// A pooled buffer handed out as `Bytes`
+struct Pooled(Vec<u8>);
+
+impl AsRef<[u8]> for Pooled {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        give(std::mem::take(&mut self.0));
+    }
+}
+
+/// `buf` as `Bytes` without copying it. The buffer goes back to the pool of the thread that
+/// drops the last reference to it.
+pub fn freeze(buf: Vec<u8>) -> Bytes {
+    Bytes::from_owner(Pooled(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let mut buf = take(1000);
+        assert!(buf.capacity() >= 4 * 1024);
+        buf.extend_from_slice(b"data");
+        let ptr = buf.as_ptr();
+        give(buf);
+
+        let buf = take(3000);
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        // a bigger one has to be allocated
+        assert!(take(10 * 1024).capacity() >= 16 * 1024);
+
+        // too small and too big for any class
+        give(Vec::with_capacity(100));
+        give(Vec::with_capacity(1024 * 1024));
+        assert!(take(1024 * 1024).capacity() >= 1024 * 1024);
+    }
+
+    #[test]
+    fn frozen() {
+        let mut buf = take(1000);
+        buf.extend_from_slice(b"data");
+        let ptr = buf.as_ptr();
+        let bytes = freeze(buf);
+        assert_eq!(bytes.as_ptr(), ptr);
+        let slice = bytes.slice(1..);
+        drop(bytes);
+        // still in use
+        let other = take(1000);
+        assert_ne!(other.as_ptr(), ptr);
+        give(other);
+        assert_eq!(&slice[..], b"ata");
+        drop(slice);
+        let buf = take(1000);
+        assert_eq!(buf.as_ptr(), ptr);
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often buckets that filled up again are dropped
+const EVICT_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// The tokens at `now`, refilled at `rate` per second up to `burst`
+    fn tokens(&self, now: Instant, rate: f64, burst: f64) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * rate).min(burst)
+    }
+}
+
+struct Buckets {
+    by_ip: HashMap<IpAddr, Bucket>,
+    last_evict: Instant,
+}
+
+/// Token buckets of `--rate-limit`, one per client IP
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self {
+            buckets: Mutex::new(Buckets {
+                by_ip: HashMap::new(),
+                last_evict: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Take a token from the bucket of `ip`, `false` when it's empty. Buckets hold up to `burst`
+    /// tokens and refill at `rate` per second.
+    pub fn allow(&self, ip: IpAddr, now: Instant, rate: f64, burst: u32) -> bool {
+        let burst = f64::from(burst);
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.saturating_duration_since(buckets.last_evict) >= EVICT_INTERVAL {
+            // a full bucket is no different from a new one
+            buckets
+                .by_ip
+                .retain(|_, bucket| bucket.tokens(now, rate, burst) < burst);
+            buckets.last_evict = now;
+        }
+        let bucket = buckets.by_ip.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = bucket.tokens(now, rate, burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Clients with a bucket, full ones are only dropped every `EVICT_INTERVAL`
+    pub fn clients(&self) -> usize {
+        self.buckets.lock().unwrap().by_ip.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        let limiter = RateLimiter::default();
+        let a = IpAddr::from([10, 0, 0, 1]);
+        let b = IpAddr::from([10, 0, 0, 2]);
+        let start = Instant::now();
+        assert!((0..3).all(|_| limiter.allow(a, start, 2.0, 3)));
+        assert!(!limiter.allow(a, start, 2.0, 3));
+        assert!(limiter.allow(b, start, 2.0, 3));
+
+        // refilled at 2 per second
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.allow(a, later, 2.0, 3));
+        assert!(!limiter.allow(a, later, 2.0, 3));
+        assert_eq!(limiter.clients(), 2);
+
+        // both are full again by the time buckets are evicted, only the new one is kept
+        let much_later = start + EVICT_INTERVAL;
+        assert!(limiter.allow(IpAddr::from([10, 0, 0, 3]), much_later, 2.0, 3));
+        assert_eq!(limiter.clients(), 1);
+    }
+}
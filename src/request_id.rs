@@ -0,0 +1,54 @@
+/// Longest client supplied id that is passed on, longer ones are replaced
+const MAX_LEN: usize = 128;
+
+/// A random version 4 UUID
+pub fn generate() -> String {
+    let mut uuid = rand::random::<u128>();
+    // the version nibble is 4, the variant bits 10
+    uuid = (uuid & !(0xf << 76)) | (0x4 << 76);
+    uuid = (uuid & !(0x3 << 62)) | (0x2 << 62);
+    let hex = format!("{uuid:032x}");
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// The id a request is traced by: the one the client sent if it is usable, else a new one
+pub fn from_client(sent: Option<&[u8]>) -> String {
+    sent.and_then(|id| std::str::from_utf8(id).ok())
+        .map(str::trim)
+        .filter(|id| {
+            !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+        })
+        .map_or_else(generate, str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uuid() {
+        let id = generate();
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        assert!(matches!(&id[19..20], "8" | "9" | "a" | "b"), "{id}");
+        assert_eq!(id.matches('-').count(), 4);
+        assert_ne!(generate(), id);
+    }
+
+    #[test]
+    fn client_ids() {
+        assert_eq!(from_client(Some(b"abc-123")), "abc-123");
+        assert_eq!(from_client(Some(b" abc ")), "abc");
+        for bad in [&b""[..], b"a b", b"\xff", &[b'a'; 129]] {
+            assert_eq!(from_client(Some(bad)).len(), 36);
+        }
+        assert_eq!(from_client(None).len(), 36);
+    }
+}
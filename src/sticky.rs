@@ -0,0 +1,112 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Points each target gets on the ring per unit of weight
+const POINTS_PER_WEIGHT: u32 = 100;
+
+/// Consistent hashing over the targets of `--sticky`. A key keeps its target until that one
+/// goes away, and only the keys of a target that went away move, to the ones next on the ring.
+#[derive(Debug, Default)]
+pub struct Ring {
+    /// Hashes of the points and the targets they belong to, by hash
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    /// A ring over targets with `weights`, targets are referred to by their index
+    pub fn new(targets: &[(String, u32)]) -> Self {
+        let mut points: Vec<_> = targets
+            .iter()
+            .enumerate()
+            .flat_map(|(i, (target, weight))| {
+                (0..weight * POINTS_PER_WEIGHT).map(move |point| (hash((target, point)), i))
+            })
+            .collect();
+        points.sort_unstable();
+        Self { points }
+    }
+
+    /// The target of `key`, the first one on the ring from its hash on that is `usable`
+    pub fn get(&self, key: &str, usable: impl Fn(usize) -> bool) -> Option<usize> {
+        let start = self.points.partition_point(|(point, _)| *point < hash(key));
+        let (after, before) = self.points.split_at(start);
+        before
+            .iter()
+            .chain(after)
+            .map(|(_, target)| *target)
+            .find(|target| usable(*target))
+    }
+}
+
+fn hash(value: impl Hash) -> u64 {
+    // the keys are fixed, so the ring is the same in every worker and after a restart
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The value of the cookie `name` in `Cookie` headers
+pub fn cookie<'a>(headers: &'a http::HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.trim_matches('"'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(names: &[&str]) -> Vec<(String, u32)> {
+        names.iter().map(|name| (name.to_string(), 1)).collect()
+    }
+
+    #[test]
+    fn consistent() {
+        let ring = Ring::new(&targets(&["a:80", "b:80", "c:80"]));
+        let keys: Vec<_> = (0..300).map(|i| format!("10.0.0.{i}")).collect();
+        let before: Vec<_> = keys.iter().map(|key| ring.get(key, |_| true)).collect();
+        assert!((0..3).all(|target| before.contains(&Some(target))));
+        assert_eq!(
+            before,
+            keys.iter()
+                .map(|key| ring.get(key, |_| true))
+                .collect::<Vec<_>>()
+        );
+
+        // only the keys of b move
+        let after: Vec<_> = keys.iter().map(|key| ring.get(key, |t| t != 1)).collect();
+        for (before, after) in before.iter().zip(&after) {
+            match before {
+                Some(1) => assert_ne!(after, &Some(1)),
+                _ => assert_eq!(after, before),
+            }
+        }
+        assert_eq!(ring.get("10.0.0.1", |_| false), None);
+        assert_eq!(Ring::new(&[]).get("10.0.0.1", |_| true), None);
+    }
+
+    #[test]
+    fn weighted() {
+        let ring = Ring::new(&[("a:80".to_string(), 3), ("b:80".to_string(), 1)]);
+        let to_a = (0..1000)
+            .filter(|i| ring.get(&i.to_string(), |_| true) == Some(0))
+            .count();
+        assert!((650..850).contains(&to_a), "{to_a}");
+    }
+
+    #[test]
+    fn cookies() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(cookie(&headers, "affinity"), None);
+        headers.append(http::header::COOKIE, "a=1; affinity=\"x\"".parse().unwrap());
+        headers.append(http::header::COOKIE, "b=2".parse().unwrap());
+        assert_eq!(cookie(&headers, "affinity"), Some("x"));
+        assert_eq!(cookie(&headers, "b"), Some("2"));
+        assert_eq!(cookie(&headers, "c"), None);
+    }
+}
@@ -0,0 +1,150 @@
+use log::warn;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Header asking for the bodies of a request to be tapped
+pub const TAP_HEADER: &str = "x-tap";
+
+/// Whether a request asks to be tapped, with an `X-Tap` header or a `tap` query parameter
+pub fn wanted(headers: &http::HeaderMap, query: Option<&str>) -> bool {
+    headers.contains_key(TAP_HEADER)
+        || query.is_some_and(|query| {
+            query
+                .split('&')
+                .any(|param| param.split('=').next() == Some("tap"))
+        })
+}
+
+/// A name for the tap files of a request, unique within the process, sorting by time and
+/// ending in the request id
+pub fn next_id(request_id: &str) -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis());
+    // clients choose the id, it mustn't lead out of the directory
+    let request_id: String = request_id
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    format!(
+        "{millis}-{}-{request_id}",
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// A body being copied to a file of `--tap-dir`. Failing to write only ends the copy, the
+/// proxied body is never affected.
+pub struct Tap {
+    file: Option<File>,
+    /// Bytes written by all taps, shared so together they stay within `limit`
+    written: Arc<AtomicUsize>,
+    limit: usize,
+    /// Body bytes handed to `write` so far
+    offered: usize,
+    /// Where in the body the attempt being written is, behind `offered` while a retry replays it
+    position: usize,
+}
+
+impl Tap {
+    pub fn create(dir: &Path, name: &str, written: Arc<AtomicUsize>, limit: usize) -> Self {
+        let path = dir.join(name);
+        let file = File::create(&path)
+            .inspect_err(|e| warn!("not tapping to {}: {e}", path.display()))
+            .ok();
+        Self {
+            file,
+            written,
+            limit,
+            offered: 0,
+            position: 0,
+        }
+    }
+
+    /// Append `data`, as much of it as the limit leaves room for
+    pub fn write(&mut self, data: &[u8]) {
+        // the part a replayed body repeats is in the file already
+        let repeated = self.offered.saturating_sub(self.position).min(data.len());
+        self.position += data.len();
+        self.offered = self.offered.max(self.position);
+        let data = &data[repeated..];
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let Ok(before) =
+            self.written
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |written| {
+                    (written < self.limit).then(|| (written + data.len()).min(self.limit))
+                })
+        else {
+            // the rest would be cut off anyway
+            self.file = None;
+            return;
+        };
+        let len = data.len().min(self.limit - before);
+        if let Err(e) = file.write_all(&data[..len]) {
+            warn!("stopped tapping: {e}");
+            self.file = None;
+        }
+    }
+
+    /// Start the body over for a retry, which sends it again from its first byte
+    pub fn replay(&mut self) {
+        self.position = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wanted_by() {
+        let mut headers = http::HeaderMap::new();
+        assert!(!wanted(&headers, None));
+        assert!(wanted(&headers, Some("a=1&tap")));
+        assert!(wanted(&headers, Some("tap=1")));
+        assert!(!wanted(&headers, Some("tapped=1&a=tap")));
+        headers.insert(TAP_HEADER, "1".parse().unwrap());
+        assert!(wanted(&headers, None));
+    }
+
+    #[test]
+    fn limit() {
+        let dir = std::env::temp_dir().join(format!("http-proxy-tap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let written = Arc::new(AtomicUsize::new(0));
+        let mut a = Tap::create(&dir, "a", written.clone(), 10);
+        let mut b = Tap::create(&dir, "b", written.clone(), 10);
+        a.write(b"123456");
+        b.write(b"abcdef");
+        a.write(b"789");
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"123456");
+        assert_eq!(std::fs::read(dir.join("b")).unwrap(), b"abcd");
+        assert_eq!(written.load(Ordering::Relaxed), 10);
+
+        // a retry only adds what the first attempt didn't get to
+        let written = Arc::new(AtomicUsize::new(0));
+        let mut retried = Tap::create(&dir, "retried", written.clone(), 100);
+        retried.write(b"abc");
+        retried.replay();
+        retried.write(b"ab");
+        retried.write(b"cdef");
+        assert_eq!(std::fs::read(dir.join("retried")).unwrap(), b"abcdef");
+        assert_eq!(written.load(Ordering::Relaxed), 6);
+
+        // nowhere to write to
+        let mut c = Tap::create(&dir.join("missing"), "c", written, 10);
+        c.write(b"x");
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_ne!(next_id("a"), next_id("a"));
+        assert!(next_id("../../etc/passwd").ends_with("-______etc_passwd"));
+    }
+}
@@ -0,0 +1,34 @@
+mod common;
+
+use common::{Proxy, upstream};
+use std::time::Duration;
+
+#[test]
+fn credentials_required() {
+    let (target, received) = upstream();
+    let proxy = Proxy::start(&["-t", &target, "--basic-auth", "alice:secret"]);
+
+    let response = proxy.request(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 401"), "{response}");
+    assert!(
+        response
+            .to_ascii_lowercase()
+            .contains("www-authenticate: basic"),
+        "{response}"
+    );
+    // alice:wrong
+    let response = proxy.request(
+        b"GET / HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic YWxpY2U6d3Jvbmc=\r\n\
+          Connection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 401"), "{response}");
+    assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+
+    let response = proxy.request(
+        b"GET / HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic YWxpY2U6c2VjcmV0\r\n\
+          Connection: close\r\n\r\n",
+    );
+    assert!(response.ends_with("ok"), "{response}");
+    let (head, _) = received.recv().unwrap();
+    assert!(!head.contains("authorization"), "{head}");
+}
@@ -0,0 +1,74 @@
+mod common;
+
+use common::{Proxy, upstream};
+
+fn post(proxy: &Proxy, framing: &str, body: &[u8]) -> String {
+    let mut request = format!(
+        "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\n\
+         {framing}\r\nConnection: close\r\n\r\n"
+    )
+    .into_bytes();
+    request.extend_from_slice(body);
+    proxy.request(&request)
+}
+
+/// `body` as a single chunk and the last one
+fn chunked(body: &[u8]) -> Vec<u8> {
+    let mut chunked = format!("{:x}\r\n", body.len()).into_bytes();
+    chunked.extend_from_slice(body);
+    chunked.extend_from_slice(b"\r\n0\r\n\r\n");
+    chunked
+}
+
+#[test]
+fn buffered_bodies_have_a_length() {
+    let (target, received) = upstream();
+    let proxy = Proxy::start(&["-t", &target, "--buffer-request-body"]);
+
+    let body = b"buffered whole ".repeat(1_000);
+    let length = format!("Content-Length: {}", body.len());
+    for (framing, sent) in [
+        (length.as_str(), body.clone()),
+        ("Transfer-Encoding: chunked", chunked(&body)),
+    ] {
+        assert!(post(&proxy, framing, &sent).ends_with("ok"));
+        let (head, forwarded) = received.recv().unwrap();
+        assert!(head.contains("content-encoding: zstd"), "{head}");
+        assert!(!head.contains("transfer-encoding"), "{head}");
+        let length = format!("content-length: {}\r\n", forwarded.len());
+        assert!(head.contains(&length), "{head}");
+        assert_eq!(zstd::decode_all(&forwarded[..]).unwrap(), body);
+    }
+
+    // too long for pingora to keep, these are turned away rather than sent on chunked
+    let body = b"longer than the buffer ".repeat(4_000);
+    let length = format!("Content-Length: {}", body.len());
+    for (framing, sent) in [
+        (length.as_str(), body.clone()),
+        ("Transfer-Encoding: chunked", chunked(&body)),
+    ] {
+        let response = post(&proxy, framing, &sent);
+        assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+    }
+    assert!(received.try_recv().is_err());
+
+    // the limit holds while buffering
+    let proxy = Proxy::start(&[
+        "-t",
+        &target,
+        "--buffer-request-body",
+        "--max-request-body",
+        "50000",
+    ]);
+    let body = b"over the limit ".repeat(4_000);
+    let response = post(
+        &proxy,
+        "Transfer-Encoding: chunked",
+        &chunked(&body[..40_000]),
+    );
+    assert!(response.ends_with("ok"), "{response}");
+    received.recv().unwrap();
+    let response = post(&proxy, "Transfer-Encoding: chunked", &chunked(&body));
+    assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+    assert!(received.try_recv().is_err());
+}
@@ -0,0 +1,103 @@
+mod common;
+
+use common::{Proxy, upstream};
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::time::Duration;
+
+#[test]
+fn repeated_gets() {
+    let (target, received) = upstream();
+    let proxy = Proxy::start(&["-t", &target, "--cache-size", "65536"]);
+    let get = |headers: &str| {
+        let request =
+            format!("GET /a HTTP/1.1\r\nHost: localhost\r\n{headers}Connection: close\r\n\r\n");
+        String::from_utf8_lossy(&proxy.request_bytes(request.as_bytes())).to_ascii_lowercase()
+    };
+    let fetched = || received.recv_timeout(Duration::from_millis(500)).is_ok();
+
+    let first = get("Accept-Encoding: zstd\r\n");
+    assert!(first.contains("content-encoding: zstd"), "{first}");
+    assert!(fetched());
+    // kept compressed, with a length instead of chunks
+    let hit = get("Accept-Encoding: zstd\r\n");
+    assert!(hit.contains("content-encoding: zstd"), "{hit}");
+    assert!(hit.contains("content-length: "), "{hit}");
+    assert!(!fetched());
+
+    // not for a client that can't decode it, that one is fetched and cached plain
+    let plain = get("");
+    assert!(plain.ends_with("ok"), "{plain}");
+    assert!(fetched());
+    assert!(get("").ends_with("ok"));
+    assert!(!fetched());
+
+    // the client opts out
+    assert!(get("Cache-Control: no-store\r\n").ends_with("ok"));
+    assert!(fetched());
+}
+
+/// An upstream answering `GET /<path>` with `ok` and the headers named by the path, sending the
+/// path over the channel
+fn personal_upstream() -> (String, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let request = String::from_utf8_lossy(&request);
+            let path = request.split(' ').nth(1).unwrap_or_default().to_string();
+            let headers = match path.as_str() {
+                "/cookie" => "Set-Cookie: session=secret\r\n",
+                "/private" => "Cache-Control: private, max-age=60\r\n",
+                "/vary" => "Vary: Accept-Encoding, Authorization\r\n",
+                _ => "Vary: Accept-Encoding\r\n",
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n{headers}Content-Length: 2\r\nConnection: close\r\n\r\nok"
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+            tx.send(path).unwrap();
+        }
+    });
+    (addr, rx)
+}
+
+#[test]
+fn personal_responses() {
+    let (target, received) = personal_upstream();
+    let proxy = Proxy::start(&["-t", &target, "--cache-size", "65536", "--sticky", "cookie"]);
+    let get = |path: &str| {
+        let request =
+            format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        String::from_utf8_lossy(&proxy.request_bytes(request.as_bytes())).to_ascii_lowercase()
+    };
+    let fetched = || received.recv_timeout(Duration::from_millis(500)).is_ok();
+
+    // never handed to another client
+    for path in ["/cookie", "/private", "/vary"] {
+        for _ in 0..2 {
+            assert!(get(path).ends_with("ok"), "{path}");
+            assert!(fetched(), "{path}");
+        }
+    }
+
+    // the affinity cookie the proxy gives the first client stays with that client
+    let first = get("/shared");
+    assert!(first.contains("set-cookie: "), "{first}");
+    assert!(fetched());
+    let hit = get("/shared");
+    assert!(hit.ends_with("ok"), "{hit}");
+    assert!(!fetched());
+    assert!(!hit.contains("set-cookie: "), "{hit}");
+}
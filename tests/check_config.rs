@@ -0,0 +1,46 @@
+use std::net::TcpListener;
+use std::process::Command;
+
+#[test]
+fn exits_without_listening() {
+    // the port it would listen on stays free
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port().to_string();
+    drop(listener);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_http-proxy"))
+        .args(["-t", "127.0.0.1:80", "-p", &port, "--check-config"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let summary = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        summary.contains(&format!("listen: 0.0.0.0:{port}\n")),
+        "{summary}"
+    );
+    assert!(TcpListener::bind(("127.0.0.1", port.parse::<u16>().unwrap())).is_ok());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_http-proxy"))
+        .args(["-t", "127.0.0.1", "--check-config"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let error = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        error.starts_with("invalid configuration: upstream 127.0.0.1: "),
+        "{error}"
+    );
+
+    // pingora has no TLS backend in this build to serve it with
+    let output = Command::new(env!("CARGO_BIN_EXE_http-proxy"))
+        .args(["-t", "127.0.0.1:80", "--check-config"])
+        .args(["--tls-cert", "cert.pem", "--tls-key", "key.pem"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let error = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        error.contains("--tls-cert needs pingora built with a TLS backend"),
+        "{error}"
+    );
+}
@@ -0,0 +1,157 @@
+// every test binary compiles this, each using only part of it
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, ExitStatus};
+use std::sync::{Mutex, mpsc};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// A running proxy binary, killed when the test is done however it ends
+pub struct Proxy {
+    child: Child,
+    pub port: u16,
+}
+
+impl Proxy {
+    pub fn start(args: &[&str]) -> Self {
+        let port = free_port();
+        let child = Command::new(env!("CARGO_BIN_EXE_http-proxy"))
+            .args(args)
+            .args(["-p", &port.to_string()])
+            .spawn()
+            .unwrap();
+        let proxy = Self { child, port };
+        let start = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "proxy didn't come up"
+            );
+            sleep(Duration::from_millis(50));
+        }
+        proxy
+    }
+
+    /// Send a raw HTTP/1.1 request and read the response until the proxy closes the connection
+    pub fn request(&self, request: &[u8]) -> String {
+        String::from_utf8(self.request_bytes(request)).unwrap()
+    }
+
+    /// `request` for a response whose body may not be text, like a compressed one
+    pub fn request_bytes(&self, request: &[u8]) -> Vec<u8> {
+        let mut client = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        client.write_all(request).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    /// Stop the proxy the way Ctrl-C does and wait for it to exit
+    pub fn interrupt(self) {
+        self.signal("INT");
+        self.wait();
+    }
+
+    /// Send the proxy a signal like `TERM`
+    pub fn signal(&self, signal: &str) {
+        let status = Command::new("kill")
+            .args([&format!("-{signal}"), &self.child.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    /// Wait for the proxy to exit on its own
+    pub fn wait(mut self) -> ExitStatus {
+        let start = Instant::now();
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "proxy didn't exit"
+            );
+            sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A port nothing listens on, never the same one twice so a proxy can't end up as its own target
+pub fn free_port() -> u16 {
+    static HANDED_OUT: Mutex<Vec<u16>> = Mutex::new(Vec::new());
+    let mut handed_out = HANDED_OUT.lock().unwrap();
+    loop {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        if !handed_out.contains(&port) {
+            handed_out.push(port);
+            return port;
+        }
+    }
+}
+
+/// An upstream answering every request with `ok`, sending its lowercased head and its body over
+/// the channel
+pub fn upstream() -> (String, mpsc::Receiver<(String, Vec<u8>)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        'connections: for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 64 * 1024];
+            let head_end = loop {
+                if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break end + 4;
+                }
+                let n = stream.read(&mut buf).unwrap();
+                // the proxy connected but gave up on the request before sending it
+                if n == 0 {
+                    continue 'connections;
+                }
+                request.extend_from_slice(&buf[..n]);
+            };
+            let head = String::from_utf8(request[..head_end].to_vec())
+                .unwrap()
+                .to_ascii_lowercase();
+            let mut body = request.split_off(head_end);
+            let content_length = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .map(|cl| cl.parse::<usize>().unwrap());
+            let chunked = head.contains("transfer-encoding: chunked");
+            let done = |body: &[u8]| match content_length {
+                Some(cl) => body.len() >= cl,
+                None if chunked => body.ends_with(b"0\r\n\r\n"),
+                None => true,
+            };
+            while !done(&body) {
+                let n = stream.read(&mut buf).unwrap();
+                assert!(n > 0, "request cut off");
+                body.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                .unwrap();
+            tx.send((head, body)).unwrap();
+        }
+    });
+    (addr, rx)
+}
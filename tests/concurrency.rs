@@ -0,0 +1,66 @@
+mod common;
+
+use common::Proxy;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+#[test]
+fn over_the_limit() {
+    // holds the first request until told to answer it
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = listener.local_addr().unwrap().to_string();
+    let (arrived_tx, arrived) = mpsc::channel();
+    let (answer_tx, answer) = mpsc::channel::<()>();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            arrived_tx.send(()).unwrap();
+            answer.recv().unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                .unwrap();
+        }
+    });
+    let proxy = Proxy::start(&["-t", &target, "--max-concurrent-requests", "1"]);
+    let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+    let mut first = TcpStream::connect(("127.0.0.1", proxy.port)).unwrap();
+    first
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    first.write_all(request).unwrap();
+    arrived.recv_timeout(Duration::from_secs(10)).unwrap();
+
+    let rejected = proxy.request(request);
+    assert!(rejected.starts_with("HTTP/1.1 503"), "{rejected}");
+    assert!(
+        rejected.to_ascii_lowercase().contains("retry-after: 1\r\n"),
+        "{rejected}"
+    );
+
+    answer_tx.send(()).unwrap();
+    let mut response = String::new();
+    first.read_to_string(&mut response).unwrap();
+    assert!(response.ends_with("ok"), "{response}");
+
+    // the slot is free again, once the first request is done with it
+    answer_tx.send(()).unwrap();
+    let start = Instant::now();
+    loop {
+        let response = proxy.request(request);
+        if response.ends_with("ok") {
+            break;
+        }
+        assert!(start.elapsed() < Duration::from_secs(5), "{response}");
+        sleep(Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use common::{Proxy, upstream};
+use std::time::Duration;
+
+#[test]
+fn preflight_answered_by_proxy() {
+    let (target, received) = upstream();
+    let proxy = Proxy::start(&["-t", &target, "--cors-allow-origin", "*"]);
+
+    let response = proxy.request(
+        b"OPTIONS /items HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example.com\r\n\
+          Access-Control-Request-Method: POST\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 204"), "{response}");
+    let response = response.to_ascii_lowercase();
+    assert!(
+        response.contains("access-control-allow-origin: *"),
+        "{response}"
+    );
+    assert!(
+        response.contains("access-control-allow-methods: get, head, post, put, patch, delete"),
+        "{response}"
+    );
+    assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+
+    let response = proxy.request(
+        b"GET /items HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example.com\r\n\
+          Connection: close\r\n\r\n",
+    );
+    assert!(response.ends_with("ok"), "{response}");
+    assert!(
+        response
+            .to_ascii_lowercase()
+            .contains("access-control-allow-origin: *"),
+        "{response}"
+    );
+    received.recv().unwrap();
+}
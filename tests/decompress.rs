@@ -0,0 +1,108 @@
+mod common;
+
+use common::{Proxy, upstream};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::io::Write;
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn post(proxy: &Proxy, headers: &str, body: &[u8]) -> String {
+    let mut request = format!(
+        "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Encoding: gzip\r\n\
+         Content-Length: {}\r\n{headers}Connection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(body);
+    proxy.request(&request)
+}
+
+#[test]
+fn decoded_length_is_consistent() {
+    let (target, received) = upstream();
+    let proxy = Proxy::start(&["-t", &target]);
+    let body = b"decompressed ".repeat(500);
+    let compressed = gzip(&body);
+
+    // the compressed length would be wrong for what goes upstream
+    assert!(post(&proxy, "", &compressed).ends_with("ok"));
+    let (head, forwarded) = received.recv().unwrap();
+    assert!(!head.contains("content-length"), "{head}");
+    assert!(!head.contains("content-encoding"), "{head}");
+    assert!(head.contains("transfer-encoding: chunked"), "{head}");
+    assert!(forwarded.ends_with(b"0\r\n\r\n"));
+
+    // the peer proxy stashed the original length
+    let stash = format!("x-crd-content-length: {}\r\n", body.len());
+    assert!(post(&proxy, &stash, &compressed).ends_with("ok"));
+    let (head, forwarded) = received.recv().unwrap();
+    assert!(
+        head.contains(&format!("content-length: {}\r\n", body.len())),
+        "{head}"
+    );
+    assert!(!head.contains("transfer-encoding"), "{head}");
+    assert!(!head.contains("content-encoding"), "{head}");
+    assert_eq!(forwarded, body);
+
+    // a stash that doesn't match fails the request rather than the upstream framing
+    let stash = format!("x-crd-content-length: {}\r\n", body.len() + 1);
+    let response = post(&proxy, &stash, &compressed);
+    assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+}
+
+#[test]
+fn sniffed_encoding() {
+    let (target, received) = upstream();
+    let proxy = Proxy::start(&["-t", &target, "--sniff-encoding"]);
+    let body = b"mislabeled ".repeat(500);
+
+    // gzip sent as zstd
+    let mut request = b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Encoding: zstd\r\n\
+        Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n"
+        .to_vec();
+    // the magic bytes come in two chunks
+    let compressed = gzip(&body);
+    for chunk in [&compressed[..1], &compressed[1..]] {
+        request.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+        request.extend_from_slice(chunk);
+        request.extend_from_slice(b"\r\n");
+    }
+    request.extend_from_slice(b"0\r\n\r\n");
+    let response = proxy.request(&request);
+    assert!(response.ends_with("ok"), "{response}");
+    let (head, forwarded) = received.recv().unwrap();
+    assert!(!head.contains("content-encoding"), "{head}");
+    let decoded = String::from_utf8_lossy(&forwarded);
+    assert_eq!(decoded.matches("mislabeled ").count(), 500);
+}
+
+#[test]
+fn layered_encodings() {
+    let (target, received) = upstream();
+    let proxy = Proxy::start(&["-t", &target]);
+    let body = b"gzip, then zstd ".repeat(500);
+    let layered = zstd::encode_all(&gzip(&body)[..], 3).unwrap();
+
+    let mut request = format!(
+        "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Encoding: gzip, zstd\r\n\
+         Content-Length: {}\r\nX-Crd-Content-Length: {}\r\nConnection: close\r\n\r\n",
+        layered.len(),
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(&layered);
+    let response = proxy.request(&request);
+    assert!(response.ends_with("ok"), "{response}");
+    let (head, forwarded) = received.recv().unwrap();
+    assert!(!head.contains("content-encoding"), "{head}");
+    assert!(
+        head.contains(&format!("content-length: {}", body.len())),
+        "{head}"
+    );
+    assert_eq!(forwarded, body);
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use common::{Proxy, free_port, upstream};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Send `request` to the admin listener on `port`, waiting for it to come up
+fn admin(port: u16, request: &str) -> String {
+    admin_at("127.0.0.1", port, request)
+}
+
+fn admin_at(ip: &str, port: u16, request: &str) -> String {
+    let start = Instant::now();
+    let mut client = loop {
+        match TcpStream::connect((ip, port)) {
+            Ok(client) => break client,
+            Err(e) => assert!(start.elapsed() < Duration::from_secs(10), "{e}"),
+        }
+        sleep(Duration::from_millis(50));
+    };
+    client.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn drain_fails_health_checks() {
+    let (target, received) = upstream();
+    let admin_port = free_port();
+    let proxy = Proxy::start(&["-t", &target, "--metrics-port", &admin_port.to_string()]);
+    let health =
+        || proxy.request(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(health().starts_with("HTTP/1.1 200"));
+
+    let request = |method: &str, path: &str| {
+        format!(
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )
+    };
+    let response = admin(admin_port, &request("GET", "/drain"));
+    assert!(response.starts_with("HTTP/1.1 405"), "{response}");
+    assert!(health().starts_with("HTTP/1.1 200"));
+
+    let response = admin(admin_port, &request("POST", "/drain"));
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    let response = health();
+    assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+    // requests are still served
+    let response = proxy.request(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(response.ends_with("ok"), "{response}");
+    received.recv().unwrap();
+
+    let response = admin(admin_port, &request("POST", "/undrain"));
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(health().starts_with("HTTP/1.1 200"));
+}
+
+#[test]
+fn admin_listens_on_loopback() {
+    let (target, _received) = upstream();
+    let stats = "GET /stats HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+    // not on every interface, though the proxy itself is
+    let port = free_port();
+    let _proxy = Proxy::start(&["-t", &target, "--metrics-port", &port.to_string()]);
+    assert!(admin(port, stats).starts_with("HTTP/1.1 200"));
+    assert!(TcpStream::connect(("127.0.0.2", port)).is_err());
+
+    let port = free_port();
+    let _proxy = Proxy::start(&[
+        "-t",
+        &target,
+        "--metrics-port",
+        &port.to_string(),
+        "--metrics-bind",
+        "127.0.0.2",
+    ]);
+    assert!(admin_at("127.0.0.2", port, stats).starts_with("HTTP/1.1 200"));
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use common::{Proxy, upstream};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixListener;
+use std::sync::mpsc;
+
+/// A CONNECT proxy on a Unix socket at `path`, sending the head of each CONNECT over the
+/// channel before tunneling to the host it names
+fn egress_proxy(path: &std::path::Path) -> mpsc::Receiver<String> {
+    let listener = UnixListener::bind(path).unwrap();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut client = stream.unwrap();
+            let mut head = Vec::new();
+            let mut byte = [0];
+            while !head.ends_with(b"\r\n\r\n") {
+                client.read_exact(&mut byte).unwrap();
+                head.push(byte[0]);
+            }
+            let head = String::from_utf8(head).unwrap();
+            let authority = head.split(' ').nth(1).unwrap().to_string();
+            tx.send(head).unwrap();
+            let mut upstream = TcpStream::connect(authority).unwrap();
+            client
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .unwrap();
+            let (mut client_read, mut upstream_write) =
+                (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+            std::thread::spawn(move || std::io::copy(&mut client_read, &mut upstream_write));
+            std::thread::spawn(move || std::io::copy(&mut upstream, &mut client));
+        }
+    });
+    rx
+}
+
+#[test]
+fn tunneled_upstream() {
+    let path = std::env::temp_dir().join(format!("http-proxy-egress-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let connects = egress_proxy(&path);
+    let (target, received) = upstream();
+    let proxy = Proxy::start(&[
+        "-t",
+        &target,
+        "--egress-proxy",
+        path.to_str().unwrap(),
+        "--egress-proxy-auth",
+        "alice:secret",
+        "-a",
+        "gzip",
+    ]);
+
+    let body = b"{\"tunneled\": true} ".repeat(100);
+    let mut request = format!(
+        "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(&body);
+    let response = proxy.request(&request);
+    assert!(response.ends_with("ok"), "{response}");
+
+    let connect = connects.recv().unwrap();
+    assert!(
+        connect.starts_with(&format!("CONNECT {target} HTTP/1.1\r\n")),
+        "{connect}"
+    );
+    // alice:secret
+    assert!(
+        connect
+            .to_ascii_lowercase()
+            .contains("proxy-authorization: basic ywxpy2u6c2vjcmv0\r\n"),
+        "{connect}"
+    );
+    let (head, forwarded) = received.recv().unwrap();
+    assert!(head.contains("content-encoding: gzip"), "{head}");
+    assert!(forwarded.len() < body.len());
+    let _ = std::fs::remove_file(&path);
+}
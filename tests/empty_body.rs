@@ -0,0 +1,19 @@
+mod common;
+
+use common::{Proxy, upstream};
+
+#[test]
+fn get_arrives_unencoded() {
+    let (target, received) = upstream();
+    let proxy = Proxy::start(&["-t", &target, "--min-compress-size", "0"]);
+
+    let response = proxy.request(
+        b"GET /items HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+          Connection: close\r\n\r\n",
+    );
+    assert!(response.ends_with("ok"), "{response}");
+    let (head, body) = received.recv().unwrap();
+    assert!(!head.contains("content-encoding"), "{head}");
+    assert!(!head.contains("transfer-encoding"), "{head}");
+    assert!(body.is_empty());
+}
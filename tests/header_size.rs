@@ -0,0 +1,22 @@
+mod common;
+
+use common::{Proxy, upstream};
+use std::time::Duration;
+
+#[test]
+fn oversize_headers_rejected() {
+    let (target, received) = upstream();
+    let proxy = Proxy::start(&["-t", &target, "--max-header-size", "200"]);
+
+    let response = proxy.request(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(response.ends_with("ok"), "{response}");
+    received.recv().unwrap();
+
+    let request = format!(
+        "GET / HTTP/1.1\r\nHost: localhost\r\nCookie: {}\r\nConnection: close\r\n\r\n",
+        "a".repeat(200)
+    );
+    let response = proxy.request(request.as_bytes());
+    assert!(response.starts_with("HTTP/1.1 431"), "{response}");
+    assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+}
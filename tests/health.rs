@@ -0,0 +1,20 @@
+mod common;
+
+use common::{Proxy, free_port};
+
+#[test]
+fn health_check_skips_upstream() {
+    // nothing listens on the target
+    let target = format!("127.0.0.1:{}", free_port());
+    let proxy = Proxy::start(&["-t", &target, "--health-path", "/ready"]);
+
+    let response =
+        proxy.request(b"GET /ready HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(response.ends_with("\r\n\r\nok\n"), "{response}");
+    assert!(!response.to_ascii_lowercase().contains("content-encoding"));
+
+    let response =
+        proxy.request(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 502"), "{response}");
+}
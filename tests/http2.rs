@@ -0,0 +1,75 @@
+mod common;
+
+use bytes::Bytes;
+use common::Proxy;
+use std::sync::mpsc;
+
+/// An h2c upstream answering every request with `ok`, sending the request headers and body it
+/// got over the channel
+fn upstream() -> (String, mpsc::Receiver<(http::HeaderMap, Vec<u8>)>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut connection = h2::server::handshake(stream).await.unwrap();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    while let Some(Ok((request, mut respond))) = connection.accept().await {
+                        let tx = tx.clone();
+                        tokio::spawn(async move {
+                            let (parts, mut body) = request.into_parts();
+                            let mut received = Vec::new();
+                            while let Some(data) = body.data().await {
+                                let data = data.unwrap();
+                                let _ = body.flow_control().release_capacity(data.len());
+                                received.extend_from_slice(&data);
+                            }
+                            let response = http::Response::builder()
+                                .status(200)
+                                .header("content-length", "2")
+                                .body(())
+                                .unwrap();
+                            let mut send = respond.send_response(response, false).unwrap();
+                            send.send_data(Bytes::from_static(b"ok"), true).unwrap();
+                            tx.send((parts.headers, received)).unwrap();
+                        });
+                    }
+                });
+            }
+        });
+    });
+    (addr, rx)
+}
+
+#[test]
+fn compressed_body_over_h2c() {
+    let (target, received) = upstream();
+    let proxy = Proxy::start(&["-t", &target, "--upstream-http2"]);
+
+    let body = "hello http/2 ".repeat(200);
+    let response = proxy.request(
+        format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+        .as_bytes(),
+    );
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(response.ends_with("ok"), "{response}");
+
+    let (headers, compressed) = received.recv().unwrap();
+    // the chunked coding is HTTP/1 only, DATA frames delimit the body
+    assert!(headers.get("transfer-encoding").is_none());
+    assert_eq!(headers["content-encoding"], "zstd");
+    assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), body.as_bytes());
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use common::Proxy;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// A keep-alive upstream answering bodyless requests with `ok`, counting the connections it got
+fn upstream() -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            std::thread::spawn(move || {
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                loop {
+                    while let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        request.drain(..end + 4);
+                        stream
+                            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                            .unwrap();
+                    }
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+            });
+        }
+    });
+    (addr, connections)
+}
+
+fn get_three_times(args: &[&str], pause: Duration) -> usize {
+    let (target, connections) = upstream();
+    let proxy = Proxy::start(&[&["-t", &target][..], args].concat());
+    // a client asking for `Connection: close` would have the upstream connection closed too
+    for _ in 0..3 {
+        let mut client = TcpStream::connect(("127.0.0.1", proxy.port)).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        let mut buf = [0; 4096];
+        while !response.ends_with(b"\r\n\r\nok") {
+            let n = client.read(&mut buf).unwrap();
+            assert!(n > 0, "response cut off");
+            response.extend_from_slice(&buf[..n]);
+        }
+        sleep(pause);
+    }
+    connections.load(Ordering::SeqCst)
+}
+
+#[test]
+fn upstream_connections_are_reused() {
+    assert_eq!(get_three_times(&[], Duration::ZERO), 1);
+    // the pooled connection is closed before the next request comes
+    let args = ["--upstream-idle-timeout", "50"];
+    assert_eq!(get_three_times(&args, Duration::from_millis(300)), 3);
+}
+
+/// Send `requests` GETs on one client connection, returning the responses and whether the
+/// proxy closed the connection after the last one
+fn client_connection(proxy: &Proxy, requests: usize) -> (Vec<String>, bool) {
+    let mut client = TcpStream::connect(("127.0.0.1", proxy.port)).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut responses = Vec::new();
+    let mut buf = [0; 4096];
+    for _ in 0..requests {
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\nok") {
+            let n = client.read(&mut buf).unwrap();
+            assert!(n > 0, "response cut off");
+            response.extend_from_slice(&buf[..n]);
+        }
+        responses.push(String::from_utf8(response).unwrap().to_ascii_lowercase());
+    }
+    let closed = matches!(client.read(&mut buf), Ok(0));
+    (responses, closed)
+}
+
+#[test]
+fn downstream_keepalive_limits() {
+    let (target, _) = upstream();
+    let proxy = Proxy::start(&["-t", &target, "--downstream-max-requests", "2"]);
+    let (responses, closed) = client_connection(&proxy, 2);
+    assert!(
+        !responses[0].contains("connection: close"),
+        "{}",
+        responses[0]
+    );
+    assert!(
+        responses[1].contains("connection: close"),
+        "{}",
+        responses[1]
+    );
+    assert!(closed);
+
+    let (target, _) = upstream();
+    let proxy = Proxy::start(&["-t", &target, "--downstream-keepalive-timeout", "1"]);
+    let start = std::time::Instant::now();
+    let (responses, closed) = client_connection(&proxy, 3);
+    assert!(responses.iter().all(|r| !r.contains("connection: close")));
+    // idle for a second
+    assert!(closed);
+    assert!(start.elapsed() >= Duration::from_millis(900));
+}
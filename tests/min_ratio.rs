@@ -0,0 +1,72 @@
+mod common;
+
+use common::{Proxy, upstream};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Bytes that don't compress
+fn noise(len: usize) -> Vec<u8> {
+    let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn post(proxy: &Proxy, body: &[u8]) -> String {
+    let mut request = format!(
+        "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(body);
+    proxy.request(&request)
+}
+
+#[test]
+fn incompressible_bodies_go_uncompressed() {
+    let (target, received) = upstream();
+    let proxy = Proxy::start(&["-t", &target, "--compression-min-ratio", "1.1"]);
+
+    let body = noise(20_000);
+    assert!(post(&proxy, &body).ends_with("ok"));
+    let (head, forwarded) = received.recv().unwrap();
+    assert!(!head.contains("content-encoding"), "{head}");
+    assert!(head.contains("content-length: 20000"), "{head}");
+    assert_eq!(forwarded, body);
+
+    let body = b"compresses well ".repeat(2_000);
+    assert!(post(&proxy, &body).ends_with("ok"));
+    let (head, forwarded) = received.recv().unwrap();
+    assert!(head.contains("content-encoding: zstd"), "{head}");
+    assert!(forwarded.len() < body.len());
+
+    // the sample is read in two goes, the second overflowing the 64 KiB pingora keeps of it
+    let body = noise(300_000);
+    let mut client = TcpStream::connect(("127.0.0.1", proxy.port)).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let head = format!(
+        "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    client.write_all(head.as_bytes()).unwrap();
+    client.write_all(&body[..1000]).unwrap();
+    sleep(Duration::from_millis(300));
+    client.write_all(&body[1000..]).unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(response.ends_with("ok"), "{response}");
+    let (head, forwarded) = received.recv().unwrap();
+    assert!(!head.contains("content-encoding"), "{head}");
+    assert_eq!(forwarded, body);
+}
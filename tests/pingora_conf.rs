@@ -0,0 +1,28 @@
+mod common;
+
+use common::{Proxy, free_port};
+use std::process::{Command, Stdio};
+
+#[test]
+fn pingora_conf_file() {
+    let path = std::env::temp_dir().join(format!("http-proxy-pingora-{}.yaml", std::process::id()));
+    std::fs::write(&path, "---\nversion: 1\nthreads: 2\n").unwrap();
+    let target = format!("127.0.0.1:{}", free_port());
+    let proxy = Proxy::start(&["-t", &target, "--pingora-conf", path.to_str().unwrap()]);
+    let response =
+        proxy.request(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    // the file is only read
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "---\nversion: 1\nthreads: 2\n"
+    );
+    std::fs::remove_file(path).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_http-proxy"))
+        .args(["-t", &target, "--pingora-conf", "/nonexistent/pingora.yaml"])
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
@@ -0,0 +1,34 @@
+mod common;
+
+use common::{Proxy, upstream};
+
+/// The value of `name` in a lowercased head
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+        .map(str::trim)
+}
+
+#[test]
+fn generated_and_echoed() {
+    let (target, received) = upstream();
+    let proxy = Proxy::start(&["-t", &target]);
+
+    let response = proxy.request(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    let (head, _) = received.recv().unwrap();
+    let id = header(&head, "x-request-id").unwrap();
+    assert_eq!(id.len(), 36, "{head}");
+    let response = response.to_ascii_lowercase();
+    assert_eq!(header(&response, "x-request-id"), Some(id), "{response}");
+
+    // another header, and the client's own id
+    let proxy = Proxy::start(&["-t", &target, "--request-id-header", "X-Trace"]);
+    let response = proxy.request(
+        b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Trace: trace-42\r\nConnection: close\r\n\r\n",
+    );
+    let (head, _) = received.recv().unwrap();
+    assert_eq!(header(&head, "x-trace"), Some("trace-42"), "{head}");
+    assert!(header(&head, "x-request-id").is_none(), "{head}");
+    let response = response.to_ascii_lowercase();
+    assert_eq!(header(&response, "x-trace"), Some("trace-42"), "{response}");
+}
@@ -0,0 +1,74 @@
+mod common;
+
+use common::Proxy;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Reads one chunked request and answers it, sending what it received over the channel
+fn upstream() -> (String, mpsc::Receiver<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        while !request.ends_with(b"\r\n0\r\n\r\n") {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0, "request cut off");
+            request.extend_from_slice(&buf[..n]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+            .unwrap();
+        tx.send(request).unwrap();
+    });
+    (addr, rx)
+}
+
+#[test]
+fn sigterm_lets_uploads_finish() {
+    let (target, received) = upstream();
+    let proxy = Proxy::start(&["-t", &target, "--shutdown-timeout", "20"]);
+
+    let mut client = TcpStream::connect(("127.0.0.1", proxy.port)).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    client
+        .write_all(
+            b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\n\
+              Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+    let chunk = "a".repeat(4096);
+    client
+        .write_all(format!("1000\r\n{chunk}\r\n").as_bytes())
+        .unwrap();
+    sleep(Duration::from_millis(300));
+
+    proxy.signal("TERM");
+    sleep(Duration::from_millis(500));
+    // no new connections once shutting down
+    assert!(TcpStream::connect(("127.0.0.1", proxy.port)).is_err());
+
+    // the rest of the upload still goes through the compressor
+    let start = Instant::now();
+    client
+        .write_all(format!("1000\r\n{chunk}\r\n0\r\n\r\n").as_bytes())
+        .unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(response.ends_with("ok"), "{response}");
+    let request = received.recv().unwrap();
+    let head = String::from_utf8_lossy(&request).to_ascii_lowercase();
+    assert!(head.contains("content-encoding: zstd"), "{head}");
+
+    // and the proxy is gone right after, not at the end of the timeout
+    assert!(proxy.wait().success());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use common::{Proxy, upstream};
+use std::time::Duration;
+
+#[test]
+fn cookie_affinity() {
+    let (first, first_received) = upstream();
+    let (second, second_received) = upstream();
+    let proxy = Proxy::start(&[
+        "-t",
+        &first,
+        "-t",
+        &second,
+        "--sticky",
+        "cookie",
+        "--sticky-cookie",
+        "backend",
+    ]);
+
+    let response = proxy.request(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(response.ends_with("ok"), "{response}");
+    let cookie = response
+        .lines()
+        .find_map(|line| {
+            line.to_ascii_lowercase()
+                .strip_prefix("set-cookie: ")
+                .map(str::to_string)
+        })
+        .unwrap_or_else(|| panic!("{response}"));
+    let affinity = cookie.split(';').next().unwrap().to_string();
+    assert!(affinity.starts_with("backend="), "{cookie}");
+    let first_got = first_received
+        .recv_timeout(Duration::from_millis(500))
+        .is_ok();
+    if !first_got {
+        second_received.recv().unwrap();
+    }
+
+    for _ in 0..6 {
+        let request = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nCookie: {affinity}\r\nConnection: close\r\n\r\n"
+        );
+        let response = proxy.request(request.as_bytes());
+        assert!(response.ends_with("ok"), "{response}");
+        assert!(
+            !response.to_ascii_lowercase().contains("set-cookie"),
+            "{response}"
+        );
+        let (same, other) = if first_got {
+            (&first_received, &second_received)
+        } else {
+            (&second_received, &first_received)
+        };
+        same.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(other.try_recv().is_err());
+    }
+}
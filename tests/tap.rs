@@ -0,0 +1,41 @@
+mod common;
+
+use common::{Proxy, upstream};
+
+#[test]
+fn tapped_bodies() {
+    let dir = std::env::temp_dir().join(format!("http-proxy-taps-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (target, received) = upstream();
+    let proxy = Proxy::start(&["-t", &target, "--tap-dir", dir.to_str().unwrap()]);
+
+    let body = "{\"tapped\": true}".repeat(100);
+    let request = format!(
+        "POST /items?tap HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let response = proxy.request(request.as_bytes());
+    assert!(response.ends_with("ok"), "{response}");
+    let (head, forwarded) = received.recv().unwrap();
+    // still compressed for the upstream
+    assert!(head.contains("content-encoding: zstd"), "{head}");
+    assert_ne!(forwarded, body.as_bytes());
+
+    let mut taps: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    taps.sort();
+    assert_eq!(taps.len(), 2, "{taps:?}");
+    assert!(taps[0].to_str().unwrap().ends_with(".request"));
+    assert_eq!(std::fs::read_to_string(&taps[0]).unwrap(), body);
+    assert_eq!(std::fs::read_to_string(&taps[1]).unwrap(), "ok");
+
+    // without asking for it nothing is tapped
+    let request = request.replace("?tap", "");
+    proxy.request(request.as_bytes());
+    received.recv().unwrap();
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,13 @@
+mod common;
+
+use common::{Proxy, upstream};
+
+#[test]
+fn fastopen_listener_serves() {
+    let (target, received) = upstream();
+    let proxy = Proxy::start(&["-t", &target, "--tcp-fastopen", "16"]);
+
+    let response = proxy.request(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(response.ends_with("ok"), "{response}");
+    received.recv().unwrap();
+}
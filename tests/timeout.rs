@@ -0,0 +1,49 @@
+mod common;
+
+use common::Proxy;
+use std::net::TcpListener;
+use std::time::{Duration, Instant};
+
+#[test]
+fn read_timeout_is_504() {
+    // accepts connections but never answers
+    let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = upstream.local_addr().unwrap().to_string();
+    let proxy = Proxy::start(&["-t", &target, "--read-timeout", "500"]);
+
+    let sent = Instant::now();
+    let response = proxy.request(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 504"), "{response}");
+    let elapsed = sent.elapsed();
+    assert!(elapsed >= Duration::from_millis(500) && elapsed < Duration::from_secs(5));
+}
+
+#[test]
+fn error_page() {
+    let page = std::env::temp_dir().join(format!("http-proxy-error-{}.html", std::process::id()));
+    std::fs::write(&page, "<h1>down for maintenance</h1>").unwrap();
+    // nothing listens there
+    let target = format!("127.0.0.1:{}", common::free_port());
+    let proxy = Proxy::start(&[
+        "-t",
+        &target,
+        "--upstream-error-status",
+        "503",
+        "--error-page",
+        page.to_str().unwrap(),
+    ]);
+
+    let response = proxy.request(
+        b"GET / HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: zstd, gzip\r\n\
+          Connection: close\r\n\r\n",
+    );
+    std::fs::remove_file(&page).unwrap();
+    assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+    assert!(
+        response.ends_with("<h1>down for maintenance</h1>"),
+        "{response}"
+    );
+    let head = response.to_ascii_lowercase();
+    assert!(head.contains("content-type: text/html"), "{response}");
+    assert!(!head.contains("content-encoding"), "{response}");
+}
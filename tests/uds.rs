@@ -0,0 +1,38 @@
+mod common;
+
+use common::{Proxy, free_port};
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+
+#[test]
+fn unix_socket_listener() {
+    let path = std::env::temp_dir().join(format!("http-proxy-{}.sock", std::process::id()));
+    let target = format!("127.0.0.1:{}", free_port());
+    let proxy = Proxy::start(&[
+        "-t",
+        &target,
+        "--listen-uds",
+        path.to_str().unwrap(),
+        "--uds-mode",
+        "600",
+    ]);
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let mut client = UnixStream::connect(&path).unwrap();
+    client
+        .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+    // still listening on TCP too
+    let response =
+        proxy.request(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+    proxy.interrupt();
+    assert!(!path.exists());
+}
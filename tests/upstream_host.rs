@@ -0,0 +1,38 @@
+mod common;
+
+use common::{Proxy, upstream};
+
+/// The value of `name` in a lowercased head
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+        .map(str::trim)
+}
+
+#[test]
+fn overridden_host() {
+    let (target, received) = upstream();
+    let (routed, routed_received) = upstream();
+    let route = format!("api.example.com={routed},host=api.internal");
+    let proxy = Proxy::start(&[
+        "-t",
+        &target,
+        "--upstream-host-header",
+        "backend.internal",
+        "--route",
+        &route,
+    ]);
+
+    let response =
+        proxy.request(b"GET / HTTP/1.1\r\nHost: www.example.com\r\nConnection: close\r\n\r\n");
+    assert!(response.ends_with("ok"), "{response}");
+    let (head, _) = received.recv().unwrap();
+    assert_eq!(header(&head, "host"), Some("backend.internal"), "{head}");
+
+    // the route's own takes precedence
+    let response =
+        proxy.request(b"GET / HTTP/1.1\r\nHost: api.example.com\r\nConnection: close\r\n\r\n");
+    assert!(response.ends_with("ok"), "{response}");
+    let (head, _) = routed_received.recv().unwrap();
+    assert_eq!(header(&head, "host"), Some("api.internal"), "{head}");
+}